@@ -0,0 +1,10 @@
+CREATE TABLE users_backup (
+  id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  terms_signed BOOLEAN NOT NULL DEFAULT 'f',
+  not_resident BOOLEAN NOT NULL DEFAULT 'f',
+  address VARCHAR NOT NULL,
+  amount LONG INTEGER NOT NULL DEFAULT 0
+);
+INSERT INTO users_backup SELECT id, terms_signed, not_resident, address, amount FROM users;
+DROP TABLE users;
+ALTER TABLE users_backup RENAME TO users;
@@ -0,0 +1,4 @@
+ALTER TABLE users ADD COLUMN payout_status VARCHAR NOT NULL DEFAULT 'pending';
+ALTER TABLE users ADD COLUMN payout_tx VARCHAR;
+ALTER TABLE users ADD COLUMN paid_amount LONG INTEGER;
+ALTER TABLE users ADD COLUMN paid_at TIMESTAMP;
@@ -0,0 +1,8 @@
+CREATE TABLE vesting_schedules (
+  id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  user_id INTEGER NOT NULL UNIQUE REFERENCES users (id) ON DELETE CASCADE,
+  start_at TIMESTAMP NOT NULL,
+  cliff_secs LONG INTEGER NOT NULL DEFAULT 0,
+  duration_secs LONG INTEGER NOT NULL,
+  period_secs LONG INTEGER NOT NULL DEFAULT 0
+)
@@ -0,0 +1,24 @@
+CREATE TABLE tasks (
+  id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  title VARCHAR NOT NULL,
+  description TEXT NOT NULL DEFAULT '',
+  reward LONG INTEGER NOT NULL DEFAULT 0,
+  active BOOLEAN NOT NULL DEFAULT 't',
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE submissions (
+  id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  task_id INTEGER NOT NULL REFERENCES tasks (id) ON DELETE CASCADE,
+  user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  proof_url VARCHAR NOT NULL,
+  proof_text TEXT NOT NULL DEFAULT '',
+  status VARCHAR NOT NULL DEFAULT 'pending',
+  reason TEXT,
+  reward LONG INTEGER NOT NULL DEFAULT 0,
+  moderator VARCHAR,
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  moderated_at TIMESTAMP
+);
+CREATE INDEX submissions_user_id ON submissions (user_id);
+CREATE INDEX submissions_status ON submissions (status)
@@ -0,0 +1,21 @@
+CREATE TABLE admin_accounts (
+  id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  username VARCHAR NOT NULL UNIQUE,
+  password_hash VARCHAR NOT NULL,
+  totp_secret VARCHAR NOT NULL,
+  totp_last_step LONG INTEGER NOT NULL DEFAULT 0,
+  role VARCHAR NOT NULL,
+  failed_attempts INTEGER NOT NULL DEFAULT 0,
+  locked_until TIMESTAMP,
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  last_login_at TIMESTAMP
+);
+
+CREATE TABLE admin_sessions (
+  id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  token_hash VARCHAR NOT NULL UNIQUE,
+  account_id INTEGER NOT NULL REFERENCES admin_accounts (id) ON DELETE CASCADE,
+  csrf_token VARCHAR NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  expires_at TIMESTAMP NOT NULL
+)
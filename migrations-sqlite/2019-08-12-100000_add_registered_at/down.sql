@@ -0,0 +1,14 @@
+CREATE TABLE users_backup (
+  id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  terms_signed BOOLEAN NOT NULL DEFAULT 'f',
+  not_resident BOOLEAN NOT NULL DEFAULT 'f',
+  address VARCHAR NOT NULL,
+  amount LONG INTEGER NOT NULL DEFAULT 0,
+  payout_status VARCHAR NOT NULL DEFAULT 'pending',
+  payout_tx VARCHAR,
+  paid_amount LONG INTEGER,
+  paid_at TIMESTAMP
+);
+INSERT INTO users_backup SELECT id, terms_signed, not_resident, address, amount, payout_status, payout_tx, paid_amount, paid_at FROM users;
+DROP TABLE users;
+ALTER TABLE users_backup RENAME TO users;
@@ -0,0 +1 @@
+ALTER TABLE users ADD COLUMN registered_at TIMESTAMP;
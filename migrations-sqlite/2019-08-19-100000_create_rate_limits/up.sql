@@ -0,0 +1,6 @@
+CREATE TABLE rate_limits (
+  bucket VARCHAR PRIMARY KEY NOT NULL,
+  tokens REAL NOT NULL,
+  updated_at TIMESTAMP NOT NULL
+);
+CREATE INDEX rate_limits_updated_at ON rate_limits (updated_at);
@@ -0,0 +1,9 @@
+CREATE TABLE registration_attempts (
+  id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  address VARCHAR NOT NULL,
+  ip VARCHAR,
+  ip_list VARCHAR,
+  outcome VARCHAR NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX registration_attempts_created_at ON registration_attempts (created_at);
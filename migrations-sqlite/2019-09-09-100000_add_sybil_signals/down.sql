@@ -0,0 +1,30 @@
+CREATE TABLE users_backup (
+  id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  terms_signed BOOLEAN NOT NULL DEFAULT 'f',
+  not_resident BOOLEAN NOT NULL DEFAULT 'f',
+  address VARCHAR NOT NULL,
+  amount LONG INTEGER NOT NULL DEFAULT 0,
+  payout_status VARCHAR NOT NULL DEFAULT 'pending',
+  payout_tx VARCHAR,
+  paid_amount LONG INTEGER,
+  paid_at TIMESTAMP,
+  registered_at TIMESTAMP,
+  registered_country VARCHAR,
+  country_flagged BOOLEAN NOT NULL DEFAULT 'f'
+);
+INSERT INTO users_backup SELECT id, terms_signed, not_resident, address, amount, payout_status, payout_tx, paid_amount, paid_at, registered_at, registered_country, country_flagged FROM users;
+DROP TABLE users;
+ALTER TABLE users_backup RENAME TO users;
+
+CREATE TABLE registration_attempts_backup (
+  id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  address VARCHAR NOT NULL,
+  ip VARCHAR,
+  ip_list VARCHAR,
+  outcome VARCHAR NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+INSERT INTO registration_attempts_backup SELECT id, address, ip, ip_list, outcome, created_at FROM registration_attempts;
+DROP TABLE registration_attempts;
+ALTER TABLE registration_attempts_backup RENAME TO registration_attempts;
+CREATE INDEX registration_attempts_created_at ON registration_attempts (created_at);
@@ -0,0 +1,3 @@
+ALTER TABLE registration_attempts ADD COLUMN user_agent VARCHAR;
+ALTER TABLE registration_attempts ADD COLUMN referrer VARCHAR;
+ALTER TABLE users ADD COLUMN ineligible BOOLEAN NOT NULL DEFAULT 'f';
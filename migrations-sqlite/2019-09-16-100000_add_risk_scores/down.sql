@@ -0,0 +1,14 @@
+CREATE TABLE registration_attempts_backup (
+  id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  address VARCHAR NOT NULL,
+  ip VARCHAR,
+  ip_list VARCHAR,
+  outcome VARCHAR NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  user_agent VARCHAR,
+  referrer VARCHAR
+);
+INSERT INTO registration_attempts_backup SELECT id, address, ip, ip_list, outcome, created_at, user_agent, referrer FROM registration_attempts;
+DROP TABLE registration_attempts;
+ALTER TABLE registration_attempts_backup RENAME TO registration_attempts;
+CREATE INDEX registration_attempts_created_at ON registration_attempts (created_at);
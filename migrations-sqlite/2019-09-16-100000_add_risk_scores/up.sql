@@ -0,0 +1,3 @@
+ALTER TABLE registration_attempts ADD COLUMN risk_score INTEGER;
+ALTER TABLE registration_attempts ADD COLUMN risk_factors TEXT;
+ALTER TABLE registration_attempts ADD COLUMN risk_decision VARCHAR;
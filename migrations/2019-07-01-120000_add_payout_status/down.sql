@@ -0,0 +1,4 @@
+ALTER TABLE users DROP COLUMN paid_at;
+ALTER TABLE users DROP COLUMN paid_amount;
+ALTER TABLE users DROP COLUMN payout_tx;
+ALTER TABLE users DROP COLUMN payout_status;
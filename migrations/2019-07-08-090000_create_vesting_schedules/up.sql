@@ -0,0 +1,8 @@
+CREATE TABLE vesting_schedules (
+  id SERIAL PRIMARY KEY,
+  user_id INTEGER NOT NULL UNIQUE REFERENCES users (id) ON DELETE CASCADE,
+  start_at TIMESTAMP NOT NULL,
+  cliff_secs BIGINT NOT NULL DEFAULT 0,
+  duration_secs BIGINT NOT NULL,
+  period_secs BIGINT NOT NULL DEFAULT 0
+)
@@ -0,0 +1,8 @@
+CREATE TABLE referrals (
+  id SERIAL PRIMARY KEY,
+  referee_id INTEGER NOT NULL UNIQUE REFERENCES users (id) ON DELETE CASCADE,
+  referrer_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  bonus BIGINT NOT NULL DEFAULT 0,
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX referrals_referrer_id ON referrals (referrer_id)
@@ -0,0 +1,24 @@
+CREATE TABLE tasks (
+  id SERIAL PRIMARY KEY,
+  title VARCHAR NOT NULL,
+  description TEXT NOT NULL DEFAULT '',
+  reward BIGINT NOT NULL DEFAULT 0,
+  active BOOLEAN NOT NULL DEFAULT 't',
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE submissions (
+  id SERIAL PRIMARY KEY,
+  task_id INTEGER NOT NULL REFERENCES tasks (id) ON DELETE CASCADE,
+  user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  proof_url VARCHAR NOT NULL,
+  proof_text TEXT NOT NULL DEFAULT '',
+  status VARCHAR NOT NULL DEFAULT 'pending',
+  reason TEXT,
+  reward BIGINT NOT NULL DEFAULT 0,
+  moderator VARCHAR,
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  moderated_at TIMESTAMP
+);
+CREATE INDEX submissions_user_id ON submissions (user_id);
+CREATE INDEX submissions_status ON submissions (status)
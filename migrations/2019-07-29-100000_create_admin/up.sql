@@ -0,0 +1,19 @@
+CREATE TABLE api_keys (
+  id SERIAL PRIMARY KEY,
+  name VARCHAR NOT NULL,
+  key_hash VARCHAR NOT NULL UNIQUE,
+  role VARCHAR NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  last_used_at TIMESTAMP,
+  revoked_at TIMESTAMP
+);
+
+CREATE TABLE admin_audit (
+  id SERIAL PRIMARY KEY,
+  actor VARCHAR NOT NULL,
+  action VARCHAR NOT NULL,
+  user_id INTEGER REFERENCES users (id) ON DELETE SET NULL,
+  detail TEXT NOT NULL DEFAULT '',
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX admin_audit_user_id ON admin_audit (user_id)
@@ -0,0 +1,21 @@
+CREATE TABLE admin_accounts (
+  id SERIAL PRIMARY KEY,
+  username VARCHAR NOT NULL UNIQUE,
+  password_hash VARCHAR NOT NULL,
+  totp_secret VARCHAR NOT NULL,
+  totp_last_step BIGINT NOT NULL DEFAULT 0,
+  role VARCHAR NOT NULL,
+  failed_attempts INTEGER NOT NULL DEFAULT 0,
+  locked_until TIMESTAMP,
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  last_login_at TIMESTAMP
+);
+
+CREATE TABLE admin_sessions (
+  id SERIAL PRIMARY KEY,
+  token_hash VARCHAR NOT NULL UNIQUE,
+  account_id INTEGER NOT NULL REFERENCES admin_accounts (id) ON DELETE CASCADE,
+  csrf_token VARCHAR NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  expires_at TIMESTAMP NOT NULL
+)
@@ -0,0 +1,2 @@
+ALTER TABLE users DROP COLUMN country_flagged;
+ALTER TABLE users DROP COLUMN registered_country;
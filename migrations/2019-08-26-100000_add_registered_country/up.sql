@@ -0,0 +1,2 @@
+ALTER TABLE users ADD COLUMN registered_country VARCHAR;
+ALTER TABLE users ADD COLUMN country_flagged BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,3 @@
+ALTER TABLE users DROP COLUMN ineligible;
+ALTER TABLE registration_attempts DROP COLUMN referrer;
+ALTER TABLE registration_attempts DROP COLUMN user_agent;
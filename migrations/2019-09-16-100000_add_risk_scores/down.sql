@@ -0,0 +1,3 @@
+ALTER TABLE registration_attempts DROP COLUMN risk_decision;
+ALTER TABLE registration_attempts DROP COLUMN risk_factors;
+ALTER TABLE registration_attempts DROP COLUMN risk_score;
@@ -0,0 +1,12 @@
+CREATE TABLE payout_transfers (
+  id SERIAL PRIMARY KEY,
+  user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  tx_hash VARCHAR NOT NULL UNIQUE,
+  amount BIGINT NOT NULL,
+  status VARCHAR NOT NULL,
+  imported_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+-- transactions imported so far, users only kept the last one and its total
+INSERT INTO payout_transfers (user_id, tx_hash, amount, status, imported_at)
+  SELECT id, LOWER(payout_tx), COALESCE(paid_amount, 0), payout_status, COALESCE(paid_at, CURRENT_TIMESTAMP)
+  FROM users WHERE payout_tx IS NOT NULL;
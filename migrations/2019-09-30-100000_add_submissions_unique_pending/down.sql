@@ -0,0 +1 @@
+DROP INDEX submissions_user_task_pending;
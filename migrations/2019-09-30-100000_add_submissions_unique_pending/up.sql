@@ -0,0 +1 @@
+CREATE UNIQUE INDEX submissions_user_task_pending ON submissions (user_id, task_id) WHERE status <> 'rejected';
@@ -0,0 +1,252 @@
+//! Admin authorization with roles, by bearer API keys or by session cookies (see `session`).
+//! Keys are stored as SHA-256 hashes, the key itself is shown only once on creation.
+
+use std::fmt;
+use std::str::FromStr;
+use actix_web::dev::Payload;
+use actix_web::http::{header, Method, StatusCode};
+use actix_web::{FromRequest, HttpMessage, HttpRequest, HttpResponse, ResponseError};
+use chrono::Utc;
+use diesel::prelude::*;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+
+use crate::api;
+use crate::db::TheConnection;
+use crate::db::models::{ApiKey, NewApiKey};
+use crate::state::State;
+use super::session;
+
+
+const KEY_PREFIX: &str = "bk_";
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+	/// Read only
+	Viewer,
+	/// Can edit user flags and moderate submissions
+	Support,
+	/// Can change amounts
+	Admin,
+}
+
+impl Role {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Role::Viewer => "viewer",
+			Role::Support => "support",
+			Role::Admin => "admin",
+		}
+	}
+}
+
+impl FromStr for Role {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"viewer" => Ok(Role::Viewer),
+			"support" => Ok(Role::Support),
+			"admin" => Ok(Role::Admin),
+			_ => Err(format!("unknown role: {}", s)),
+		}
+	}
+}
+
+
+/// Authorized admin principal.
+#[derive(Debug, Clone)]
+pub struct Admin {
+	/// Used for attribution in the audit log
+	pub actor: String,
+	pub role: Role,
+	/// CSRF token a form request has still to present, see `confirm_csrf`
+	pending_csrf: Option<String>,
+}
+
+impl Admin {
+	pub fn require(&self, role: Role) -> Result<(), AuthError> {
+		if self.pending_csrf.is_some() {
+			log::warn!("admin: {} without csrf token", self.actor);
+			Err(AuthError::Forbidden)
+		} else if self.role >= role {
+			Ok(())
+		} else {
+			log::warn!("admin: {} ({}) requires {}", self.actor, self.role.as_str(), role.as_str());
+			Err(AuthError::Forbidden)
+		}
+	}
+
+	/// Checks the CSRF token of a form request against the session.
+	pub fn confirm_csrf(&mut self, token: &str) -> Result<(), AuthError> {
+		if let Some(expected) = &self.pending_csrf {
+			if !session::tokens_match(expected, token) {
+				log::warn!("admin: csrf mismatch for {}", self.actor);
+				return Err(AuthError::Forbidden);
+			}
+		}
+		self.pending_csrf = None;
+		Ok(())
+	}
+}
+
+impl FromRequest for Admin {
+	type Config = ();
+	type Error = AuthError;
+	type Future = Result<Self, AuthError>;
+
+	fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+		let conn = State::get().get_pool().get().map_err(|err| {
+			                                          log::error!("admin: pool: {:?}", err);
+			                                          AuthError::Internal
+			                                         })?;
+
+		match bearer_token(req) {
+			Some(token) => from_api_key(&conn, token),
+			None => from_session(&conn, req),
+		}
+	}
+}
+
+fn bearer_token(req: &HttpRequest) -> Option<&str> {
+	req.headers()
+	   .get(header::AUTHORIZATION)
+	   .and_then(|value| value.to_str().ok())
+	   .and_then(|value| {
+		   let mut parts = value.splitn(2, ' ');
+		   match (parts.next(), parts.next()) {
+			   (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("bearer") => Some(token.trim()),
+		     _ => None,
+		   }
+		  })
+}
+
+fn from_api_key(conn: &TheConnection, token: &str) -> Result<Admin, AuthError> {
+	let key = find_key(conn, token).map_err(|err| {
+		                               log::error!("admin: api key lookup: {:?}", err);
+		                               AuthError::Internal
+		                              })?
+	                                .ok_or(AuthError::Unauthorized)?;
+
+	let role = key.role.parse().map_err(|err| {
+		                           log::error!("admin: key#{}: {}", key.id, err);
+		                           AuthError::Forbidden
+		                          })?;
+	Ok(Admin { actor: format!("key#{} ({})", key.id, key.name),
+	           role,
+	           pending_csrf: None })
+}
+
+/// Session cookie auth. Requests other than GET and HEAD need the session's CSRF token,
+/// HTML forms send it in the body and the handler passes it to `Admin::confirm_csrf`.
+fn from_session(conn: &TheConnection, req: &HttpRequest) -> Result<Admin, AuthError> {
+	let cookie = req.cookie(session::COOKIE_NAME).ok_or(AuthError::Unauthorized)?;
+	let (admin_session, account) = session::find(conn, cookie.value()).map_err(|err| {
+		                                                                   log::error!("admin: session lookup: {:?}", err);
+		                                                                   AuthError::Internal
+		                                                                  })?
+	                                                                .ok_or(AuthError::Unauthorized)?;
+
+	let safe = *req.method() == Method::GET || *req.method() == Method::HEAD;
+	let header_token = req.headers()
+	                      .get(session::CSRF_HEADER)
+	                      .and_then(|value| value.to_str().ok());
+	let mut pending_csrf = None;
+	if !safe {
+		match header_token {
+			None if req.content_type() == "application/x-www-form-urlencoded" => {
+				pending_csrf = Some(admin_session.csrf_token.clone());
+			},
+			token => {
+				if !session::csrf_matches(&admin_session, token.unwrap_or_default()) {
+					log::warn!("admin: csrf mismatch for admin#{}", account.id);
+					return Err(AuthError::Forbidden);
+				}
+			},
+		}
+	}
+
+	let role = account.role.parse().map_err(|err| {
+		                               log::error!("admin: admin#{}: {}", account.id, err);
+		                               AuthError::Forbidden
+		                              })?;
+	Ok(Admin { actor: format!("admin#{} ({})", account.id, account.username),
+	           role,
+	           pending_csrf })
+}
+
+
+#[derive(Debug)]
+pub enum AuthError {
+	Unauthorized,
+	Forbidden,
+	Internal,
+}
+
+impl fmt::Display for AuthError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			AuthError::Unauthorized => write!(f, "Unauthorized"),
+			AuthError::Forbidden => write!(f, "Forbidden"),
+			AuthError::Internal => write!(f, "Internal Error"),
+		}
+	}
+}
+
+impl ResponseError for AuthError {
+	fn error_response(&self) -> HttpResponse {
+		let status = match self {
+			AuthError::Unauthorized => StatusCode::UNAUTHORIZED,
+			AuthError::Forbidden => StatusCode::FORBIDDEN,
+			AuthError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+		};
+		HttpResponse::build(status).json(api::Resp::from(api::Error { code: status.as_u16(),
+		                                                               message: self.to_string() }))
+	}
+
+	fn render_response(&self) -> HttpResponse { self.error_response() }
+}
+
+
+pub fn hash_key(key: &str) -> String { hex::encode(ring::digest::digest(&ring::digest::SHA256, key.as_bytes())) }
+
+/// Active key by its plain value. Updates its last usage time.
+pub fn find_key(conn: &TheConnection, key: &str) -> QueryResult<Option<ApiKey>> {
+	use crate::db::schema::api_keys::dsl::*;
+
+	let found = api_keys.filter(key_hash.eq(hash_key(key)))
+	                    .filter(revoked_at.is_null())
+	                    .first::<ApiKey>(conn)
+	                    .optional()?;
+	if let Some(found) = &found {
+		diesel::update(api_keys.filter(id.eq(found.id))).set(last_used_at.eq(Some(Utc::now().naive_utc())))
+		                                                .execute(conn)?;
+	}
+	Ok(found)
+}
+
+/// Creates a key and returns it in plain.
+pub fn create_key(conn: &TheConnection, key_name: &str, key_role: Role) -> Result<String, failure::Error> {
+	use crate::db::schema::api_keys::dsl::*;
+
+	let mut bytes = [0; 32];
+	SystemRandom::new().fill(&mut bytes)
+	                   .map_err(|_| failure::format_err!("no randomness"))?;
+	let key = format!("{}{}", KEY_PREFIX, hex::encode(&bytes));
+
+	diesel::insert_into(api_keys).values(&NewApiKey { name: key_name,
+	                                                  key_hash: &hash_key(&key),
+	                                                  role: key_role.as_str(),
+	                                                  created_at: Utc::now().naive_utc() })
+	                             .execute(conn)?;
+	Ok(key)
+}
+
+pub fn revoke_key(conn: &TheConnection, key_id: i32) -> QueryResult<usize> {
+	use crate::db::schema::api_keys::dsl::*;
+
+	diesel::update(api_keys.filter(id.eq(key_id))).set(revoked_at.eq(Some(Utc::now().naive_utc())))
+	                                              .execute(conn)
+}
@@ -0,0 +1,320 @@
+//! Server-rendered admin dashboard under `/admin/ui` for session logins.
+//! Templates are in `templates/admin` and compiled into the binary,
+//! all changes go through `service` like the JSON API.
+
+use std::fmt;
+use std::sync::Arc;
+use actix_web::http::{header, StatusCode};
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, ResponseError, Scope};
+use askama::Template;
+use serde::Deserialize;
+
+use crate::state::State;
+use crate::stats::Stats;
+use super::auth::{Admin, AuthError, Role};
+use super::service::{self, AmountAdjustment, UserDetail, UserPatch};
+use super::{conn, session, DEFAULT_PAGE};
+
+
+const ROOT: &str = "/admin/ui/";
+const LOGIN: &str = "/admin/ui/login";
+
+
+pub fn scope() -> Scope {
+	web::scope("/ui").service(web::resource("/").route(web::get().to(overview)))
+	                 .service(web::resource("/login").route(web::get().to(login_page))
+	                                                 .route(web::post().to(login)))
+	                 .service(web::resource("/logout").route(web::post().to(logout)))
+	                 .service(web::resource("/users").route(web::get().to(users)))
+	                 .service(web::resource("/users/{id}").route(web::get().to(user))
+	                                                      .route(web::post().to(update_user)))
+	                 .service(web::resource("/users/{id}/amount").route(web::post().to(adjust_amount)))
+}
+
+
+/// Signed in account, shown on every page.
+struct Nav {
+	username: String,
+	role: &'static str,
+	csrf_token: String,
+	/// Current search
+	query: String,
+}
+
+#[derive(Template)]
+#[template(path = "admin/login.html")]
+struct LoginPage {
+	error: Option<&'static str>,
+}
+
+#[derive(Template)]
+#[template(path = "admin/overview.html")]
+struct OverviewPage {
+	nav: Nav,
+	stats: Arc<Stats>,
+}
+
+#[derive(Template)]
+#[template(path = "admin/users.html")]
+struct UsersPage {
+	nav: Nav,
+	users: Vec<crate::db::models::User>,
+}
+
+#[derive(Template)]
+#[template(path = "admin/user.html")]
+struct UserPage {
+	nav: Nav,
+	detail: UserDetail,
+	payout_tx: String,
+	can_edit: bool,
+	can_adjust: bool,
+	notice: Option<&'static str>,
+	error: Option<&'static str>,
+}
+
+
+#[derive(Debug, Deserialize)]
+pub struct SearchForm {
+	#[serde(default)]
+	pub q: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UserView {
+	/// Set after a successful change
+	#[serde(default)]
+	pub saved: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CsrfForm {
+	pub csrf_token: String,
+}
+
+/// Checkboxes are sent only when checked.
+#[derive(Debug, Deserialize)]
+pub struct FlagsForm {
+	pub csrf_token: String,
+	pub terms_signed: Option<String>,
+	pub not_resident: Option<String>,
+	pub reason: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AmountForm {
+	pub csrf_token: String,
+	pub delta: i64,
+	pub reason: String,
+}
+
+
+#[derive(Debug)]
+pub enum PageError {
+	/// Not signed in, redirects to the login page
+	SignIn,
+	NotFound,
+	Auth(AuthError),
+	Render(askama::Error),
+}
+
+impl From<AuthError> for PageError {
+	fn from(error: AuthError) -> Self {
+		match error {
+			AuthError::Unauthorized => PageError::SignIn,
+			error => PageError::Auth(error),
+		}
+	}
+}
+
+impl From<askama::Error> for PageError {
+	fn from(error: askama::Error) -> Self { PageError::Render(error) }
+}
+
+impl fmt::Display for PageError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			PageError::SignIn => write!(f, "Sign in required"),
+			PageError::NotFound => write!(f, "Not found"),
+			PageError::Auth(err) => write!(f, "{}", err),
+			PageError::Render(_) => write!(f, "Internal Error"),
+		}
+	}
+}
+
+impl ResponseError for PageError {
+	fn error_response(&self) -> HttpResponse {
+		let status = match self {
+			PageError::SignIn => return redirect(LOGIN),
+			PageError::NotFound => StatusCode::NOT_FOUND,
+			PageError::Auth(AuthError::Forbidden) => StatusCode::FORBIDDEN,
+			PageError::Auth(_) => StatusCode::INTERNAL_SERVER_ERROR,
+			PageError::Render(err) => {
+				log::error!("admin dashboard: render: {}", err);
+				StatusCode::INTERNAL_SERVER_ERROR
+			},
+		};
+		HttpResponse::build(status).content_type("text/plain; charset=utf-8")
+		                           .body(self.to_string())
+	}
+
+	fn render_response(&self) -> HttpResponse { self.error_response() }
+}
+
+
+fn redirect(location: &str) -> HttpResponse {
+	HttpResponse::SeeOther().header(header::LOCATION, location).finish()
+}
+
+fn render(status: StatusCode, page: &impl Template) -> Result<HttpResponse, PageError> {
+	Ok(HttpResponse::build(status).content_type("text/html; charset=utf-8")
+	                              .header("X-Frame-Options", "DENY")
+	                              .body(page.render()?))
+}
+
+/// Session of the request, API keys can not use the dashboard.
+fn nav(req: &HttpRequest, query: &str) -> Result<Nav, PageError> {
+	let cookie = req.cookie(session::COOKIE_NAME).ok_or(PageError::SignIn)?;
+	let found = session::find(&*conn()?, cookie.value()).map_err(|err| {
+		                                                    log::error!("admin dashboard: session: {:?}", err);
+		                                                    AuthError::Internal
+		                                                   })?;
+	let info = found.and_then(|(admin_session, account)| session::info(&admin_session, &account))
+	                .ok_or(PageError::SignIn)?;
+	Ok(Nav { username: info.username,
+	         role: info.role.as_str(),
+	         csrf_token: info.csrf_token,
+	         query: query.to_owned() })
+}
+
+fn service_error(err: service::Error) -> Result<&'static str, PageError> {
+	match err {
+		service::Error::NotFound => Err(PageError::NotFound),
+		service::Error::Invalid(message) => Ok(message),
+		service::Error::Db(err) => {
+			log::error!("admin dashboard: {:?}", err);
+			Err(AuthError::Internal.into())
+		},
+	}
+}
+
+fn db_error(err: diesel::result::Error) -> PageError {
+	log::error!("admin dashboard: {:?}", err);
+	AuthError::Internal.into()
+}
+
+
+fn login_page() -> Result<HttpResponse, PageError> { render(StatusCode::OK, &LoginPage { error: None }) }
+
+fn login(data: web::Form<session::Login>) -> Result<HttpResponse, PageError> {
+	match session::login(&*conn()?, &data) {
+		Ok((token, _)) => {
+			let cookie = State::get().admin_session().session_cookie(token);
+			Ok(HttpResponse::SeeOther().cookie(cookie)
+			                           .header(header::LOCATION, ROOT)
+			                           .finish())
+		},
+		Err(session::Error::InvalidCredentials) | Err(session::Error::Locked) => {
+			render(StatusCode::UNAUTHORIZED,
+			       &LoginPage { error: Some("Invalid credentials or the account is locked") })
+		},
+		Err(err) => {
+			log::error!("admin dashboard login: {:?}", err);
+			Err(AuthError::Internal.into())
+		},
+	}
+}
+
+fn logout(admin: Result<Admin, AuthError>, form: web::Form<CsrfForm>, req: HttpRequest) -> Result<HttpResponse, PageError> {
+	let mut admin = admin?;
+	admin.confirm_csrf(&form.csrf_token)?;
+	admin.require(Role::Viewer)?;
+	if let Some(cookie) = req.cookie(session::COOKIE_NAME) {
+		session::logout(&*conn()?, cookie.value()).map_err(db_error)?;
+	}
+	let cookie = State::get().admin_session().removal_cookie();
+	Ok(HttpResponse::SeeOther().cookie(cookie)
+	                           .header(header::LOCATION, LOGIN)
+	                           .finish())
+}
+
+
+fn overview(admin: Result<Admin, AuthError>, req: HttpRequest) -> Result<HttpResponse, PageError> {
+	admin?.require(Role::Viewer)?;
+	let nav = nav(&req, "")?;
+	let stats = State::get().stats().get(&*conn()?).map_err(db_error)?;
+	render(StatusCode::OK, &OverviewPage { nav, stats })
+}
+
+/// Search by address prefix, all users when the search is empty.
+fn users(admin: Result<Admin, AuthError>, search: web::Query<SearchForm>, req: HttpRequest) -> Result<HttpResponse, PageError> {
+	admin?.require(Role::Viewer)?;
+	let nav = nav(&req, search.q.trim())?;
+	let conn = conn()?;
+	let users = if nav.query.is_empty() {
+		service::list_users(&conn, 0, DEFAULT_PAGE)
+	} else {
+		service::search_users(&conn, &nav.query, DEFAULT_PAGE)
+	}.map_err(db_error)?;
+	render(StatusCode::OK, &UsersPage { nav, users })
+}
+
+fn user_page(admin: &Admin,
+             req: &HttpRequest,
+             user_id: i32,
+             notice: Option<&'static str>,
+             error: Option<&'static str>)
+             -> Result<HttpResponse, PageError> {
+	let nav = nav(req, "")?;
+	let detail = match service::get_user(&*conn()?, user_id) {
+		Ok(detail) => detail,
+		Err(service::Error::Db(err)) => return Err(db_error(err)),
+		Err(_) => return Err(PageError::NotFound),
+	};
+	let page = UserPage { nav,
+	                      payout_tx: detail.user.payout_tx.clone().unwrap_or_default(),
+	                      detail,
+	                      can_edit: admin.role >= Role::Support,
+	                      can_adjust: admin.role >= Role::Admin,
+	                      notice,
+	                      error };
+	let status = if error.is_some() { StatusCode::BAD_REQUEST } else { StatusCode::OK };
+	render(status, &page)
+}
+
+fn user(admin: Result<Admin, AuthError>, id: web::Path<i32>, view: web::Query<UserView>, req: HttpRequest)
+        -> Result<HttpResponse, PageError> {
+	let admin = admin?;
+	admin.require(Role::Viewer)?;
+	let notice = if view.saved { Some("Saved") } else { None };
+	user_page(&admin, &req, *id, notice, None)
+}
+
+/// Address changes stay with the JSON API.
+fn update_user(admin: Result<Admin, AuthError>, id: web::Path<i32>, form: web::Form<FlagsForm>, req: HttpRequest)
+               -> Result<HttpResponse, PageError> {
+	let mut admin = admin?;
+	admin.confirm_csrf(&form.csrf_token)?;
+	admin.require(Role::Support)?;
+	let patch = UserPatch { terms_signed: Some(form.terms_signed.is_some()),
+	                        not_resident: Some(form.not_resident.is_some()),
+	                        address: None,
+	                        reason: form.reason.clone() };
+	match service::update_user(&*conn()?, &admin.actor, *id, &patch) {
+		Ok(_) => Ok(redirect(&format!("{}users/{}?saved=true", ROOT, *id))),
+		Err(err) => user_page(&admin, &req, *id, None, Some(service_error(err)?)),
+	}
+}
+
+fn adjust_amount(admin: Result<Admin, AuthError>, id: web::Path<i32>, form: web::Form<AmountForm>, req: HttpRequest)
+                 -> Result<HttpResponse, PageError> {
+	let mut admin = admin?;
+	admin.confirm_csrf(&form.csrf_token)?;
+	admin.require(Role::Admin)?;
+	let adjustment = AmountAdjustment { delta: form.delta,
+	                                    reason: form.reason.clone() };
+	match service::adjust_amount(&*conn()?, &admin.actor, *id, &adjustment) {
+		Ok(_) => Ok(redirect(&format!("{}users/{}?saved=true", ROOT, *id))),
+		Err(err) => user_page(&admin, &req, *id, None, Some(service_error(err)?)),
+	}
+}
@@ -0,0 +1,207 @@
+//! Administrative API under `/admin`.
+//!
+//! Requests are authorized by `Authorization: Bearer <api key>`
+//! or by the session cookie from `/admin/login`, see `auth` for roles.
+//! The HTML dashboard for browsers is under `/admin/ui`, see `dashboard`.
+
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Scope};
+use serde::Deserialize;
+
+use crate::api;
+use crate::db::ThePooledConnection;
+use crate::state::State;
+use crate::tasks::{self, Decision};
+
+pub mod auth;
+pub mod dashboard;
+pub mod service;
+pub mod session;
+pub mod totp;
+
+use self::auth::{Admin, AuthError, Role};
+use self::service::{AmountAdjustment, UserPatch};
+
+
+const DEFAULT_PAGE: i64 = 50;
+
+
+pub fn scope() -> Scope {
+	web::scope("/admin").service(web::resource("/login").route(web::post().to(login)))
+	                    .service(web::resource("/logout").route(web::post().to(logout)))
+	                    .service(web::resource("/session").route(web::get().to(current_session)))
+	                    .service(web::resource("/stats").route(web::get().to(stats)))
+	                    .service(web::resource("/referrals").route(web::get().to(referrals)))
+	                    .service(web::resource("/users").route(web::get().to(list_users)))
+	                    .service(web::resource("/users/search").route(web::get().to(search_users)))
+	                    .service(web::resource("/users/{id}").route(web::get().to(get_user))
+	                                                         .route(web::patch().to(update_user)))
+	                    .service(web::resource("/users/{id}/amount").route(web::post().to(adjust_amount)))
+	                    .service(web::resource("/submissions").route(web::get().to(submission_queue)))
+	                    .service(web::resource("/submissions/{id}").route(web::post().to(moderate)))
+	                    .service(dashboard::scope())
+}
+
+
+#[derive(Debug, Deserialize)]
+pub struct Page {
+	#[serde(default)]
+	pub offset: i64,
+	pub limit: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Search {
+	/// Address prefix
+	pub q: String,
+	pub limit: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Moderation {
+	pub decision: Decision,
+	pub reason: String,
+}
+
+
+fn conn() -> Result<ThePooledConnection, AuthError> {
+	State::get().get_pool().get().map_err(|err| {
+		                             log::error!("admin: pool: {:?}", err);
+		                             AuthError::Internal
+		                            })
+}
+
+fn service_error(err: service::Error) -> HttpResponse {
+	match err {
+		service::Error::NotFound => HttpResponse::NotFound().json(api::ApiError::UserNotFound.to_resp()),
+		service::Error::Invalid(message) => {
+			HttpResponse::BadRequest().json(api::Resp::from(api::Error { code: 400,
+			                                                             message: message.to_owned() }))
+		},
+		service::Error::Db(err) => {
+			log::error!("admin: {:?}", err);
+			HttpResponse::InternalServerError().json(api::ApiError::Internal.to_resp())
+		},
+	}
+}
+
+
+fn login(data: web::Json<session::Login>) -> Result<HttpResponse, AuthError> {
+	match session::login(&*conn()?, &data) {
+		Ok((token, info)) => {
+			let cookie = State::get().admin_session().session_cookie(token);
+			Ok(HttpResponse::Ok().cookie(cookie).json(info))
+		},
+		Err(session::Error::InvalidCredentials) | Err(session::Error::Locked) => Err(AuthError::Unauthorized),
+		Err(err) => {
+			log::error!("admin login: {:?}", err);
+			Err(AuthError::Internal)
+		},
+	}
+}
+
+/// Ends the session. Needs the CSRF token like any other mutating request.
+fn logout(admin: Admin, req: HttpRequest) -> Result<HttpResponse, AuthError> {
+	admin.require(Role::Viewer)?;
+	if let Some(cookie) = req.cookie(session::COOKIE_NAME) {
+		session::logout(&*conn()?, cookie.value()).map_err(|err| {
+			                                          log::error!("admin logout: {:?}", err);
+			                                          AuthError::Internal
+			                                         })?;
+	}
+	let cookie = State::get().admin_session().removal_cookie();
+	Ok(HttpResponse::NoContent().cookie(cookie).finish())
+}
+
+/// The current session with its CSRF token.
+fn current_session(req: HttpRequest) -> Result<HttpResponse, AuthError> {
+	let cookie = req.cookie(session::COOKIE_NAME).ok_or(AuthError::Unauthorized)?;
+	let found = session::find(&*conn()?, cookie.value()).map_err(|err| {
+		                                                    log::error!("admin session: {:?}", err);
+		                                                    AuthError::Internal
+		                                                   })?;
+	match found.and_then(|(admin_session, account)| session::info(&admin_session, &account)) {
+		Some(info) => Ok(HttpResponse::Ok().json(info)),
+		None => Err(AuthError::Unauthorized),
+	}
+}
+
+
+fn stats(admin: Admin) -> Result<HttpResponse, AuthError> {
+	admin.require(Role::Viewer)?;
+	Ok(match State::get().stats().get(&*conn()?) {
+		   Ok(stats) => HttpResponse::Ok().json(&*stats),
+		   Err(err) => service_error(err.into()),
+	   })
+}
+
+/// Referees and bonus per referrer.
+fn referrals(admin: Admin) -> Result<HttpResponse, AuthError> {
+	admin.require(Role::Viewer)?;
+	let _query = crate::db::instrument("referral_stats");
+	Ok(match crate::referral::stats(&*conn()?) {
+		   Ok(stats) => HttpResponse::Ok().json(stats),
+		   Err(err) => service_error(err.into()),
+	   })
+}
+
+fn list_users(admin: Admin, page: web::Query<Page>) -> Result<HttpResponse, AuthError> {
+	admin.require(Role::Viewer)?;
+	let limit = page.limit.unwrap_or(DEFAULT_PAGE);
+	Ok(match service::list_users(&*conn()?, page.offset, limit) {
+		   Ok(users) => HttpResponse::Ok().json(users),
+		   Err(err) => service_error(err.into()),
+	   })
+}
+
+fn search_users(admin: Admin, search: web::Query<Search>) -> Result<HttpResponse, AuthError> {
+	admin.require(Role::Viewer)?;
+	let limit = search.limit.unwrap_or(DEFAULT_PAGE);
+	Ok(match service::search_users(&*conn()?, &search.q, limit) {
+		   Ok(users) => HttpResponse::Ok().json(users),
+		   Err(err) => service_error(err.into()),
+	   })
+}
+
+fn get_user(admin: Admin, id: web::Path<i32>) -> Result<HttpResponse, AuthError> {
+	admin.require(Role::Viewer)?;
+	Ok(match service::get_user(&*conn()?, *id) {
+		   Ok(detail) => HttpResponse::Ok().json(detail),
+		   Err(err) => service_error(err),
+	   })
+}
+
+fn update_user(admin: Admin, id: web::Path<i32>, patch: web::Json<UserPatch>) -> Result<HttpResponse, AuthError> {
+	admin.require(Role::Support)?;
+	if patch.touches_address() {
+		admin.require(Role::Admin)?;
+	}
+	Ok(match service::update_user(&*conn()?, &admin.actor, *id, &patch) {
+		   Ok(user) => HttpResponse::Ok().json(user),
+		   Err(err) => service_error(err),
+	   })
+}
+
+fn adjust_amount(admin: Admin, id: web::Path<i32>, adjustment: web::Json<AmountAdjustment>)
+                 -> Result<HttpResponse, AuthError> {
+	admin.require(Role::Admin)?;
+	Ok(match service::adjust_amount(&*conn()?, &admin.actor, *id, &adjustment) {
+		   Ok(user) => HttpResponse::Ok().json(user),
+		   Err(err) => service_error(err),
+	   })
+}
+
+fn submission_queue(admin: Admin) -> Result<HttpResponse, AuthError> {
+	admin.require(Role::Viewer)?;
+	Ok(match tasks::queue(&*conn()?) {
+		   Ok(queue) => HttpResponse::Ok().json(queue),
+		   Err(err) => service_error(err.into()),
+	   })
+}
+
+fn moderate(admin: Admin, id: web::Path<i32>, moderation: web::Json<Moderation>) -> Result<HttpResponse, AuthError> {
+	admin.require(Role::Support)?;
+	Ok(match service::moderate(&*conn()?, &admin.actor, *id, moderation.decision, &moderation.reason) {
+		   Ok(()) => HttpResponse::NoContent().finish(),
+		   Err(err) => service_error(err),
+	   })
+}
@@ -0,0 +1,219 @@
+//! Admin operations on users. Every change is written to the audit log
+//! together with the actor who made it.
+
+use chrono::Utc;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::db::TheConnection;
+use crate::db::models::{AuditRecord, NewAuditRecord, User, VestingSchedule};
+use crate::tasks::{self, Decision};
+
+
+pub const MAX_PAGE: i64 = 500;
+
+
+#[derive(Debug)]
+pub enum Error {
+	NotFound,
+	Invalid(&'static str),
+	Db(diesel::result::Error),
+}
+
+impl From<diesel::result::Error> for Error {
+	fn from(error: diesel::result::Error) -> Self { Error::Db(error) }
+}
+
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UserDetail {
+	pub user: User,
+	pub vesting: Option<VestingSchedule>,
+	pub audit: Vec<AuditRecord>,
+}
+
+/// Editable fields, `None` - leave as is.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UserPatch {
+	pub terms_signed: Option<bool>,
+	pub not_resident: Option<bool>,
+	/// Requires the admin role
+	pub address: Option<String>,
+	/// Why the change is made
+	pub reason: String,
+}
+
+impl UserPatch {
+	pub fn touches_address(&self) -> bool { self.address.is_some() }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AmountAdjustment {
+	/// AKT tokens to add, negative to subtract
+	pub delta: i64,
+	pub reason: String,
+}
+
+
+pub fn list_users(conn: &TheConnection, offset: i64, limit: i64) -> QueryResult<Vec<User>> {
+	use crate::db::schema::users::dsl::*;
+
+	users.order(id.asc())
+	     .offset(offset.max(0))
+	     .limit(limit.max(0).min(MAX_PAGE))
+	     .load::<User>(conn)
+}
+
+/// Users with an address starting with `prefix`, case-insensitive.
+pub fn search_users(conn: &TheConnection, prefix: &str, limit: i64) -> QueryResult<Vec<User>> {
+	use crate::db::lower;
+	use crate::db::schema::users::dsl::*;
+	use diesel::expression_methods::EscapeExpressionMethods;
+
+	let pattern = format!("{}%", escape_like(&prefix.trim().to_lowercase()));
+	users.filter(lower(address).like(pattern).escape('\\'))
+	     .order(id.asc())
+	     .limit(limit.max(0).min(MAX_PAGE))
+	     .load::<User>(conn)
+}
+
+fn escape_like(s: &str) -> String { s.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_") }
+
+pub fn get_user(conn: &TheConnection, user_id: i32) -> Result<UserDetail, Error> {
+	use crate::db::schema::admin_audit::dsl as a;
+	use crate::db::schema::users::dsl as u;
+
+	let user = u::users.filter(u::id.eq(user_id))
+	                   .first::<User>(conn)
+	                   .optional()?
+	                   .ok_or(Error::NotFound)?;
+	let vesting = crate::vesting::find_schedule(conn, user.id)?;
+	let audit = a::admin_audit.filter(a::user_id.eq(user.id))
+	                          .order(a::id.desc())
+	                          .load::<AuditRecord>(conn)?;
+	Ok(UserDetail { user, vesting, audit })
+}
+
+
+pub fn update_user(conn: &TheConnection, actor: &str, user_id: i32, patch: &UserPatch) -> Result<User, Error> {
+	use crate::db::schema::users::dsl::*;
+
+	if patch.reason.trim().is_empty() {
+		return Err(Error::Invalid("reason is required"));
+	}
+	if let Some(new_address) = &patch.address {
+		if new_address.trim().is_empty() {
+			return Err(Error::Invalid("address is empty"));
+		}
+	}
+
+	conn.transaction(|| {
+		    let before = users.filter(id.eq(user_id))
+		                      .first::<User>(conn)
+		                      .optional()?
+		                      .ok_or(Error::NotFound)?;
+
+		    if let Some(value) = patch.terms_signed {
+			    diesel::update(users.filter(id.eq(user_id))).set(terms_signed.eq(value)).execute(conn)?;
+		    }
+		    if let Some(value) = patch.not_resident {
+			    diesel::update(users.filter(id.eq(user_id))).set(not_resident.eq(value)).execute(conn)?;
+		    }
+		    if let Some(value) = &patch.address {
+			    diesel::update(users.filter(id.eq(user_id))).set(address.eq(value.trim())).execute(conn)?;
+		    }
+
+		    let detail = serde_json::json!({ "before": {
+			                                     "terms_signed": before.terms_signed,
+			                                     "not_resident": before.not_resident,
+			                                     "address": before.address,
+			                                 },
+			                                 "patch": patch });
+		    audit(conn, actor, "update_user", Some(user_id), &detail)?;
+
+		    Ok(users.filter(id.eq(user_id)).first::<User>(conn)?)
+	    })
+}
+
+pub fn adjust_amount(conn: &TheConnection, actor: &str, user_id: i32, adjustment: &AmountAdjustment) -> Result<User, Error> {
+	use crate::db::schema::users::dsl::*;
+
+	if adjustment.reason.trim().is_empty() {
+		return Err(Error::Invalid("reason is required"));
+	}
+
+	conn.transaction(|| {
+		    let before = users.filter(id.eq(user_id))
+		                      .first::<User>(conn)
+		                      .optional()?
+		                      .ok_or(Error::NotFound)?;
+		    let new_amount = before.amount
+		                           .checked_add(adjustment.delta)
+		                           .filter(|value| *value >= 0)
+		                           .ok_or(Error::Invalid("amount would be negative"))?;
+
+		    diesel::update(users.filter(id.eq(user_id))).set(amount.eq(new_amount))
+		                                                .execute(conn)?;
+
+		    let detail = serde_json::json!({ "before": before.amount,
+		                                     "after": new_amount,
+		                                     "delta": adjustment.delta,
+		                                     "reason": adjustment.reason });
+		    audit(conn, actor, "adjust_amount", Some(user_id), &detail)?;
+
+		    Ok(users.filter(id.eq(user_id)).first::<User>(conn)?)
+	    })
+}
+
+
+/// Approves or rejects a submission on behalf of the actor.
+pub fn moderate(conn: &TheConnection,
+                actor: &str,
+                submission_id: i32,
+                decision: Decision,
+                reason: &str)
+                -> Result<(), Error> {
+	if reason.trim().is_empty() {
+		return Err(Error::Invalid("reason is required"));
+	}
+
+	conn.transaction(|| {
+		    tasks::moderate(conn, submission_id, decision, reason, actor).map_err(|err| match err {
+			                                                                 tasks::Error::SubmissionNotFound => Error::NotFound,
+		                                                                     tasks::Error::AlreadyModerated => Error::Invalid("already moderated"),
+		                                                                     tasks::Error::Db(err) => Error::Db(err),
+		                                                                     _ => Error::Invalid("cannot moderate"),
+			                                                                })?;
+		    let detail = serde_json::json!({ "submission": submission_id,
+		                                     "decision": decision,
+		                                     "reason": reason });
+		    audit(conn, actor, "moderate", None, &detail)?;
+		    Ok(())
+	    })
+}
+
+
+/// Writes an audit record.
+pub fn audit(conn: &TheConnection,
+             actor: &str,
+             action: &str,
+             user_id: Option<i32>,
+             detail: &serde_json::Value)
+             -> QueryResult<usize> {
+	use crate::db::schema::admin_audit::dsl::admin_audit;
+
+	log::info!("audit: {} {} user#{:?} {}", actor, action, user_id, detail);
+	diesel::insert_into(admin_audit).values(&NewAuditRecord { actor,
+	                                                          action,
+	                                                          user_id,
+	                                                          detail: &detail.to_string(),
+	                                                          created_at: Utc::now().naive_utc() })
+	                                .execute(conn)
+}
+
+
+#[test]
+fn escape_like_test() {
+	assert_eq!(escape_like("0xab"), "0xab");
+	assert_eq!(escape_like("a%b_c\\"), "a\\%b\\_c\\\\");
+}
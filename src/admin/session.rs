@@ -0,0 +1,243 @@
+//! Browser login for admin accounts: Argon2 password, mandatory TOTP
+//! and server-side sessions carried in an HttpOnly SameSite cookie.
+//!
+//! Mutating requests of a session must carry its CSRF token in the `X-CSRF-Token` header,
+//! or in the `csrf_token` field of a dashboard form.
+
+use actix_web::cookie::SameSite;
+use actix_web::http::Cookie;
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+
+use crate::db::TheConnection;
+use crate::db::models::{AdminAccount, AdminSession, NewAdminAccount, NewAdminSession};
+use super::auth::{hash_key, Role};
+use super::service::audit;
+use super::totp;
+
+
+pub const COOKIE_NAME: &str = "bounty_admin";
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+
+const SESSION_HOURS: i64 = 8;
+const MAX_FAILED_ATTEMPTS: i32 = 5;
+const LOCKOUT_MINUTES: i64 = 15;
+/// Verified against when the username is unknown, so both cases take the same time.
+const DUMMY_HASH: &str = "$argon2id$v=19$m=4096,t=3,p=1$c29tZXNhbHRzb21lc2FsdA$B6+7dInVc2XjRTxgXQ+HtKdXgtEcOp4FTHSPDlcVcmY";
+
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+	/// Send the cookie only over https
+	pub cookie_secure: bool,
+}
+
+impl Settings {
+	pub fn from_config(config: &crate::config::Admin) -> Self { Self { cookie_secure: config.cookie_secure } }
+
+	pub fn cookie<'c>(&self, value: String, max_age: Duration) -> Cookie<'c> {
+		Cookie::build(COOKIE_NAME, value).path("/admin")
+		                                 .http_only(true)
+		                                 .same_site(SameSite::Strict)
+		                                 .secure(self.cookie_secure)
+		                                 .max_age(max_age.num_seconds())
+		                                 .finish()
+	}
+
+	pub fn session_cookie<'c>(&self, token: String) -> Cookie<'c> { self.cookie(token, Duration::hours(SESSION_HOURS)) }
+
+	pub fn removal_cookie<'c>(&self) -> Cookie<'c> { self.cookie(String::new(), Duration::zero()) }
+}
+
+
+#[derive(Debug, Deserialize)]
+pub struct Login {
+	pub username: String,
+	pub password: String,
+	pub totp: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionInfo {
+	pub username: String,
+	pub role: Role,
+	pub csrf_token: String,
+	pub expires_at: NaiveDateTime,
+}
+
+#[derive(Debug)]
+pub enum Error {
+	InvalidCredentials,
+	Locked,
+	Db(diesel::result::Error),
+	Internal(String),
+}
+
+impl From<diesel::result::Error> for Error {
+	fn from(error: diesel::result::Error) -> Self { Error::Db(error) }
+}
+
+
+fn random_token() -> Result<String, Error> {
+	let mut bytes = [0; 32];
+	SystemRandom::new().fill(&mut bytes)
+	                   .map_err(|_| Error::Internal("no randomness".to_owned()))?;
+	Ok(hex::encode(&bytes))
+}
+
+fn argon2_config() -> argon2::Config<'static> {
+	argon2::Config { variant: argon2::Variant::Argon2id,
+	                 ..argon2::Config::default() }
+}
+
+pub fn hash_password(password: &str) -> Result<String, Error> {
+	let mut salt = [0; 16];
+	SystemRandom::new().fill(&mut salt)
+	                   .map_err(|_| Error::Internal("no randomness".to_owned()))?;
+	argon2::hash_encoded(password.as_bytes(), &salt, &argon2_config()).map_err(|err| Error::Internal(err.to_string()))
+}
+
+
+/// Creates an account and returns its TOTP secret.
+pub fn create_account(conn: &TheConnection, name: &str, password: &str, account_role: Role) -> Result<String, Error> {
+	use crate::db::schema::admin_accounts::dsl::*;
+
+	let secret = totp::generate_secret().ok_or_else(|| Error::Internal("no randomness".to_owned()))?;
+	diesel::insert_into(admin_accounts).values(&NewAdminAccount { username: name,
+	                                                              password_hash: &hash_password(password)?,
+	                                                              totp_secret: &secret,
+	                                                              role: account_role.as_str(),
+	                                                              created_at: Utc::now().naive_utc() })
+	                                   .execute(conn)?;
+	Ok(secret)
+}
+
+
+/// Checks the credentials and opens a session.
+/// Returns the session cookie value and the session.
+pub fn login(conn: &TheConnection, login: &Login) -> Result<(String, SessionInfo), Error> {
+	use crate::db::schema::admin_accounts::dsl::*;
+
+	let now = Utc::now().naive_utc();
+	let account = admin_accounts.filter(username.eq(login.username.trim()))
+	                            .first::<AdminAccount>(conn)
+	                            .optional()?;
+	let account = match account {
+		Some(account) => account,
+		None => {
+			let _ = argon2::verify_encoded(DUMMY_HASH, login.password.as_bytes());
+			log::warn!("admin login: unknown user {:?}", login.username);
+			return Err(Error::InvalidCredentials);
+		},
+	};
+	let actor = format!("admin#{} ({})", account.id, account.username);
+
+	if account.locked_until.map(|until| until > now).unwrap_or(false) {
+		log::warn!("admin login: {} is locked", actor);
+		return Err(Error::Locked);
+	}
+
+	let password_ok = argon2::verify_encoded(&account.password_hash, login.password.as_bytes()).unwrap_or(false);
+	let step = if password_ok {
+		totp::verify(&account.totp_secret, &login.totp, now.timestamp(), account.totp_last_step)
+	} else {
+		None
+	};
+
+	let step = match step {
+		Some(step) => step,
+		None => {
+			// counted in SQL, parallel guesses must not each write back the count they read
+			conn.transaction::<_, Error, _>(|| {
+				    diesel::update(admin_accounts.filter(id.eq(account.id))).set(failed_attempts.eq(failed_attempts + 1))
+				                                                            .execute(conn)?;
+				    let attempts = admin_accounts.find(account.id).select(failed_attempts).first::<i32>(conn)?;
+				    let lock = if attempts >= MAX_FAILED_ATTEMPTS {
+					    Some(now + Duration::minutes(LOCKOUT_MINUTES))
+					   } else {
+					    None
+					   };
+				    diesel::update(admin_accounts.filter(id.eq(account.id))).set(locked_until.eq(lock))
+				                                                            .execute(conn)?;
+				    audit(conn,
+				          &actor,
+				          "login_failed",
+				          None,
+				          &serde_json::json!({ "attempts": attempts, "locked_until": lock }))?;
+				    Ok(())
+			    })?;
+			return Err(Error::InvalidCredentials);
+		},
+	};
+
+	let account_role: Role = account.role.parse().map_err(Error::Internal)?;
+	let token = random_token()?;
+	let csrf = random_token()?;
+	let expires = now + Duration::hours(SESSION_HOURS);
+
+	conn.transaction::<_, Error, _>(|| {
+		    use crate::db::schema::admin_sessions::dsl::admin_sessions;
+
+		    // a parallel login may have used the code or locked the account since it was read
+		    let updated = diesel::update(admin_accounts.filter(id.eq(account.id))
+		                                               .filter(totp_last_step.lt(step))
+		                                               .filter(locked_until.is_null().or(locked_until.le(now))))
+		                  .set((failed_attempts.eq(0),
+		                        locked_until.eq(None::<NaiveDateTime>),
+		                        totp_last_step.eq(step),
+		                        last_login_at.eq(Some(now))))
+		                  .execute(conn)?;
+		    if updated == 0 {
+			    log::warn!("admin login: {} used a spent code or was locked meanwhile", actor);
+			    return Err(Error::InvalidCredentials);
+		    }
+		    diesel::insert_into(admin_sessions).values(&NewAdminSession { token_hash: &hash_key(&token),
+		                                                                  account_id: account.id,
+		                                                                  csrf_token: &csrf,
+		                                                                  created_at: now,
+		                                                                  expires_at: expires })
+		                                       .execute(conn)?;
+		    audit(conn, &actor, "login", None, &serde_json::json!({}))?;
+		    Ok(())
+	    })?;
+
+	Ok((token,
+	    SessionInfo { username: account.username,
+	                  role: account_role,
+	                  csrf_token: csrf,
+	                  expires_at: expires }))
+}
+
+/// Active session by the cookie value.
+pub fn find(conn: &TheConnection, token: &str) -> QueryResult<Option<(AdminSession, AdminAccount)>> {
+	use crate::db::schema::admin_accounts;
+	use crate::db::schema::admin_sessions::dsl::*;
+
+	admin_sessions.inner_join(admin_accounts::table)
+	              .filter(token_hash.eq(hash_key(token)))
+	              .filter(expires_at.gt(Utc::now().naive_utc()))
+	              .first::<(AdminSession, AdminAccount)>(conn)
+	              .optional()
+}
+
+pub fn logout(conn: &TheConnection, token: &str) -> QueryResult<usize> {
+	use crate::db::schema::admin_sessions::dsl::*;
+
+	diesel::delete(admin_sessions.filter(token_hash.eq(hash_key(token)))).execute(conn)
+}
+
+pub fn info(session: &AdminSession, account: &AdminAccount) -> Option<SessionInfo> {
+	Some(SessionInfo { username: account.username.clone(),
+	                   role: account.role.parse().ok()?,
+	                   csrf_token: session.csrf_token.clone(),
+	                   expires_at: session.expires_at })
+}
+
+pub fn csrf_matches(session: &AdminSession, token: &str) -> bool { tokens_match(&session.csrf_token, token) }
+
+/// Constant time comparison of CSRF tokens.
+pub fn tokens_match(expected: &str, token: &str) -> bool {
+	ring::constant_time::verify_slices_are_equal(expected.as_bytes(), token.trim().as_bytes()).is_ok()
+}
@@ -1,6 +1,12 @@
 use std::collections::HashSet;
+use std::fmt;
+use actix_web::ResponseError;
+use actix_web::http::header::{HeaderName, HeaderValue};
 use serde::{Serialize, Deserialize};
+use chrono::NaiveDateTime;
 use crate::db::models::User as DbUser;
+use crate::db::models::VestingSchedule;
+use crate::tasks::SubmissionView;
 use crate::recaptcha::Code;
 use num_format::{Locale, ToFormattedString};
 
@@ -13,8 +19,23 @@ pub struct Reg {
 	pub terms: bool,
 	/// Ethereum address
 	pub address: String,
+	/// Ethereum address of the referrer
+	#[serde(default)]
+	pub referrer: Option<String>,
 
 	pub recaptcha: String,
+	/// From `/1.0/form-token` when the form was shown
+	#[serde(default)]
+	pub form_token: Option<String>,
+	/// reCAPTCHA v2 answer when a step-up challenge was asked for
+	#[serde(default)]
+	pub challenge: Option<String>,
+	/// Honeypot, hidden in the form
+	#[serde(default)]
+	pub website: Option<String>,
+	/// Honeypot, hidden in the form
+	#[serde(default)]
+	pub email: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -25,6 +46,19 @@ pub struct Get {
 	pub recaptcha: String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Submit {
+	/// Ethereum address
+	pub address: String,
+	pub task_id: i32,
+	/// Link to the work done
+	pub proof_url: String,
+	#[serde(default)]
+	pub proof_text: String,
+
+	pub recaptcha: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Resp {
 	pub error: Option<Error>,
@@ -45,10 +79,17 @@ impl From<User> for Resp {
 	}
 }
 
-impl From<DbUser> for Resp {
-	fn from(user: DbUser) -> Self {
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubmissionsResp {
+	pub error: Option<Error>,
+	pub submissions: Option<Vec<SubmissionView>>,
+}
+
+impl From<Vec<SubmissionView>> for SubmissionsResp {
+	fn from(submissions: Vec<SubmissionView>) -> Self {
 		Self { error: None,
-		       user: Some(user.into()) }
+		       submissions: Some(submissions) }
 	}
 }
 
@@ -57,14 +98,27 @@ impl From<DbUser> for Resp {
 pub struct User {
 	/// Ethereum address
 	pub address: String,
-	/// Amount of AKRO
+	/// Total amount of AKRO
 	pub amount: String,
+	/// Unlocked to date
+	pub vested: String,
+	/// Not unlocked yet
+	pub locked: String,
+	/// pending, sent, confirmed or failed
+	pub payout_status: String,
+	/// Hash of the payout transaction
+	pub payout_tx: Option<String>,
 }
 
-impl From<crate::db::models::User> for User {
-	fn from(user: DbUser) -> Self {
+impl User {
+	pub fn new(user: DbUser, vesting: Option<&VestingSchedule>, now: NaiveDateTime) -> Self {
+		let vested = crate::vesting::vested(vesting, user.amount, now);
 		Self { address: user.address.to_owned(),
-		       amount: user.amount.to_formatted_string(&Locale::en)
+		       amount: user.amount.to_formatted_string(&Locale::en),
+		       vested: vested.to_formatted_string(&Locale::en),
+		       locked: (user.amount - vested).to_formatted_string(&Locale::en),
+		       payout_status: user.payout_status().to_string(),
+		       payout_tx: user.payout_tx,
 		}
 	}
 }
@@ -83,6 +137,18 @@ impl Error {
 			       404 => "User not found".to_owned(),
 			       901 => "User should not be resident".to_owned(),
 			       902 => "User have to accept Terms & Conditions".to_owned(),
+			       903 => "Self-referral is not allowed".to_owned(),
+			       904 => "Referral cycle is not allowed".to_owned(),
+			       905 => "Referrer not found".to_owned(),
+			       907 => "Task not found".to_owned(),
+			       908 => "Task already submitted".to_owned(),
+			       909 => "Invalid proof".to_owned(),
+			       910 => "Registration is not available in your country".to_owned(),
+			       911 => "Address is not eligible".to_owned(),
+			       912 => "Registrations from your network are not allowed".to_owned(),
+			       913 => "Additional verification required".to_owned(),
+			       914 => "Registration looks automated".to_owned(),
+			       429 => "Too many requests".to_owned(),
 			       _ => "Unknown Internal Error".to_owned(),
 		       } }
 	}
@@ -94,7 +160,20 @@ pub enum ApiError {
 	UserNotFound,
 	UserIsResident,
 	TermsNotAccepted,
+	SelfReferral,
+	ReferralCycle,
+	UnknownReferrer,
+	TaskNotFound,
+	AlreadySubmitted,
+	InvalidProof,
+	RestrictedCountry,
+	AddressBlocked,
+	NetworkBlocked,
+	StepUpRequired,
+	RiskDenied,
+	RateLimited,
 	RecaptchaErr(HashSet<Code>),
+	Internal,
 }
 
 impl ApiError {
@@ -105,6 +184,19 @@ impl ApiError {
 			UserNotFound => Error::new(404).into(),
 			UserIsResident => Error::new(901).into(),
 			TermsNotAccepted => Error::new(902).into(),
+			SelfReferral => Error::new(903).into(),
+			ReferralCycle => Error::new(904).into(),
+			UnknownReferrer => Error::new(905).into(),
+			TaskNotFound => Error::new(907).into(),
+			AlreadySubmitted => Error::new(908).into(),
+			InvalidProof => Error::new(909).into(),
+			RestrictedCountry => Error::new(910).into(),
+			AddressBlocked => Error::new(911).into(),
+			NetworkBlocked => Error::new(912).into(),
+			StepUpRequired => Error::new(913).into(),
+			RiskDenied => Error::new(914).into(),
+			RateLimited => Error::new(429).into(),
+			Internal => Error::new(500).into(),
 			RecaptchaErr(err) => {
 				Error { code: 906,
 				        message: format!("{}", err.iter().fold(String::new(), |a, c| format!("{} {}", a, c))).trim().to_string() }.into()
@@ -114,6 +206,39 @@ impl ApiError {
 }
 
 
+/// An error leaving a middleware that sets headers on every response,
+/// answered like the error plus those headers.
+pub struct WithHeaders {
+	error: actix_web::Error,
+	headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl WithHeaders {
+	pub fn new(error: actix_web::Error, headers: Vec<(HeaderName, HeaderValue)>) -> Self { Self { error, headers } }
+
+	fn with_headers(&self, mut resp: actix_web::HttpResponse) -> actix_web::HttpResponse {
+		for (name, value) in &self.headers {
+			resp.headers_mut().insert(name.clone(), value.clone());
+		}
+		resp
+	}
+}
+
+impl fmt::Debug for WithHeaders {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { fmt::Debug::fmt(&self.error, f) }
+}
+
+impl fmt::Display for WithHeaders {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { fmt::Display::fmt(&self.error, f) }
+}
+
+impl ResponseError for WithHeaders {
+	fn error_response(&self) -> actix_web::HttpResponse { self.with_headers(self.error.as_response_error().error_response()) }
+
+	fn render_response(&self) -> actix_web::HttpResponse { self.with_headers(self.error.as_response_error().render_response()) }
+}
+
+
 // #[derive(Debug, Serialize, Deserialize)]
 // pub struct Req<T> {
 // 	pub recaptcha: String,
@@ -0,0 +1,62 @@
+//! Registration attempts with what is known about the client, kept for abuse investigation.
+
+use std::net::IpAddr;
+use actix_web::http::header;
+use actix_web::HttpRequest;
+use diesel::prelude::*;
+
+use crate::api;
+use crate::db::TheConnection;
+use crate::db::models::NewRegistrationAttempt;
+
+
+/// What a registration attempt ended with, from the response sent.
+pub fn outcome(resp: &actix_web::HttpResponse) -> &'static str {
+	let status = resp.status();
+	if status.is_server_error() {
+		"error"
+	} else if status.is_success() || status.is_redirection() {
+		"registered"
+	} else {
+		"rejected"
+	}
+}
+
+/// Records an attempt. Failures are logged, they must not fail the registration.
+pub fn record(conn: &TheConnection,
+              req: &HttpRequest,
+              data: &api::Reg,
+              ip: Option<IpAddr>,
+              ip_list: Option<&str>,
+              risk: Option<&crate::risk::Assessment>,
+              outcome: &str) {
+	use crate::db::schema::registration_attempts::dsl::registration_attempts;
+
+	let referrer = data.referrer
+	                   .as_ref()
+	                   .map(|referrer| referrer.trim().to_lowercase())
+	                   .filter(|referrer| !referrer.is_empty());
+	let _query = crate::db::instrument("record attempt");
+	let attempt = NewRegistrationAttempt { address: &data.address,
+	                                       ip: ip.map(|ip| ip.to_string()),
+	                                       ip_list,
+	                                       outcome,
+	                                       created_at: chrono::Utc::now().naive_utc(),
+	                                       user_agent: req.headers()
+	                                                      .get(header::USER_AGENT)
+	                                                      .and_then(|value| value.to_str().ok()),
+	                                       referrer: referrer.as_ref().map(String::as_str),
+	                                       risk_score: risk.map(|risk| risk.score as i32),
+	                                       risk_factors: risk.map(crate::risk::Assessment::factors_json),
+	                                       risk_decision: risk.map(|risk| risk.decision.as_str()) };
+	if let Err(err) = diesel::insert_into(registration_attempts).values(&attempt).execute(conn) {
+		log::error!("recording registration attempt: {:?}", err);
+	}
+}
+
+/// Deletes the attempts recorded before `before`, returns how many.
+pub fn purge(conn: &TheConnection, before: chrono::NaiveDateTime) -> QueryResult<usize> {
+	use crate::db::schema::registration_attempts::dsl::*;
+
+	diesel::delete(registration_attempts.filter(created_at.lt(before))).execute(conn)
+}
@@ -0,0 +1,206 @@
+//! Offline commands: `bounty-server <command> [--option value]...`
+//!
+//! Without a command the binary starts the http server.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use failure::{format_err, Error};
+
+use crate::config::Config;
+
+
+pub struct Args {
+	opts: HashMap<String, String>,
+	flags: Vec<String>,
+}
+
+impl Args {
+	pub fn parse<I: Iterator<Item = String>>(args: I) -> Result<Self, Error> {
+		let mut opts = HashMap::new();
+		let mut flags = Vec::new();
+		let mut args = args.peekable();
+
+		while let Some(arg) = args.next() {
+			if !arg.starts_with("--") {
+				return Err(format_err!("unexpected argument: {}", arg));
+			}
+			let name = arg.trim_start_matches("--").to_owned();
+			match args.peek() {
+				Some(value) if !value.starts_with("--") => {
+					opts.insert(name, args.next().unwrap());
+				},
+				_ => flags.push(name),
+			}
+		}
+
+		Ok(Self { opts, flags })
+	}
+
+	pub fn flag(&self, name: &str) -> bool { self.flags.iter().any(|f| f == name) }
+
+	pub fn get(&self, name: &str) -> Option<&str> { self.opts.get(name).map(String::as_str) }
+
+	pub fn require(&self, name: &str) -> Result<&str, Error> {
+		self.get(name).ok_or_else(|| format_err!("--{} is required", name))
+	}
+
+	pub fn parse_opt<T: FromStr>(&self, name: &str) -> Result<Option<T>, Error>
+		where T::Err: std::fmt::Display {
+		match self.get(name) {
+			Some(value) => value.parse()
+			                    .map(Some)
+			                    .map_err(|err| format_err!("--{}: {}", name, err)),
+			None => Ok(None),
+		}
+	}
+
+	pub fn parse_required<T: FromStr>(&self, name: &str) -> Result<T, Error>
+		where T::Err: std::fmt::Display {
+		self.parse_opt(name)?.ok_or_else(|| format_err!("--{} is required", name))
+	}
+}
+
+
+/// Runs the command if one is given on the command line.
+/// Returns `None` when the server should be started instead.
+pub fn run(config: &Config) -> Option<Result<(), Error>> {
+	let mut args = std::env::args().skip(1);
+	let command = args.next()?;
+
+	let result = Args::parse(args).and_then(|args| {
+		                              match command.as_str() {
+			                              "sign-payouts" => crate::payout::sign_payouts(config, &args),
+			                              "import-payouts" => crate::payout::import_payouts(config, &args),
+			                              "withheld-report" => crate::payout::withheld_report(config, &args),
+			                              "sybil-report" => crate::sybil::report(config, &args),
+			                              "add-task" => add_task(config, &args),
+			                              "submissions" => submissions(config, &args),
+			                              "moderate" => moderate(config, &args),
+			                              "create-api-key" => create_api_key(config, &args),
+			                              "revoke-api-key" => revoke_api_key(config, &args),
+			                              "create-admin" => create_admin(config, &args),
+			                              "purge-attempts" => purge_attempts(config, &args),
+		                                _ => Err(format_err!("unknown command: {}", command)),
+		                              }
+		                             });
+	Some(result)
+}
+
+
+/// `add-task --title TEXT --reward N [--description TEXT]`
+fn add_task(config: &Config, args: &Args) -> Result<(), Error> {
+	let conn = establish_connection(config)?;
+	crate::tasks::create_task(&conn,
+	                          args.require("title")?,
+	                          args.get("description").unwrap_or_default(),
+	                          args.parse_required("reward")?)?;
+	Ok(())
+}
+
+/// `submissions` prints the moderation queue as JSON.
+fn submissions(config: &Config, _args: &Args) -> Result<(), Error> {
+	let conn = establish_connection(config)?;
+	let queue = crate::tasks::queue(&conn)?;
+	println!("{}", serde_json::to_string_pretty(&queue)?);
+	Ok(())
+}
+
+/// `moderate --submission ID (--approve | --reject) --reason TEXT [--moderator NAME]`
+fn moderate(config: &Config, args: &Args) -> Result<(), Error> {
+	use crate::tasks::Decision;
+
+	let decision = match (args.flag("approve"), args.flag("reject")) {
+		(true, false) => Decision::Approve,
+		(false, true) => Decision::Reject,
+		_ => return Err(format_err!("one of --approve or --reject is required")),
+	};
+	let moderator = match args.get("moderator") {
+		Some(moderator) => moderator.to_owned(),
+		None => std::env::var("USER").map_err(|_| format_err!("--moderator is required"))?,
+	};
+
+	let conn = establish_connection(config)?;
+	crate::tasks::moderate(&conn, args.parse_required("submission")?, decision, args.require("reason")?, &moderator)?;
+	Ok(())
+}
+
+
+/// `purge-attempts --older-than DAYS` deletes the registration attempts recorded before that.
+fn purge_attempts(config: &Config, args: &Args) -> Result<(), Error> {
+	let days: i64 = args.parse_required("older-than")?;
+	if days < 1 {
+		return Err(format_err!("--older-than: at least 1 day"));
+	}
+	let before = chrono::Utc::now().naive_utc() - chrono::Duration::days(days);
+
+	let conn = establish_connection(config)?;
+	let deleted = crate::attempts::purge(&conn, before)?;
+	log::info!("{} registration attempts before {} deleted", deleted, before);
+	Ok(())
+}
+
+
+/// `create-api-key --name NAME --role (viewer | support | admin)` prints the new key.
+fn create_api_key(config: &Config, args: &Args) -> Result<(), Error> {
+	use crate::admin::auth::{self, Role};
+
+	let role: Role = args.require("role")?.parse().map_err(|err: String| format_err!("--role: {}", err))?;
+	let conn = establish_connection(config)?;
+	let key = auth::create_key(&conn, args.require("name")?, role)?;
+	println!("{}", key);
+	Ok(())
+}
+
+/// `revoke-api-key --id N`
+fn revoke_api_key(config: &Config, args: &Args) -> Result<(), Error> {
+	let conn = establish_connection(config)?;
+	if crate::admin::auth::revoke_key(&conn, args.parse_required("id")?)? == 0 {
+		return Err(format_err!("api key not found"));
+	}
+	Ok(())
+}
+
+
+/// `create-admin --username NAME [--role admin]`, the password is read from stdin.
+/// Prints the TOTP secret to enroll in an authenticator app.
+fn create_admin(config: &Config, args: &Args) -> Result<(), Error> {
+	use crate::admin::auth::Role;
+	use crate::admin::{session, totp};
+
+	let username = args.require("username")?;
+	let role: Role = args.get("role")
+	                     .unwrap_or("admin")
+	                     .parse()
+	                     .map_err(|err: String| format_err!("--role: {}", err))?;
+
+	eprintln!("password:");
+	let mut password = String::new();
+	std::io::stdin().read_line(&mut password)?;
+	let password = password.trim_end_matches(&['\r', '\n'][..]);
+	if password.len() < 12 {
+		return Err(format_err!("password must be at least 12 characters"));
+	}
+
+	let conn = establish_connection(config)?;
+	let secret = session::create_account(&conn, username, password, role).map_err(|err| format_err!("{:?}", err))?;
+	println!("TOTP secret: {}", secret);
+	println!("{}", totp::uri("Bounty Admin", username, &secret));
+	Ok(())
+}
+
+
+pub fn establish_connection(config: &Config) -> Result<crate::db::TheConnection, Error> {
+	use diesel::Connection;
+	Ok(crate::db::TheConnection::establish(config.database.url()?)?)
+}
+
+
+#[test]
+fn args_test() {
+	let args = Args::parse(vec!["--chain-id", "1", "--eip1559", "--nonce", "7"].into_iter()
+	                                                                          .map(String::from)).unwrap();
+	assert!(args.flag("eip1559"));
+	assert_eq!(args.parse_required::<u64>("chain-id").unwrap(), 1);
+	assert_eq!(args.parse_opt::<u64>("nonce").unwrap(), Some(7));
+	assert!(args.require("keystore").is_err());
+}
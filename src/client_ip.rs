@@ -0,0 +1,305 @@
+//! Client address of a request.
+//!
+//! `X-Forwarded-For` or `Forwarded` (RFC 7239), whichever `client_ip.header` (`CLIENT_IP_HEADER`) names,
+//! is honoured only when the peer is one of the `client_ip.trusted_proxies` (`TRUSTED_PROXIES`),
+//! addresses or CIDRs, none by default. The chain is walked from the nearest hop back and the first address
+//! that is not a trusted proxy is the client. Requests over a unix socket come from a proxy on this host
+//! and are trusted like one in the list; without a header their client is `127.0.0.1`.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use actix_web::dev::Extensions;
+use actix_web::http::header::{HeaderMap, FORWARDED};
+use actix_web::HttpRequest;
+
+use crate::state::State;
+
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+
+/// An address block like `10.0.0.0/8` or `2001:db8::/32`, a bare address is a single host.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+	network: IpAddr,
+	prefix: u8,
+}
+
+impl Cidr {
+	pub fn contains(&self, ip: IpAddr) -> bool {
+		match (self.network, canonical(ip)) {
+			(IpAddr::V4(network), IpAddr::V4(ip)) => {
+				mask(u128::from(u32::from(network)), self.prefix, 32) == mask(u128::from(u32::from(ip)), self.prefix, 32)
+			},
+			(IpAddr::V6(network), IpAddr::V6(ip)) => {
+				mask(u128::from(network), self.prefix, 128) == mask(u128::from(ip), self.prefix, 128)
+			},
+			_ => false,
+		}
+	}
+
+	pub fn network(&self) -> IpAddr { self.network }
+
+	pub fn prefix(&self) -> u8 { self.prefix }
+}
+
+fn mask(bits: u128, prefix: u8, width: u8) -> u128 {
+	if prefix == 0 {
+		0
+	} else {
+		bits & (!0u128 << (width - prefix))
+	}
+}
+
+impl std::str::FromStr for Cidr {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let s = s.trim();
+		let (address, prefix) = match s.find('/') {
+			Some(pos) => (&s[..pos], Some(&s[pos + 1..])),
+			None => (s, None),
+		};
+		let network = canonical(address.parse().map_err(|_| format!("invalid address in {:?}", s))?);
+		let width = if network.is_ipv4() { 32 } else { 128 };
+		let prefix = match prefix {
+			Some(prefix) => {
+				prefix.parse::<u8>()
+				      .ok()
+				      .filter(|prefix| *prefix <= width)
+				      .ok_or_else(|| format!("invalid prefix in {:?}", s))?
+			},
+			None => width,
+		};
+		Ok(Cidr { network, prefix })
+	}
+}
+
+/// IPv4-mapped IPv6 addresses as IPv4.
+pub fn canonical(ip: IpAddr) -> IpAddr {
+	match ip {
+		IpAddr::V6(v6) => {
+			let segments = v6.segments();
+			if segments[..5] == [0; 5] && segments[5] == 0xffff {
+				IpAddr::V4(Ipv4Addr::new((segments[6] >> 8) as u8,
+				                         segments[6] as u8,
+				                         (segments[7] >> 8) as u8,
+				                         segments[7] as u8))
+			} else {
+				ip
+			}
+		},
+		ip => ip,
+	}
+}
+
+
+/// The forwarding header set by the trusted proxies. Only that one is read,
+/// a proxy passes the other one on from the client as is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Header {
+	XForwardedFor,
+	Forwarded,
+}
+
+impl Default for Header {
+	fn default() -> Self { Header::XForwardedFor }
+}
+
+impl std::str::FromStr for Header {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.trim().to_lowercase().as_str() {
+			X_FORWARDED_FOR => Ok(Header::XForwardedFor),
+			"forwarded" => Ok(Header::Forwarded),
+			_ => Err(format!("unsupported header {:?}", s)),
+		}
+	}
+}
+
+
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+	pub trusted_proxies: Vec<Cidr>,
+	pub header: Header,
+}
+
+impl Settings {
+	pub fn from_config(config: &crate::config::ClientIp) -> Result<Self, String> {
+		let trusted_proxies = config.trusted_proxies
+		                            .iter()
+		                            .map(|item| item.parse().map_err(|err| format!("client_ip.trusted_proxies: {}", err)))
+		                            .collect::<Result<_, _>>()?;
+		let header = config.header.parse().map_err(|err| format!("client_ip.header: {}", err))?;
+		Ok(Self { trusted_proxies, header })
+	}
+
+	fn trusts(&self, ip: IpAddr) -> bool { self.trusted_proxies.iter().any(|cidr| cidr.contains(ip)) }
+}
+
+
+/// Request extension set by `crate::uds` on requests that came over a unix socket, which have no peer address.
+#[derive(Debug, Clone, Copy)]
+pub struct UnixPeer;
+
+/// The client address of the request.
+pub fn resolve(req: &HttpRequest) -> Option<IpAddr> { resolve_parts(req.peer_addr(), &req.extensions(), req.headers()) }
+
+/// The client address from the peer, the request extensions and headers, for middleware without an `HttpRequest`.
+pub fn resolve_parts(peer: Option<SocketAddr>, extensions: &Extensions, headers: &HeaderMap) -> Option<IpAddr> {
+	let state = State::try_get();
+	if extensions.get::<UnixPeer>().is_some() {
+		let local = IpAddr::V4(Ipv4Addr::LOCALHOST);
+		return Some(state.map(|state| forwarded_client(state.client_ip(), local, headers)).unwrap_or(local));
+	}
+	let peer = canonical(peer?.ip());
+	match state {
+		Some(state) => Some(resolve_with(state.client_ip(), peer, headers)),
+		None => Some(peer),
+	}
+}
+
+fn resolve_with(settings: &Settings, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+	if !settings.trusts(peer) {
+		return peer;
+	}
+	forwarded_client(settings, peer, headers)
+}
+
+/// Walks the forwarding header back from `hop`, a trusted proxy.
+fn forwarded_client(settings: &Settings, hop: IpAddr, headers: &HeaderMap) -> IpAddr {
+	let chain = match settings.header {
+		Header::XForwardedFor => x_forwarded_for(headers),
+		Header::Forwarded => forwarded_for(headers),
+	};
+
+	let mut client = hop;
+	for hop in chain.iter().rev() {
+		match hop {
+			// obfuscated or unknown hops end the part of the chain we can follow
+			None => break,
+			Some(ip) => {
+				client = canonical(*ip);
+				if !settings.trusts(client) {
+					break;
+				}
+			},
+		}
+	}
+	client
+}
+
+/// `X-Forwarded-For` hops, client first. Repeated headers continue the list.
+fn x_forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+	headers.get_all(X_FORWARDED_FOR)
+	       .filter_map(|value| value.to_str().ok())
+	       .flat_map(|value| value.split(','))
+	       .map(parse_node)
+	       .collect()
+}
+
+/// `for=` of every `Forwarded` element, client first.
+fn forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+	headers.get_all(FORWARDED)
+	       .filter_map(|value| value.to_str().ok())
+	       .flat_map(|value| split_unquoted(value, ','))
+	       .map(|element| {
+		       split_unquoted(element, ';').into_iter().find_map(|pair| {
+			                                   let mut parts = pair.splitn(2, '=');
+			                                   let name = parts.next()?.trim();
+			                                   let value = parts.next()?.trim();
+			                                   if name.eq_ignore_ascii_case("for") {
+				                                   Some(parse_node(value.trim_matches('"')))
+				                                  } else {
+				                                   None
+				                                  }
+			                                  })
+		                                   .unwrap_or(None)
+		      })
+	       .collect()
+}
+
+/// Splits on `sep` outside of quoted strings.
+fn split_unquoted(s: &str, sep: char) -> Vec<&str> {
+	let mut parts = Vec::new();
+	let mut quoted = false;
+	let mut start = 0;
+	for (i, c) in s.char_indices() {
+		match c {
+			'"' => quoted = !quoted,
+			c if c == sep && !quoted => {
+				parts.push(&s[start..i]);
+				start = i + c.len_utf8();
+			},
+			_ => (),
+		}
+	}
+	parts.push(&s[start..]);
+	parts
+}
+
+/// A node is `1.2.3.4`, `1.2.3.4:port`, `[v6]`, `[v6]:port` or a bare v6 address,
+/// `unknown` and `_obfuscated` identifiers are `None`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+	let node = node.trim();
+	if node.starts_with('[') {
+		let end = node.find(']')?;
+		return node[1..end].parse::<Ipv6Addr>().ok().map(IpAddr::V6);
+	}
+	if let Ok(ip) = node.parse::<IpAddr>() {
+		return Some(ip);
+	}
+	node.parse::<SocketAddr>().ok().map(|addr| addr.ip())
+}
+
+
+#[test]
+fn resolve_test() {
+	use actix_web::http::header::HeaderValue;
+
+	let mut settings = Settings { trusted_proxies: vec!["10.0.0.0/8".parse().unwrap(), "2001:db8::/32".parse().unwrap()],
+	                              header: Header::XForwardedFor };
+	let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+	let headers = |name: &'static str, value: &'static str| {
+		let mut headers = HeaderMap::new();
+		headers.insert(actix_web::http::header::HeaderName::from_static(name), HeaderValue::from_static(value));
+		headers
+	};
+
+	// untrusted peers can not claim another address
+	let spoofed = headers("x-forwarded-for", "198.51.100.1");
+	assert_eq!(resolve_with(&settings, ip("203.0.113.7"), &spoofed), ip("203.0.113.7"));
+	assert_eq!(resolve_with(&settings, ip("10.0.0.1"), &spoofed), ip("198.51.100.1"));
+
+	// spoofed entries before the first untrusted hop are ignored
+	let chain = headers("x-forwarded-for", "192.0.2.1, 203.0.113.7, 10.1.1.1");
+	assert_eq!(resolve_with(&settings, ip("10.0.0.1"), &chain), ip("203.0.113.7"));
+
+	// behind an `X-Forwarded-For` proxy a client sent `Forwarded` is not read
+	let mut both = headers("x-forwarded-for", "203.0.113.7");
+	both.insert(FORWARDED, HeaderValue::from_static("for=192.0.2.1"));
+	assert_eq!(resolve_with(&settings, ip("10.0.0.1"), &both), ip("203.0.113.7"));
+	assert_eq!(resolve_with(&settings, ip("10.0.0.1"), &headers("forwarded", "for=192.0.2.1")), ip("10.0.0.1"));
+
+	settings.header = Header::Forwarded;
+	assert_eq!(resolve_with(&settings, ip("10.0.0.1"), &spoofed), ip("10.0.0.1"));
+	let forwarded = headers("forwarded", "for=192.0.2.1;proto=https, for=\"[2001:db8:cafe::17]:4711\";by=10.0.0.2");
+	assert_eq!(resolve_with(&settings, ip("10.0.0.1"), &forwarded), ip("192.0.2.1"));
+	let hidden = headers("forwarded", "for=192.0.2.1, for=_hidden");
+	assert_eq!(resolve_with(&settings, ip("10.0.0.1"), &hidden), ip("10.0.0.1"));
+
+	assert_eq!(resolve_with(&settings, ip("10.0.0.1"), &both), ip("192.0.2.1"));
+
+	settings.header = Header::XForwardedFor;
+	assert_eq!(resolve_with(&settings, ip("::ffff:10.0.0.1"), &spoofed), ip("198.51.100.1"));
+
+	// the unix socket hop is trusted without a listed proxy
+	let settings = Settings::default();
+	assert_eq!(forwarded_client(&settings, ip("127.0.0.1"), &chain), ip("10.1.1.1"));
+	assert_eq!(forwarded_client(&settings, ip("127.0.0.1"), &HeaderMap::new()), ip("127.0.0.1"));
+	assert_eq!(resolve_with(&settings, ip("127.0.0.1"), &spoofed), ip("127.0.0.1"));
+	assert_eq!("Forwarded".parse(), Ok(Header::Forwarded));
+	assert!("x-real-ip".parse::<Header>().is_err());
+	assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+	assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(ip("198.51.100.1")));
+}
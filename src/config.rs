@@ -0,0 +1,561 @@
+//! Typed configuration: built-in defaults, then the TOML file at `CONFIG_FILE` (`bounty.toml` by default,
+//! skipped when that default file does not exist), then environment variables, which keep the names
+//! used before the file existed (`LISTEN_URL`, `RECAPTCHA_KEY`, ...). See `bounty.example.toml`.
+//!
+//! `Config::load` only reads the sources, `Config::validate` checks everything the server needs
+//! and reports all problems at once. Offline commands only need the database section.
+
+use std::env;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use failure::{format_err, Error};
+use serde::Deserialize;
+
+
+const DEFAULT_FILE: &str = "bounty.toml";
+const DEFAULT_RELOAD_SECS: u64 = 300;
+
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+	pub server: Server,
+	pub tls: Tls,
+	pub cors: Cors,
+	pub captcha: Captcha,
+	pub database: Database,
+	pub features: Features,
+	pub log: Log,
+	pub telemetry: Telemetry,
+	pub client_ip: ClientIp,
+	pub rate_limit: RateLimit,
+	pub referral: Referral,
+	pub admin: Admin,
+	pub stats: Stats,
+	pub geoip: Geoip,
+	pub screening: Screening,
+	pub reputation: Reputation,
+	pub risk: Risk,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Server {
+	/// `host:port` of the public listener
+	pub listen_url: Option<String>,
+	/// `host:port` serving `/metrics`, off when unset
+	pub metrics_url: Option<String>,
+	/// Path of a unix socket serving the public API
+	pub unix_socket: Option<PathBuf>,
+	/// Octal permissions of the socket file
+	pub unix_socket_mode: String,
+	/// How long listeners keep accepting after a stop signal while readiness fails
+	pub shutdown_delay_secs: u64,
+	/// How long requests in flight may take after that
+	pub shutdown_timeout_secs: u64,
+	/// Written at startup and removed on exit
+	pub pid_file: Option<PathBuf>,
+}
+
+impl Default for Server {
+	fn default() -> Self {
+		Self { listen_url: None,
+		       metrics_url: None,
+		       unix_socket: None,
+		       unix_socket_mode: "660".to_owned(),
+		       shutdown_delay_secs: 0,
+		       shutdown_timeout_secs: 30,
+		       pid_file: None }
+	}
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tls {
+	/// `host:port` of the HTTPS listener, off when unset
+	pub listen_url: Option<String>,
+	/// PEM certificate chain
+	pub cert: Option<PathBuf>,
+	/// PEM private key
+	pub key: Option<PathBuf>,
+	pub reload_secs: u64,
+	/// `host:port` of a plain listener redirecting to HTTPS
+	pub redirect_url: Option<String>,
+	/// Not sent when 0
+	pub hsts_max_age_secs: u64,
+	pub hsts_include_subdomains: bool,
+}
+
+impl Default for Tls {
+	fn default() -> Self {
+		Self { listen_url: None,
+		       cert: None,
+		       key: None,
+		       reload_secs: DEFAULT_RELOAD_SECS,
+		       redirect_url: None,
+		       hsts_max_age_secs: 365 * 24 * 3600,
+		       hsts_include_subdomains: false }
+	}
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Cors {
+	/// Exact origins, `https://*.domain` patterns or `*`
+	pub origins: Vec<String>,
+	/// Request headers pages may send besides the safelisted ones
+	pub allowed_headers: Vec<String>,
+	/// Response headers pages may read
+	pub expose_headers: Vec<String>,
+	/// How long browsers cache a preflight answer
+	pub max_age_secs: u64,
+}
+
+impl Default for Cors {
+	fn default() -> Self {
+		Self { origins: Vec::new(),
+		       allowed_headers: vec!["content-type".to_owned(), "x-request-id".to_owned(), "traceparent".to_owned()],
+		       expose_headers: vec!["x-request-id".to_owned(), "retry-after".to_owned()],
+		       max_age_secs: 3600 }
+	}
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Captcha {
+	/// reCAPTCHA secret
+	pub key: Option<String>,
+	/// Secret of the reCAPTCHA v2 site for step-up challenges
+	pub challenge_key: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Database {
+	pub url: Option<String>,
+	pub pool_size: u32,
+	/// How long a request waits for a pooled connection
+	pub connection_timeout_secs: u64,
+	/// Idle connections are closed after this, kept open when 0
+	pub idle_timeout_secs: u64,
+}
+
+impl Default for Database {
+	fn default() -> Self {
+		Self { url: None,
+		       pool_size: if cfg!(feature = "sqlite") { 1 } else { 4 },
+		       connection_timeout_secs: 30,
+		       idle_timeout_secs: 600 }
+	}
+}
+
+impl Database {
+	pub fn url(&self) -> Result<&str, Error> {
+		self.url.as_ref().map(String::as_str).ok_or_else(|| format_err!("database.url (DATABASE_URL) must be set"))
+	}
+
+	pub fn connection_timeout(&self) -> Duration { Duration::from_secs(self.connection_timeout_secs) }
+
+	pub fn idle_timeout(&self) -> Option<Duration> {
+		Some(self.idle_timeout_secs).filter(|secs| *secs > 0).map(Duration::from_secs)
+	}
+}
+
+/// Parts of the API that can be switched off.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Features {
+	/// `/admin`
+	pub admin: bool,
+	/// `/1.0/tasks` and `/1.0/submissions`
+	pub tasks: bool,
+	/// `/1.0/get` and `/1.0/set`
+	pub query_fallbacks: bool,
+	/// `/1.0/recaptcha_test/`, off unless asked for
+	pub recaptcha_test: bool,
+}
+
+impl Default for Features {
+	fn default() -> Self {
+		Self { admin: true,
+		       tasks: true,
+		       query_fallbacks: true,
+		       recaptcha_test: false }
+	}
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Log {
+	/// `text` or `json`
+	pub format: String,
+}
+
+impl Default for Log {
+	fn default() -> Self { Self { format: "text".to_owned() } }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Telemetry {
+	/// OTLP/HTTP collector, e.g. `http://localhost:4318`, tracing is off when unset
+	pub endpoint: Option<String>,
+	pub service_name: String,
+}
+
+impl Default for Telemetry {
+	fn default() -> Self {
+		Self { endpoint: None,
+		       service_name: "bounty-server".to_owned() }
+	}
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientIp {
+	/// Addresses or CIDRs
+	pub trusted_proxies: Vec<String>,
+	/// `x-forwarded-for` or `forwarded`, whichever the proxies set
+	pub header: String,
+}
+
+impl Default for ClientIp {
+	fn default() -> Self {
+		Self { trusted_proxies: Vec::new(),
+		       header: "x-forwarded-for".to_owned() }
+	}
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimit {
+	/// `memory` or `postgres`
+	pub store: String,
+	/// `N/SECS` or `off`
+	pub lookup_ip: String,
+	pub lookup_address: String,
+	pub register_ip: String,
+	pub register_address: String,
+}
+
+impl Default for RateLimit {
+	fn default() -> Self {
+		Self { store: "memory".to_owned(),
+		       lookup_ip: "30/60".to_owned(),
+		       lookup_address: "10/60".to_owned(),
+		       register_ip: "10/60".to_owned(),
+		       register_address: "5/3600".to_owned() }
+	}
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Referral {
+	/// `flat:N` or `percent:N`, no bonus when unset
+	pub bonus: Option<String>,
+	/// Max total bonus per referrer
+	pub bonus_cap: Option<i64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Admin {
+	/// `false` to allow the session cookie over plain http, e.g. for local testing
+	pub cookie_secure: bool,
+}
+
+impl Default for Admin {
+	fn default() -> Self { Self { cookie_secure: true } }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Stats {
+	pub cache_secs: u64,
+}
+
+impl Default for Stats {
+	fn default() -> Self { Self { cache_secs: 60 } }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Geoip {
+	pub database: Option<PathBuf>,
+	/// ISO 3166-1 alpha-2 codes
+	pub restricted_countries: Vec<String>,
+	/// `reject` or `flag`
+	pub policy: String,
+	pub reload_secs: u64,
+}
+
+impl Default for Geoip {
+	fn default() -> Self {
+		Self { database: None,
+		       restricted_countries: Vec::new(),
+		       policy: "reject".to_owned(),
+		       reload_secs: DEFAULT_RELOAD_SECS }
+	}
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Screening {
+	pub blocklists: Vec<PathBuf>,
+	pub allowlists: Vec<PathBuf>,
+	pub reload_secs: u64,
+}
+
+impl Default for Screening {
+	fn default() -> Self {
+		Self { blocklists: Vec::new(),
+		       allowlists: Vec::new(),
+		       reload_secs: DEFAULT_RELOAD_SECS }
+	}
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Reputation {
+	/// `name:policy:path` entries
+	pub lists: Vec<String>,
+	pub reload_secs: u64,
+}
+
+impl Default for Reputation {
+	fn default() -> Self {
+		Self { lists: Vec::new(),
+		       reload_secs: DEFAULT_RELOAD_SECS }
+	}
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Risk {
+	pub step_up: u32,
+	pub deny: u32,
+	pub min_form_secs: i64,
+	pub max_form_secs: i64,
+	/// Signs form tokens, random per process when unset
+	pub token_secret: Option<String>,
+}
+
+impl Default for Risk {
+	fn default() -> Self {
+		Self { step_up: 40,
+		       deny: 70,
+		       min_form_secs: 3,
+		       max_form_secs: 3600,
+		       token_secret: None }
+	}
+}
+
+
+impl Config {
+	/// Reads the file and the environment, see the module docs.
+	pub fn load() -> Result<Self, Error> {
+		let (path, required) = match env::var("CONFIG_FILE") {
+			Ok(path) => (PathBuf::from(path), true),
+			Err(_) => (PathBuf::from(DEFAULT_FILE), false),
+		};
+		let mut config = if required || path.exists() {
+			let content = std::fs::read_to_string(&path).map_err(|err| format_err!("{}: {}", path.display(), err))?;
+			Self::parse(&content).map_err(|err| format_err!("{}: {}", path.display(), err))?
+		} else {
+			Self::default()
+		};
+		config.apply_env().map_err(|err| format_err!("environment: {}", err))?;
+		Ok(config)
+	}
+
+	pub fn parse(content: &str) -> Result<Self, toml::de::Error> { toml::from_str(content) }
+
+	fn apply_env(&mut self) -> Result<(), String> {
+		override_opt(&mut self.server.listen_url, "LISTEN_URL")?;
+		override_opt(&mut self.server.metrics_url, "METRICS_LISTEN_URL")?;
+		override_opt(&mut self.server.unix_socket, "UNIX_SOCKET")?;
+		override_with(&mut self.server.unix_socket_mode, "UNIX_SOCKET_MODE")?;
+		override_with(&mut self.server.shutdown_delay_secs, "SHUTDOWN_DELAY_SECS")?;
+		override_with(&mut self.server.shutdown_timeout_secs, "SHUTDOWN_TIMEOUT_SECS")?;
+		override_opt(&mut self.server.pid_file, "PID_FILE")?;
+		override_opt(&mut self.tls.listen_url, "TLS_LISTEN_URL")?;
+		override_opt(&mut self.tls.cert, "TLS_CERT")?;
+		override_opt(&mut self.tls.key, "TLS_KEY")?;
+		override_with(&mut self.tls.reload_secs, "TLS_RELOAD_SECS")?;
+		override_opt(&mut self.tls.redirect_url, "TLS_REDIRECT_URL")?;
+		override_with(&mut self.tls.hsts_max_age_secs, "TLS_HSTS_MAX_AGE_SECS")?;
+		override_with(&mut self.tls.hsts_include_subdomains, "TLS_HSTS_INCLUDE_SUBDOMAINS")?;
+		override_list(&mut self.cors.origins, "CORS_ORIGIN")?;
+		override_list(&mut self.cors.allowed_headers, "CORS_ALLOWED_HEADERS")?;
+		override_list(&mut self.cors.expose_headers, "CORS_EXPOSE_HEADERS")?;
+		override_with(&mut self.cors.max_age_secs, "CORS_MAX_AGE_SECS")?;
+		override_opt(&mut self.captcha.key, "RECAPTCHA_KEY")?;
+		override_opt(&mut self.captcha.challenge_key, "RECAPTCHA_CHALLENGE_KEY")?;
+		override_opt(&mut self.database.url, "DATABASE_URL")?;
+		override_with(&mut self.database.pool_size, "DATABASE_POOL_SIZE")?;
+		override_with(&mut self.database.connection_timeout_secs, "DATABASE_CONNECTION_TIMEOUT_SECS")?;
+		override_with(&mut self.database.idle_timeout_secs, "DATABASE_IDLE_TIMEOUT_SECS")?;
+		override_with(&mut self.features.admin, "FEATURE_ADMIN")?;
+		override_with(&mut self.features.tasks, "FEATURE_TASKS")?;
+		override_with(&mut self.features.query_fallbacks, "FEATURE_QUERY_FALLBACKS")?;
+		override_with(&mut self.features.recaptcha_test, "FEATURE_RECAPTCHA_TEST")?;
+		override_with(&mut self.log.format, "LOG_FORMAT")?;
+		override_opt(&mut self.telemetry.endpoint, "OTEL_EXPORTER_OTLP_ENDPOINT")?;
+		override_with(&mut self.telemetry.service_name, "OTEL_SERVICE_NAME")?;
+		override_list(&mut self.client_ip.trusted_proxies, "TRUSTED_PROXIES")?;
+		override_with(&mut self.client_ip.header, "CLIENT_IP_HEADER")?;
+		override_with(&mut self.rate_limit.store, "RATE_LIMIT_STORE")?;
+		override_with(&mut self.rate_limit.lookup_ip, "RATE_LIMIT_LOOKUP_IP")?;
+		override_with(&mut self.rate_limit.lookup_address, "RATE_LIMIT_LOOKUP_ADDRESS")?;
+		override_with(&mut self.rate_limit.register_ip, "RATE_LIMIT_REGISTER_IP")?;
+		override_with(&mut self.rate_limit.register_address, "RATE_LIMIT_REGISTER_ADDRESS")?;
+		override_opt(&mut self.referral.bonus, "REFERRAL_BONUS")?;
+		override_opt(&mut self.referral.bonus_cap, "REFERRAL_BONUS_CAP")?;
+		override_with(&mut self.admin.cookie_secure, "ADMIN_COOKIE_SECURE")?;
+		override_with(&mut self.stats.cache_secs, "STATS_CACHE_SECS")?;
+		override_opt(&mut self.geoip.database, "GEOIP_DATABASE")?;
+		override_list(&mut self.geoip.restricted_countries, "GEOIP_RESTRICTED_COUNTRIES")?;
+		override_with(&mut self.geoip.policy, "GEOIP_POLICY")?;
+		override_with(&mut self.geoip.reload_secs, "GEOIP_RELOAD_SECS")?;
+		override_list(&mut self.screening.blocklists, "SCREENING_BLOCKLISTS")?;
+		override_list(&mut self.screening.allowlists, "SCREENING_ALLOWLISTS")?;
+		override_with(&mut self.screening.reload_secs, "SCREENING_RELOAD_SECS")?;
+		override_list(&mut self.reputation.lists, "REPUTATION_LISTS")?;
+		override_with(&mut self.reputation.reload_secs, "REPUTATION_RELOAD_SECS")?;
+		override_with(&mut self.risk.step_up, "RISK_STEP_UP")?;
+		override_with(&mut self.risk.deny, "RISK_DENY")?;
+		override_with(&mut self.risk.min_form_secs, "RISK_MIN_FORM_SECS")?;
+		override_with(&mut self.risk.max_form_secs, "RISK_MAX_FORM_SECS")?;
+		override_opt(&mut self.risk.token_secret, "RISK_TOKEN_SECRET")?;
+		Ok(())
+	}
+
+	/// Checks everything the server needs, including the formats the sections are parsed from later.
+	/// Lists files are not read here.
+	pub fn validate(&self) -> Result<(), Error> {
+		let mut errors = Vec::new();
+		let mut check = |result: Result<(), String>| {
+			if let Err(err) = result {
+				errors.push(err);
+			}
+		};
+
+		if self.server.listen_url.is_none() && self.tls.listen_url.is_none() && self.server.unix_socket.is_none()
+		   && !crate::systemd::Activated::pending()
+		{
+			let listeners = "server.listen_url (LISTEN_URL), tls.listen_url (TLS_LISTEN_URL) or server.unix_socket (UNIX_SOCKET)";
+			check(Err(format!("{} must be set without socket activation", listeners)));
+		}
+		check(required(&self.captcha.key, "captcha.key (RECAPTCHA_KEY)"));
+		check(required(&self.database.url, "database.url (DATABASE_URL)"));
+		if self.cors.origins.is_empty() {
+			check(Err("cors.origins (CORS_ORIGIN) must list at least one origin".to_owned()));
+		}
+		if self.database.pool_size == 0 {
+			check(Err("database.pool_size must be at least 1".to_owned()));
+		}
+		if self.database.connection_timeout_secs == 0 {
+			check(Err("database.connection_timeout_secs must be at least 1".to_owned()));
+		}
+		check(crate::uds::parse_mode(&self.server.unix_socket_mode).map(drop));
+		check(crate::tls::Settings::from_config(&self.tls).map(drop));
+		check(crate::cors::Policy::from_config(&self.cors).map(drop));
+		check(self.log.format.parse::<crate::logging::Format>().map(drop));
+		check(crate::client_ip::Settings::from_config(&self.client_ip).map(drop));
+		check(crate::ratelimit::Settings::from_config(&self.rate_limit).map(drop));
+		check(crate::referral::Settings::from_config(&self.referral).map(drop));
+		check(crate::geoip::Settings::from_config(&self.geoip).map(drop));
+		check(crate::reputation::Settings::from_config(&self.reputation).map(drop));
+		check(crate::risk::Settings::from_config(&self.risk).map(drop));
+
+		if errors.is_empty() {
+			Ok(())
+		} else {
+			Err(format_err!("{}", errors.join("\n")))
+		}
+	}
+}
+
+fn required<T>(value: &Option<T>, name: &str) -> Result<(), String> {
+	value.as_ref().map(drop).ok_or_else(|| format!("{} must be set", name))
+}
+
+
+/// Replaces `value` with the environment variable `name` when it is set.
+fn override_with<T: FromStr>(value: &mut T, name: &str) -> Result<(), String>
+	where T::Err: fmt::Display {
+	if let Ok(raw) = env::var(name) {
+		*value = raw.trim().parse().map_err(|err| format!("{}: {}", name, err))?;
+	}
+	Ok(())
+}
+
+/// Like `override_with`, an empty variable unsets the value.
+fn override_opt<T: FromStr>(value: &mut Option<T>, name: &str) -> Result<(), String>
+	where T::Err: fmt::Display {
+	if let Ok(raw) = env::var(name) {
+		*value = match raw.trim() {
+			"" => None,
+			raw => Some(raw.parse().map_err(|err| format!("{}: {}", name, err))?),
+		};
+	}
+	Ok(())
+}
+
+/// Comma separated, empty items are skipped.
+fn override_list<T: FromStr>(value: &mut Vec<T>, name: &str) -> Result<(), String>
+	where T::Err: fmt::Display {
+	if let Ok(raw) = env::var(name) {
+		*value = split_list(&raw).map(|item| item.parse().map_err(|err| format!("{}: {}", name, err)))
+		                         .collect::<Result<_, _>>()?;
+	}
+	Ok(())
+}
+
+fn split_list(raw: &str) -> impl Iterator<Item = &str> { raw.split(',').map(str::trim).filter(|item| !item.is_empty()) }
+
+
+#[test]
+fn parse_test() {
+	let config = Config::parse(r#"
+		[server]
+		listen_url = "127.0.0.1:8080"
+
+		[cors]
+		origins = ["https://akropolis.io"]
+
+		[captcha]
+		key = "secret"
+
+		[database]
+		url = "postgres://bounty@localhost/bounty"
+		pool_size = 8
+
+		[features]
+		recaptcha_test = false
+
+		[rate_limit]
+		register_ip = "off"
+	"#).unwrap();
+	assert_eq!(config.database.pool_size, 8);
+	assert_eq!(config.database.connection_timeout_secs, 30);
+	assert!(!config.features.recaptcha_test && config.features.admin);
+	assert_eq!(config.rate_limit.register_ip, "off");
+	assert_eq!(config.rate_limit.register_address, "5/3600");
+	assert!(config.validate().is_ok());
+
+	assert!(!Config::default().features.recaptcha_test);
+
+	let mut config = Config::default();
+	config.risk.step_up = 80;
+	config.rate_limit.lookup_ip = "lots".to_owned();
+	let err = config.validate().unwrap_err().to_string();
+	assert!(err.contains("server.listen_url (LISTEN_URL), tls.listen_url (TLS_LISTEN_URL) or server.unix_socket (UNIX_SOCKET)"));
+	assert!(err.contains("rate_limit.lookup_ip"));
+	assert!(err.contains("risk.step_up"));
+
+	assert!(Config::parse("[server]\nlisten = \"x\"").is_err());
+	assert_eq!(split_list(" a, ,b ").collect::<Vec<_>>(), vec!["a", "b"]);
+}
@@ -0,0 +1,339 @@
+//! CORS for the public API, configured in `[cors]`.
+//!
+//! `origins` holds exact origins (`https://akropolis.io`), subdomain patterns (`https://*.akropolis.io`
+//! matches any subdomain but not the bare domain) or `*` for any origin. Each route is wrapped with
+//! the methods it serves. Preflights for other origins, methods or headers are refused with `403`,
+//! other requests from origins that are not allowed are answered without CORS headers,
+//! so browsers do not hand the response to the page. Error responses get the headers as well.
+
+use std::str::FromStr;
+use std::sync::Arc;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::http::Method;
+use actix_web::{Error, HttpResponse};
+use futures::future::{ok, FutureResult};
+use futures::{Future, Poll};
+
+
+/// Request headers browsers may send without asking
+const SAFELISTED_HEADERS: &[&str] = &["accept", "accept-language", "content-language"];
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Origin {
+	Any,
+	Exact(String),
+	/// `suffix` starts with the dot, e.g. `.akropolis.io`
+	Subdomains { scheme: String, suffix: String },
+}
+
+impl FromStr for Origin {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let origin = s.trim().trim_end_matches('/').to_lowercase();
+		if origin == "*" {
+			return Ok(Origin::Any);
+		}
+		let pos = origin.find("://").ok_or_else(|| format!("expected scheme://host, got {:?}", s))?;
+		let (scheme, host) = (&origin[..pos], &origin[pos + 3..]);
+		if scheme != "http" && scheme != "https" {
+			return Err(format!("unsupported scheme in {:?}", s));
+		}
+		if host.is_empty() || host.contains('/') {
+			return Err(format!("an origin has a host and no path, got {:?}", s));
+		}
+		if host.starts_with("*.") && !host[2..].is_empty() && !host[2..].contains('*') {
+			Ok(Origin::Subdomains { scheme: scheme.to_owned(),
+			                        suffix: host[1..].to_owned() })
+		} else if host.contains('*') {
+			Err(format!("only a leading `*.` is supported, got {:?}", s))
+		} else {
+			Ok(Origin::Exact(origin))
+		}
+	}
+}
+
+impl Origin {
+	/// `origin` is lowercase.
+	fn matches(&self, origin: &str) -> bool {
+		match self {
+			Origin::Any => true,
+			Origin::Exact(exact) => exact == origin,
+			Origin::Subdomains { scheme, suffix } => {
+				match origin.find("://") {
+					Some(pos) if &origin[..pos] == scheme => {
+						let host = &origin[pos + 3..];
+						host.len() > suffix.len() && host.ends_with(suffix.as_str()) && {
+							let sub = &host[..host.len() - suffix.len()];
+							!sub.starts_with('.')
+							&& sub.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.')
+						}
+					},
+					_ => false,
+				}
+			},
+		}
+	}
+}
+
+
+#[derive(Debug, Clone)]
+pub struct Policy {
+	origins: Vec<Origin>,
+	allowed_headers: Vec<HeaderName>,
+	/// Joined for `Access-Control-Expose-Headers`
+	expose_headers: String,
+	max_age: u64,
+}
+
+impl Policy {
+	pub fn from_config(config: &crate::config::Cors) -> Result<Self, String> {
+		let origins = config.origins
+		                    .iter()
+		                    .map(|origin| origin.parse().map_err(|err| format!("cors.origins: {}", err)))
+		                    .collect::<Result<_, _>>()?;
+		let headers = |name: &str, list: &[String]| {
+			list.iter()
+			    .map(|header| {
+				    HeaderName::from_str(header.trim()).map_err(|_| format!("cors.{}: invalid header {:?}", name, header))
+				   })
+			    .collect::<Result<Vec<_>, _>>()
+		};
+		Ok(Self { origins,
+		          allowed_headers: headers("allowed_headers", &config.allowed_headers)?,
+		          expose_headers: headers("expose_headers", &config.expose_headers)?.iter()
+		                                                                            .map(HeaderName::as_str)
+		                                                                            .collect::<Vec<_>>()
+		                                                                            .join(", "),
+		          max_age: config.max_age_secs })
+	}
+
+	/// The `Access-Control-Allow-Origin` value for `origin`, `None` when it is not allowed.
+	pub fn allow_origin(&self, origin: &str) -> Option<String> {
+		let lowercase = origin.to_lowercase();
+		match self.origins.iter().find(|rule| rule.matches(&lowercase))? {
+			Origin::Any => Some("*".to_owned()),
+			_ => Some(origin.to_owned()),
+		}
+	}
+
+	/// Headers answering a preflight for a route serving `methods`, `Err` has why it is refused.
+	pub fn preflight(&self,
+	                 origin: &str,
+	                 methods: &[Method],
+	                 request_method: &str,
+	                 request_headers: Option<&str>)
+	                 -> Result<Vec<(HeaderName, String)>, String> {
+		let allow_origin = self.allow_origin(origin).ok_or_else(|| format!("origin {:?}", origin))?;
+		if !methods.iter().any(|method| method.as_str() == request_method) {
+			return Err(format!("method {:?}", request_method));
+		}
+		let requested = request_headers.unwrap_or_default()
+		                               .split(',')
+		                               .map(|name| name.trim().to_lowercase())
+		                               .filter(|name| !name.is_empty())
+		                               .collect::<Vec<_>>();
+		if let Some(name) = requested.iter().find(|name| {
+			                                    !SAFELISTED_HEADERS.contains(&name.as_str())
+			                                    && !self.allowed_headers.iter().any(|allowed| allowed.as_str() == name.as_str())
+			                                   }) {
+			return Err(format!("header {:?}", name));
+		}
+
+		let mut headers = vec![(header::ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin),
+		                       (header::ACCESS_CONTROL_ALLOW_METHODS,
+		                        methods.iter().map(Method::as_str).collect::<Vec<_>>().join(", ")),
+		                       (header::ACCESS_CONTROL_MAX_AGE, self.max_age.to_string()),
+		                       (header::VARY, "Origin".to_owned())];
+		if !requested.is_empty() {
+			headers.push((header::ACCESS_CONTROL_ALLOW_HEADERS, requested.join(", ")));
+		}
+		Ok(headers)
+	}
+}
+
+
+/// Middleware for a route serving `methods`.
+pub struct Cors {
+	policy: Arc<Policy>,
+	methods: Vec<Method>,
+}
+
+impl Cors {
+	pub fn new(policy: Arc<Policy>, methods: &[Method]) -> Self {
+		Self { policy,
+		       methods: methods.to_vec() }
+	}
+}
+
+impl<S, B> Transform<S> for Cors
+	where S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+	      S::Future: 'static,
+	      B: 'static
+{
+	type Request = ServiceRequest;
+	type Response = ServiceResponse<B>;
+	type Error = Error;
+	type InitError = ();
+	type Transform = CorsMiddleware<S>;
+	type Future = FutureResult<Self::Transform, Self::InitError>;
+
+	fn new_transform(&self, service: S) -> Self::Future {
+		ok(CorsMiddleware { service,
+		                    policy: Arc::clone(&self.policy),
+		                    methods: self.methods.clone() })
+	}
+}
+
+pub struct CorsMiddleware<S> {
+	service: S,
+	policy: Arc<Policy>,
+	methods: Vec<Method>,
+}
+
+impl<S, B> Service for CorsMiddleware<S>
+	where S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+	      S::Future: 'static,
+	      B: 'static
+{
+	type Request = ServiceRequest;
+	type Response = ServiceResponse<B>;
+	type Error = Error;
+	type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+	fn poll_ready(&mut self) -> Poll<(), Self::Error> { self.service.poll_ready() }
+
+	fn call(&mut self, req: ServiceRequest) -> Self::Future {
+		let origin = match req.headers().get(header::ORIGIN).and_then(|value| value.to_str().ok()) {
+			Some(origin) => origin.to_owned(),
+			None => return Box::new(self.service.call(req)),
+		};
+
+		let request_method = req.headers()
+		                        .get(header::ACCESS_CONTROL_REQUEST_METHOD)
+		                        .and_then(|value| value.to_str().ok())
+		                        .filter(|_| Method::OPTIONS == *req.method())
+		                        .map(str::to_owned);
+		if let Some(request_method) = request_method {
+			let request_headers = req.headers()
+			                         .get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+			                         .and_then(|value| value.to_str().ok());
+			let resp = match self.policy.preflight(&origin, &self.methods, &request_method, request_headers) {
+				Ok(headers) => {
+					let mut resp = HttpResponse::Ok();
+					for (name, value) in headers {
+						resp.header(name, value);
+					}
+					resp.finish()
+				},
+				Err(reason) => {
+					log::debug!("cors: preflight refused, {}", reason);
+					HttpResponse::Forbidden().header(header::VARY, "Origin").finish()
+				},
+			};
+			return Box::new(ok(req.into_response(resp.into_body())));
+		}
+
+		let allow_origin = self.policy.allow_origin(&origin).and_then(|value| HeaderValue::from_str(&value).ok());
+		let expose_headers = Some(&self.policy.expose_headers).filter(|expose| !expose.is_empty())
+		                                                      .and_then(|expose| HeaderValue::from_str(expose).ok());
+		let mut cors_headers = Vec::new();
+		if let Some(allow_origin) = allow_origin {
+			cors_headers.push((header::ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin));
+			if let Some(expose_headers) = expose_headers {
+				cors_headers.push((header::ACCESS_CONTROL_EXPOSE_HEADERS, expose_headers));
+			}
+		}
+		Box::new(self.service.call(req).then(move |res| match res {
+			                                    Ok(mut res) => {
+				                                    let headers = res.headers_mut();
+				                                    for (name, value) in cors_headers {
+					                                    headers.insert(name, value);
+				                                    }
+				                                    headers.append(header::VARY, HeaderValue::from_static("Origin"));
+				                                    Ok(res)
+			                                    },
+			                                    // the page should see errors too
+			                                    Err(err) => {
+				                                    cors_headers.push((header::VARY, HeaderValue::from_static("Origin")));
+				                                    Err(crate::api::WithHeaders::new(err, cors_headers).into())
+			                                    },
+		                                    }))
+	}
+}
+
+
+#[test]
+fn origin_test() {
+	let policy = Policy::from_config(&crate::config::Cors { origins: vec!["https://akropolis.io".to_owned(),
+	                                                                      "https://*.akropolis.io".to_owned()],
+	                                                        ..Default::default() }).unwrap();
+	assert_eq!(policy.allow_origin("https://akropolis.io"), Some("https://akropolis.io".to_owned()));
+	assert_eq!(policy.allow_origin("https://app.akropolis.io"), Some("https://app.akropolis.io".to_owned()));
+	assert!(policy.allow_origin("https://a.b.AKROPOLIS.io").is_some());
+	for denied in &["http://akropolis.io",
+	                "https://*.akropolis.io",
+	                "https://evilakropolis.io",
+	                "https://akropolis.io.evil.com",
+	                "https://app.akropolis.io:8443",
+	                "https://user@app.akropolis.io",
+	                "null"] {
+		assert_eq!(policy.allow_origin(denied), None, "{}", denied);
+	}
+
+	assert_eq!("*".parse(), Ok(Origin::Any));
+	assert!("https://app.*.akropolis.io".parse::<Origin>().is_err());
+	assert!("akropolis.io".parse::<Origin>().is_err());
+	assert!("https://akropolis.io/app".parse::<Origin>().is_err());
+	assert_eq!(Policy::from_config(&crate::config::Cors { origins: vec!["*".to_owned()],
+	                                                      ..Default::default() }).unwrap()
+	                                                                             .allow_origin("https://example.com"),
+	           Some("*".to_owned()));
+}
+
+#[test]
+fn preflight_test() {
+	use actix_web::{test, web, App};
+
+	let policy = Arc::new(Policy::from_config(&crate::config::Cors { origins: vec!["https://*.akropolis.io".to_owned()],
+	                                                                 ..Default::default() }).unwrap());
+	let mut app = test::init_service(App::new().service(web::resource("/1.0/").wrap(Cors::new(Arc::clone(&policy),
+	                                                                                         &[Method::GET, Method::POST]))
+	                                                                          .route(web::get().to(HttpResponse::Ok))
+	                                                                          .route(web::post().to(HttpResponse::Ok))));
+	let preflight = |method: &str, headers: &str| {
+		test::TestRequest::with_uri("/1.0/").method(Method::OPTIONS)
+		                                     .header(header::ORIGIN, "https://app.akropolis.io")
+		                                     .header(header::ACCESS_CONTROL_REQUEST_METHOD, method)
+		                                     .header(header::ACCESS_CONTROL_REQUEST_HEADERS, headers)
+		                                     .to_request()
+	};
+
+	let resp = test::call_service(&mut app, preflight("POST", "Content-Type, X-Request-Id"));
+	assert!(resp.status().is_success());
+	assert_eq!(resp.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "https://app.akropolis.io");
+	assert_eq!(resp.headers().get(header::ACCESS_CONTROL_ALLOW_METHODS).unwrap(), "GET, POST");
+	assert_eq!(resp.headers().get(header::ACCESS_CONTROL_ALLOW_HEADERS).unwrap(), "content-type, x-request-id");
+
+	assert_eq!(test::call_service(&mut app, preflight("DELETE", "")).status(), 403);
+	assert_eq!(test::call_service(&mut app, preflight("POST", "X-Admin")).status(), 403);
+
+	let get = |origin: &str| test::TestRequest::get().uri("/1.0/").header(header::ORIGIN, origin).to_request();
+	let resp = test::call_service(&mut app, get("https://app.akropolis.io"));
+	assert_eq!(resp.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "https://app.akropolis.io");
+	assert_eq!(resp.headers().get(header::VARY).unwrap(), "Origin");
+	let resp = test::call_service(&mut app, get("https://evil.example"));
+	assert!(resp.status().is_success());
+	assert!(resp.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+
+	// a middleware inside failing the request
+	let refuse = |_, _: &mut _| futures::future::err::<ServiceResponse, _>(actix_web::error::ErrorTooManyRequests("slow down"));
+	let mut app = test::init_service(App::new().service(web::resource("/1.0/").wrap_fn(refuse)
+	                                                                          .wrap(Cors::new(policy, &[Method::GET]))
+	                                                                          .route(web::get().to(HttpResponse::Ok))));
+	let resp: HttpResponse = test::block_on(app.call(get("https://app.akropolis.io"))).expect_err("an error").into();
+	assert_eq!(resp.status().as_u16(), 429);
+	assert_eq!(resp.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "https://app.akropolis.io");
+}
@@ -21,6 +21,16 @@ pub type TheConnection = diesel::SqliteConnection;
 pub type TheConnection = diesel::PgConnection;
 #[cfg(feature = "dbpool")]
 pub type TheConnectionPool = Arc<r2d2::Pool<diesel::r2d2::ConnectionManager<TheConnection>>>;
+#[cfg(feature = "dbpool")]
+pub type ThePooledConnection = r2d2::PooledConnection<diesel::r2d2::ConnectionManager<TheConnection>>;
+
+sql_function!(fn lower(x: diesel::sql_types::Text) -> diesel::sql_types::Text);
+
+
+/// Latency metric and trace span for a query, both end when dropped.
+pub fn instrument(query: &'static str) -> (crate::metrics::QueryTimer, crate::telemetry::Span) {
+	(crate::metrics::get().query_timer(query), crate::telemetry::db_span(query))
+}
 
 
 #[allow(dead_code)]
@@ -30,11 +40,16 @@ pub fn establish_connection(database_url: &str) -> TheConnection {
 
 #[allow(dead_code)]
 #[cfg(feature = "dbpool")]
-pub fn establish_connection_pool(max_size: u32, database_url: &str) -> TheConnectionPool {
+pub fn establish_connection_pool(config: &crate::config::Database, database_url: &str) -> TheConnectionPool {
 	use diesel::r2d2::Pool;
 	use diesel::r2d2::ConnectionManager;
 
 	let manager = ConnectionManager::<TheConnection>::new(database_url);
-	let pool = Arc::new(Pool::builder().max_size(max_size).build(manager).unwrap());
+	let pool = Arc::new(Pool::builder().max_size(config.pool_size)
+	                                   .connection_timeout(config.connection_timeout())
+	                                   .idle_timeout(config.idle_timeout())
+	                                   .event_handler(Box::new(crate::metrics::PoolEvents))
+	                                   .build(manager)
+	                                   .unwrap());
 	pool
 }
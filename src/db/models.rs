@@ -1,8 +1,17 @@
+use std::fmt;
+use std::str::FromStr;
+use chrono::NaiveDateTime;
 use serde::{Serialize, Deserialize};
 use super::schema::users;
+use super::schema::vesting_schedules;
+use super::schema::referrals;
+use super::schema::{submissions, tasks};
+use super::schema::{admin_accounts, admin_audit, admin_sessions, api_keys};
+use super::schema::registration_attempts;
+use super::schema::payout_transfers;
 
 
-#[derive(Debug, Queryable)]
+#[derive(Debug, Queryable, Identifiable)]
 #[derive(Clone, Serialize, Deserialize)]
 pub struct User {
 	pub id: i32,
@@ -13,6 +22,25 @@ pub struct User {
 	pub address: String,
 	/// Amount of AKT tokens
 	pub amount: i64,
+	/// One of `PayoutStatus`
+	pub payout_status: String,
+	/// Hash of the payout transaction
+	pub payout_tx: Option<String>,
+	/// Amount of AKT tokens transferred so far
+	pub paid_amount: Option<i64>,
+	pub paid_at: Option<NaiveDateTime>,
+	/// First accepted the terms
+	pub registered_at: Option<NaiveDateTime>,
+	/// Country of the client IP when the terms were accepted
+	pub registered_country: Option<String>,
+	/// Accepted the terms from a restricted country
+	pub country_flagged: bool,
+	/// Excluded from payouts after review, e.g. as part of a sybil cluster
+	pub ineligible: bool,
+}
+
+impl User {
+	pub fn payout_status(&self) -> PayoutStatus { self.payout_status.parse().unwrap_or(PayoutStatus::Pending) }
 }
 
 
@@ -27,3 +55,299 @@ pub struct NewUser<'a> {
 	/// Amount of AKT tokens
 	pub amount: i64,
 }
+
+
+#[derive(Debug, Queryable, Identifiable, Associations)]
+#[derive(Clone, Serialize, Deserialize)]
+#[belongs_to(User)]
+#[table_name = "vesting_schedules"]
+pub struct VestingSchedule {
+	pub id: i32,
+	pub user_id: i32,
+	pub start_at: NaiveDateTime,
+	/// Nothing unlocks before `start_at + cliff_secs`
+	pub cliff_secs: i64,
+	/// Everything is unlocked at `start_at + duration_secs`
+	pub duration_secs: i64,
+	/// Unlock step, 0 - continuous
+	pub period_secs: i64,
+}
+
+
+#[derive(Debug, Queryable)]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Referral {
+	pub id: i32,
+	pub referee_id: i32,
+	pub referrer_id: i32,
+	/// AKT tokens credited to the referrer for this referee
+	pub bonus: i64,
+	pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "referrals"]
+pub struct NewReferral {
+	pub referee_id: i32,
+	pub referrer_id: i32,
+	pub bonus: i64,
+	pub created_at: NaiveDateTime,
+}
+
+
+#[derive(Debug, Queryable)]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Task {
+	pub id: i32,
+	pub title: String,
+	pub description: String,
+	/// AKT tokens credited for an approved submission
+	pub reward: i64,
+	/// Accepts submissions
+	pub active: bool,
+	pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "tasks"]
+pub struct NewTask<'a> {
+	pub title: &'a str,
+	pub description: &'a str,
+	pub reward: i64,
+	pub active: bool,
+	pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Queryable)]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Submission {
+	pub id: i32,
+	pub task_id: i32,
+	pub user_id: i32,
+	pub proof_url: String,
+	pub proof_text: String,
+	/// pending, approved or rejected
+	pub status: String,
+	/// Moderator's reason
+	pub reason: Option<String>,
+	/// AKT tokens credited on approval
+	pub reward: i64,
+	pub moderator: Option<String>,
+	pub created_at: NaiveDateTime,
+	pub moderated_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "submissions"]
+pub struct NewSubmission<'a> {
+	pub task_id: i32,
+	pub user_id: i32,
+	pub proof_url: &'a str,
+	pub proof_text: &'a str,
+	pub status: &'a str,
+	pub created_at: NaiveDateTime,
+}
+
+
+#[derive(Debug, Queryable)]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+	pub id: i32,
+	pub name: String,
+	/// Hex SHA-256 of the key
+	#[serde(skip_serializing)]
+	pub key_hash: String,
+	/// viewer, support or admin
+	pub role: String,
+	pub created_at: NaiveDateTime,
+	pub last_used_at: Option<NaiveDateTime>,
+	pub revoked_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "api_keys"]
+pub struct NewApiKey<'a> {
+	pub name: &'a str,
+	pub key_hash: &'a str,
+	pub role: &'a str,
+	pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Queryable)]
+#[derive(Clone)]
+pub struct AdminAccount {
+	pub id: i32,
+	pub username: String,
+	/// Argon2 encoded hash
+	pub password_hash: String,
+	/// Base32 TOTP secret
+	pub totp_secret: String,
+	/// Last accepted TOTP time step, codes are not accepted twice
+	pub totp_last_step: i64,
+	/// viewer, support or admin
+	pub role: String,
+	/// Consecutive failed logins
+	pub failed_attempts: i32,
+	pub locked_until: Option<NaiveDateTime>,
+	pub created_at: NaiveDateTime,
+	pub last_login_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "admin_accounts"]
+pub struct NewAdminAccount<'a> {
+	pub username: &'a str,
+	pub password_hash: &'a str,
+	pub totp_secret: &'a str,
+	pub role: &'a str,
+	pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Queryable)]
+#[derive(Clone)]
+pub struct AdminSession {
+	pub id: i32,
+	/// Hex SHA-256 of the session cookie value
+	pub token_hash: String,
+	pub account_id: i32,
+	pub csrf_token: String,
+	pub created_at: NaiveDateTime,
+	pub expires_at: NaiveDateTime,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "admin_sessions"]
+pub struct NewAdminSession<'a> {
+	pub token_hash: &'a str,
+	pub account_id: i32,
+	pub csrf_token: &'a str,
+	pub created_at: NaiveDateTime,
+	pub expires_at: NaiveDateTime,
+}
+
+#[derive(Debug, Queryable)]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AuditRecord {
+	pub id: i32,
+	/// Who did it, e.g. `key#1 (deploy)`
+	pub actor: String,
+	pub action: String,
+	pub user_id: Option<i32>,
+	/// JSON
+	pub detail: String,
+	pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "admin_audit"]
+pub struct NewAuditRecord<'a> {
+	pub actor: &'a str,
+	pub action: &'a str,
+	pub user_id: Option<i32>,
+	pub detail: &'a str,
+	pub created_at: NaiveDateTime,
+}
+
+
+#[derive(Debug, Queryable)]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RegistrationAttempt {
+	pub id: i32,
+	/// Ethereum address as sent
+	pub address: String,
+	/// Resolved client IP
+	pub ip: Option<String>,
+	/// Reputation list the IP was on
+	pub ip_list: Option<String>,
+	/// `registered`, `rejected` or `error`
+	pub outcome: String,
+	pub created_at: NaiveDateTime,
+	pub user_agent: Option<String>,
+	/// Referrer address sent with the registration
+	pub referrer: Option<String>,
+	/// 0 to 100, missing when refused before the captcha
+	pub risk_score: Option<i32>,
+	/// JSON object of points per factor
+	pub risk_factors: Option<String>,
+	/// `allow`, `step_up` or `deny`
+	pub risk_decision: Option<String>,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "registration_attempts"]
+pub struct NewRegistrationAttempt<'a> {
+	pub address: &'a str,
+	pub ip: Option<String>,
+	pub ip_list: Option<&'a str>,
+	pub outcome: &'a str,
+	pub created_at: NaiveDateTime,
+	pub user_agent: Option<&'a str>,
+	pub referrer: Option<&'a str>,
+	pub risk_score: Option<i32>,
+	pub risk_factors: Option<String>,
+	pub risk_decision: Option<&'a str>,
+}
+
+
+#[derive(Debug, Queryable)]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PayoutTransfer {
+	pub id: i32,
+	pub user_id: i32,
+	/// Lowercase transaction hash
+	pub tx_hash: String,
+	/// Amount of AKT tokens transferred
+	pub amount: i64,
+	/// One of `PayoutStatus`
+	pub status: String,
+	pub imported_at: NaiveDateTime,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "payout_transfers"]
+pub struct NewPayoutTransfer<'a> {
+	pub user_id: i32,
+	pub tx_hash: &'a str,
+	pub amount: i64,
+	pub status: &'a str,
+	pub imported_at: NaiveDateTime,
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PayoutStatus {
+	Pending,
+	Sent,
+	Confirmed,
+	Failed,
+}
+
+impl PayoutStatus {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			PayoutStatus::Pending => "pending",
+			PayoutStatus::Sent => "sent",
+			PayoutStatus::Confirmed => "confirmed",
+			PayoutStatus::Failed => "failed",
+		}
+	}
+}
+
+impl fmt::Display for PayoutStatus {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { write!(f, "{}", self.as_str()) }
+}
+
+impl FromStr for PayoutStatus {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"pending" => Ok(PayoutStatus::Pending),
+			"sent" => Ok(PayoutStatus::Sent),
+			"confirmed" => Ok(PayoutStatus::Confirmed),
+			"failed" => Ok(PayoutStatus::Failed),
+			_ => Err(format!("unknown payout status: {}", s)),
+		}
+	}
+}
@@ -5,5 +5,153 @@ table! {
 		not_resident -> Bool,
 		address -> Text,
 		amount -> BigInt,
+		payout_status -> Text,
+		payout_tx -> Nullable<Text>,
+		paid_amount -> Nullable<BigInt>,
+		paid_at -> Nullable<Timestamp>,
+		registered_at -> Nullable<Timestamp>,
+		registered_country -> Nullable<Text>,
+		country_flagged -> Bool,
+		ineligible -> Bool,
 	}
 }
+
+table! {
+	vesting_schedules (id) {
+		id -> Integer,
+		user_id -> Integer,
+		start_at -> Timestamp,
+		cliff_secs -> BigInt,
+		duration_secs -> BigInt,
+		period_secs -> BigInt,
+	}
+}
+
+table! {
+	referrals (id) {
+		id -> Integer,
+		referee_id -> Integer,
+		referrer_id -> Integer,
+		bonus -> BigInt,
+		created_at -> Timestamp,
+	}
+}
+
+table! {
+	tasks (id) {
+		id -> Integer,
+		title -> Text,
+		description -> Text,
+		reward -> BigInt,
+		active -> Bool,
+		created_at -> Timestamp,
+	}
+}
+
+table! {
+	submissions (id) {
+		id -> Integer,
+		task_id -> Integer,
+		user_id -> Integer,
+		proof_url -> Text,
+		proof_text -> Text,
+		status -> Text,
+		reason -> Nullable<Text>,
+		reward -> BigInt,
+		moderator -> Nullable<Text>,
+		created_at -> Timestamp,
+		moderated_at -> Nullable<Timestamp>,
+	}
+}
+
+table! {
+	api_keys (id) {
+		id -> Integer,
+		name -> Text,
+		key_hash -> Text,
+		role -> Text,
+		created_at -> Timestamp,
+		last_used_at -> Nullable<Timestamp>,
+		revoked_at -> Nullable<Timestamp>,
+	}
+}
+
+table! {
+	admin_audit (id) {
+		id -> Integer,
+		actor -> Text,
+		action -> Text,
+		user_id -> Nullable<Integer>,
+		detail -> Text,
+		created_at -> Timestamp,
+	}
+}
+
+table! {
+	admin_accounts (id) {
+		id -> Integer,
+		username -> Text,
+		password_hash -> Text,
+		totp_secret -> Text,
+		totp_last_step -> BigInt,
+		role -> Text,
+		failed_attempts -> Integer,
+		locked_until -> Nullable<Timestamp>,
+		created_at -> Timestamp,
+		last_login_at -> Nullable<Timestamp>,
+	}
+}
+
+table! {
+	admin_sessions (id) {
+		id -> Integer,
+		token_hash -> Text,
+		account_id -> Integer,
+		csrf_token -> Text,
+		created_at -> Timestamp,
+		expires_at -> Timestamp,
+	}
+}
+
+table! {
+	rate_limits (bucket) {
+		bucket -> Text,
+		tokens -> Double,
+		updated_at -> Timestamp,
+	}
+}
+
+table! {
+	registration_attempts (id) {
+		id -> Integer,
+		address -> Text,
+		ip -> Nullable<Text>,
+		ip_list -> Nullable<Text>,
+		outcome -> Text,
+		created_at -> Timestamp,
+		user_agent -> Nullable<Text>,
+		referrer -> Nullable<Text>,
+		risk_score -> Nullable<Integer>,
+		risk_factors -> Nullable<Text>,
+		risk_decision -> Nullable<Text>,
+	}
+}
+
+table! {
+	payout_transfers (id) {
+		id -> Integer,
+		user_id -> Integer,
+		tx_hash -> Text,
+		amount -> BigInt,
+		status -> Text,
+		imported_at -> Timestamp,
+	}
+}
+
+joinable!(vesting_schedules -> users (user_id));
+joinable!(admin_sessions -> admin_accounts (account_id));
+joinable!(submissions -> tasks (task_id));
+joinable!(submissions -> users (user_id));
+joinable!(payout_transfers -> users (user_id));
+
+allow_tables_to_appear_in_same_query!(users, vesting_schedules, referrals, tasks, submissions, api_keys, admin_audit, admin_accounts, admin_sessions, rate_limits, registration_attempts, payout_transfers);
@@ -0,0 +1,203 @@
+//! Country of the client IP from an offline MaxMind database, a second residency signal
+//! next to the self-declared `not_resident`.
+//!
+//! `[geoip]`:
+//! - `database`: path to a GeoIP2/GeoLite2 Country (or City) `.mmdb`, lookups are off when unset
+//! - `restricted_countries`: ISO 3166-1 alpha-2 codes
+//! - `policy`: `reject` (default) registrations from those countries or only `flag` them
+//! - `reload_secs`: how often the file is checked for changes (default 300)
+//!
+//! A replaced file is picked up on the next check, no restart needed.
+
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use actix::prelude::*;
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+
+use crate::state::State;
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Policy {
+	Reject,
+	Flag,
+}
+
+impl std::str::FromStr for Policy {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"reject" => Ok(Policy::Reject),
+			"flag" => Ok(Policy::Flag),
+			_ => Err(format!("geoip.policy: expected reject or flag, got {:?}", s)),
+		}
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+	pub database: Option<PathBuf>,
+	pub restricted: HashSet<String>,
+	pub policy: Policy,
+	pub reload_interval: Duration,
+}
+
+impl Settings {
+	pub fn from_config(config: &crate::config::Geoip) -> Result<Self, String> {
+		let restricted = config.restricted_countries
+		                       .iter()
+		                       .map(|code| code.trim().to_uppercase())
+		                       .filter(|code| !code.is_empty())
+		                       .collect::<HashSet<_>>();
+		if let Some(code) = restricted.iter().find(|code| code.len() != 2 || !code.bytes().all(|b| b.is_ascii_alphabetic())) {
+			return Err(format!("geoip.restricted_countries: {:?} is not a country code", code));
+		}
+		Ok(Self { database: config.database.clone().filter(|path| !path.as_os_str().is_empty()),
+		          restricted,
+		          policy: config.policy.parse()?,
+		          reload_interval: Duration::from_secs(config.reload_secs.max(1)) })
+	}
+}
+
+
+/// What to do with a registration.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Residency {
+	/// Detected country code, `None` when unknown or lookups are off
+	pub country: Option<String>,
+	pub restricted: bool,
+	pub policy: Policy,
+}
+
+impl Residency {
+	pub fn rejected(&self) -> bool { self.restricted && self.policy == Policy::Reject }
+
+	pub fn flagged(&self) -> bool { self.restricted && self.policy == Policy::Flag }
+}
+
+
+struct Loaded {
+	modified: Option<SystemTime>,
+	reader: Arc<Reader<Vec<u8>>>,
+}
+
+pub struct Geoip {
+	settings: Settings,
+	loaded: RwLock<Option<Loaded>>,
+}
+
+impl Geoip {
+	/// Opens the database if configured, a missing or broken file fails startup.
+	pub fn new(settings: Settings) -> Result<Self, String> {
+		let geoip = Self { settings,
+		                   loaded: RwLock::new(None) };
+		if let Some(path) = &geoip.settings.database {
+			geoip.reload().map_err(|err| format!("geoip.database {}: {}", path.display(), err))?;
+		}
+		Ok(geoip)
+	}
+
+	pub fn settings(&self) -> &Settings { &self.settings }
+
+	/// Reopens the database if the file changed since it was loaded.
+	/// A failed reload keeps the previous database.
+	pub fn reload(&self) -> Result<bool, failure::Error> {
+		let path = match &self.settings.database {
+			Some(path) => path,
+			None => return Ok(false),
+		};
+		let modified = std::fs::metadata(path)?.modified().ok();
+		if let Some(loaded) = &*self.loaded.read().unwrap_or_else(|poisoned| poisoned.into_inner()) {
+			if modified.is_some() && loaded.modified == modified {
+				return Ok(false);
+			}
+		}
+		let reader = Reader::open_readfile(path).map_err(|err| failure::format_err!("{:?}", err))?;
+		log::info!("geoip: loaded {} built at {}", path.display(), reader.metadata.build_epoch);
+		*self.loaded.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(Loaded { modified,
+		                                                                                      reader: Arc::new(reader) });
+		Ok(true)
+	}
+
+	/// ISO code of the country the address is in.
+	pub fn country(&self, ip: IpAddr) -> Option<String> {
+		let reader = match &*self.loaded.read().unwrap_or_else(|poisoned| poisoned.into_inner()) {
+			Some(loaded) => Arc::clone(&loaded.reader),
+			None => return None,
+		};
+		match reader.lookup::<geoip2::Country>(ip) {
+			Ok(found) => found.country.and_then(|country| country.iso_code).map(|code| code.to_uppercase()),
+			Err(MaxMindDBError::AddressNotFoundError(_)) => None,
+			Err(err) => {
+				log::warn!("geoip: lookup failed: {:?}", err);
+				None
+			},
+		}
+	}
+
+	pub fn residency(&self, ip: Option<IpAddr>) -> Residency {
+		let country = ip.and_then(|ip| self.country(ip));
+		let restricted = country.as_ref().map(|code| self.settings.restricted.contains(code)).unwrap_or(false);
+		Residency { country,
+		            restricted,
+		            policy: self.settings.policy }
+	}
+}
+
+
+/// Checks the database file for changes in the current system.
+pub struct Reloader {
+	interval: Duration,
+}
+
+impl Reloader {
+	pub fn start() {
+		let geoip = State::get().geoip();
+		if geoip.settings().database.is_some() {
+			Actor::start(Reloader { interval: geoip.settings().reload_interval });
+		}
+	}
+}
+
+impl Actor for Reloader {
+	type Context = Context<Self>;
+
+	fn started(&mut self, ctx: &mut Self::Context) {
+		ctx.run_interval(self.interval, |_, _| {
+			   if let Err(err) = State::get().geoip().reload() {
+				   log::error!("geoip: reload failed, keeping the loaded database: {:?}", err);
+			   }
+		   });
+	}
+}
+
+
+#[test]
+fn residency_test() {
+	// 203.0.113.0/24 in US, 198.51.100.0/24 in DE
+	let database = PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/GeoIP2-Country-Test.mmdb"));
+	let settings = Settings { database: Some(database),
+	                          restricted: vec!["US".to_owned()].into_iter().collect(),
+	                          policy: Policy::Flag,
+	                          reload_interval: Duration::from_secs(300) };
+	let mut geoip = Geoip::new(settings).unwrap();
+	let residency = geoip.residency("203.0.113.7".parse().ok());
+	assert_eq!(residency.country.as_ref().map(String::as_str), Some("US"));
+	assert!(residency.flagged() && !residency.rejected());
+
+	let residency = geoip.residency("198.51.100.1".parse().ok());
+	assert_eq!(residency.country.as_ref().map(String::as_str), Some("DE"));
+	assert!(!residency.restricted);
+	assert_eq!(geoip.residency("192.0.2.1".parse().ok()).country, None);
+	assert_eq!(geoip.residency(None).country, None);
+
+	geoip.settings.policy = Policy::Reject;
+	assert!(geoip.residency("203.0.113.7".parse().ok()).rejected());
+	assert!(!geoip.reload().unwrap());
+	assert_eq!("flag".parse(), Ok(Policy::Flag));
+	assert!("block".parse::<Policy>().is_err());
+}
@@ -0,0 +1,92 @@
+//! Liveness and readiness probes.
+//!
+//! `/healthz` answers while the process serves requests.
+//! `/readyz` checks the state, a `SELECT 1` on a pooled connection and the reCAPTCHA circuit,
+//! and fails once shutdown has begun so the instance is taken out of rotation before it stops.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use actix_web::HttpResponse;
+use diesel::RunQueryDsl;
+use serde::Serialize;
+
+use crate::recaptcha::{self, Circuit};
+use crate::state::State;
+
+
+/// How long readiness waits for a pooled connection
+const DB_TIMEOUT: Duration = Duration::from_secs(2);
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+
+/// Makes readiness fail from now on.
+pub fn begin_shutdown() { SHUTTING_DOWN.store(true, Ordering::SeqCst); }
+
+pub fn shutting_down() -> bool { SHUTTING_DOWN.load(Ordering::SeqCst) }
+
+
+#[derive(Debug, Serialize)]
+pub struct Check {
+	pub ok: bool,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub error: Option<String>,
+}
+
+impl Check {
+	fn ok() -> Self { Self { ok: true, error: None } }
+
+	fn failed<E: ToString>(error: E) -> Self {
+		Self { ok: false,
+		       error: Some(error.to_string()) }
+	}
+}
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+	pub ready: bool,
+	pub shutting_down: bool,
+	pub state: Check,
+	pub database: Check,
+	/// Half-open is ready, otherwise no request would close the circuit again
+	pub recaptcha: Circuit,
+}
+
+
+fn check_database(state: &State) -> Check {
+	let conn = match state.get_pool().get_timeout(DB_TIMEOUT) {
+		Ok(conn) => conn,
+		Err(err) => return Check::failed(err),
+	};
+	match diesel::sql_query("SELECT 1").execute(&*conn) {
+		Ok(_) => Check::ok(),
+		Err(err) => Check::failed(err),
+	}
+}
+
+pub fn readiness() -> Readiness {
+	let (state, database) = match State::try_get() {
+		Some(state) => (Check::ok(), check_database(state)),
+		None => (Check::failed("not initialized"), Check::failed("no state")),
+	};
+	let recaptcha = recaptcha::breaker().circuit();
+	let shutting_down = shutting_down();
+	Readiness { ready: !shutting_down && state.ok && database.ok && recaptcha != Circuit::Open,
+	            shutting_down,
+	            state,
+	            database,
+	            recaptcha }
+}
+
+
+pub fn healthz() -> HttpResponse { HttpResponse::Ok().json(serde_json::json!({ "status": "ok" })) }
+
+pub fn readyz() -> HttpResponse {
+	let readiness = readiness();
+	if !readiness.ready {
+		log::warn!("not ready: {:?}", readiness);
+		HttpResponse::ServiceUnavailable().json(readiness)
+	} else {
+		HttpResponse::Ok().json(readiness)
+	}
+}
@@ -0,0 +1,293 @@
+//! Logging setup and the per-request access log.
+//!
+//! `log.format`: `text` (default) or `json`, one object per line. Filters come from `RUST_LOG` as before.
+//! Every request gets an id, taken from `X-Request-Id` if it looks sane or generated,
+//! echoed in the response and attached to every line logged while the request is handled.
+
+use std::cell::RefCell;
+use std::io::Write;
+use std::time::Instant;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::{Error, HttpMessage, HttpRequest};
+use futures::future::{ok, FutureResult};
+use futures::{Future, Poll};
+use once_cell::sync::OnceCell;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde_json::{json, Map, Value};
+
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+const ACCESS_TARGET: &str = "access";
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+static FORMAT: OnceCell<Format> = OnceCell::INIT;
+
+thread_local! {
+	/// Id of the request being handled on this thread
+	static CURRENT: RefCell<Option<String>> = RefCell::new(None);
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+	Text,
+	Json,
+}
+
+impl std::str::FromStr for Format {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"text" => Ok(Format::Text),
+			"json" => Ok(Format::Json),
+			_ => Err(format!("unknown log format: {}", s)),
+		}
+	}
+}
+
+fn format() -> Format { *FORMAT.get().unwrap_or(&Format::Text) }
+
+
+/// Installs the logger in the configured format.
+pub fn init(config: &crate::config::Log) {
+	let format = config.format.parse().unwrap_or_else(|err| {
+		                                  eprintln!("{}, using text", err);
+		                                  Format::Text
+		                                 });
+	let _ = FORMAT.set(format);
+
+	let mut builder = env_logger::Builder::from_default_env();
+	if format == Format::Json {
+		builder.format(|buf, record| {
+			       let mut line = Map::new();
+			       line.insert("ts".to_owned(), json!(chrono::Utc::now().to_rfc3339()));
+			       line.insert("level".to_owned(), json!(record.level().to_string()));
+			       line.insert("target".to_owned(), json!(record.target()));
+			       if let Some(id) = current_request_id() {
+				       line.insert("request_id".to_owned(), json!(id));
+			       }
+			       let message = record.args().to_string();
+			       // access lines are already JSON objects, their fields go to the top level
+			       match serde_json::from_str::<Map<String, Value>>(&message) {
+				       Ok(fields) if record.target() == ACCESS_TARGET => line.extend(fields),
+			         _ => {
+				         line.insert("msg".to_owned(), json!(message));
+				        },
+			       }
+			       writeln!(buf, "{}", Value::Object(line))
+		       });
+	} else {
+		builder.format(|buf, record| {
+			       let id = current_request_id().map(|id| format!(" [{}]", id)).unwrap_or_default();
+			       writeln!(buf,
+			                "{} {:5} {}{}: {}",
+			                chrono::Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+			                record.level(),
+			                record.target(),
+			                id,
+			                record.args())
+		       });
+	}
+	builder.init();
+}
+
+pub fn current_request_id() -> Option<String> { CURRENT.with(|current| current.borrow().clone()) }
+
+
+/// What happened to the request on its way, filled in by the handlers.
+#[derive(Debug, Clone, Default)]
+pub struct Outcomes {
+	pub captcha: Option<&'static str>,
+	pub db: Option<&'static str>,
+}
+
+/// Records the captcha step: `success`, `rejected`, `error` or `unavailable`.
+pub fn captcha_outcome(req: &HttpRequest, outcome: &'static str) {
+	let mut extensions = req.extensions_mut();
+	match extensions.get_mut::<Outcomes>() {
+		Some(outcomes) => outcomes.captcha = Some(outcome),
+		None => extensions.insert(Outcomes { captcha: Some(outcome),
+		                                     ..Default::default() }),
+	}
+}
+
+/// Records the DB step: `ok`, `not_found` or `error`.
+pub fn db_outcome(req: &HttpRequest, outcome: &'static str) {
+	let mut extensions = req.extensions_mut();
+	match extensions.get_mut::<Outcomes>() {
+		Some(outcomes) => outcomes.db = Some(outcome),
+		None => extensions.insert(Outcomes { db: Some(outcome),
+		                                     ..Default::default() }),
+	}
+}
+
+
+fn valid_request_id(id: &str) -> bool {
+	!id.is_empty()
+	&& id.len() <= MAX_REQUEST_ID_LEN
+	&& id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'.')
+}
+
+fn generate_request_id() -> String {
+	let mut bytes = [0; 16];
+	// not a secret, a failing generator only costs uniqueness
+	let _ = SystemRandom::new().fill(&mut bytes);
+	hex::encode(&bytes)
+}
+
+
+/// Middleware assigning request ids and writing the access log.
+pub struct RequestLog;
+
+impl<S, B> Transform<S> for RequestLog
+	where S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+	      S::Future: 'static,
+	      B: 'static
+{
+	type Request = ServiceRequest;
+	type Response = ServiceResponse<B>;
+	type Error = Error;
+	type InitError = ();
+	type Transform = RequestLogMiddleware<S>;
+	type Future = FutureResult<Self::Transform, Self::InitError>;
+
+	fn new_transform(&self, service: S) -> Self::Future { ok(RequestLogMiddleware { service }) }
+}
+
+pub struct RequestLogMiddleware<S> {
+	service: S,
+}
+
+impl<S, B> Service for RequestLogMiddleware<S>
+	where S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+	      S::Future: 'static,
+	      B: 'static
+{
+	type Request = ServiceRequest;
+	type Response = ServiceResponse<B>;
+	type Error = Error;
+	type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+	fn poll_ready(&mut self) -> Poll<(), Self::Error> { self.service.poll_ready() }
+
+	fn call(&mut self, req: ServiceRequest) -> Self::Future {
+		let started = Instant::now();
+		let id = req.headers()
+		            .get(REQUEST_ID_HEADER)
+		            .and_then(|value| value.to_str().ok())
+		            .filter(|id| valid_request_id(id))
+		            .map(ToOwned::to_owned)
+		            .unwrap_or_else(generate_request_id);
+		let method = req.method().to_string();
+		let client_ip = crate::client_ip::resolve_parts(req.peer_addr(), &req.extensions(), req.headers()).map(|ip| ip.to_string());
+
+		let inner = Scoped::new(id.clone(), || self.service.call(req));
+		let fut = inner.then(move |res| {
+			               let (status, route, outcomes) = match &res {
+				               Ok(res) => (res.status(),
+				                           crate::metrics::route_label(res),
+				                           res.request().extensions().get::<Outcomes>().cloned()),
+			                   Err(err) => (err.as_response_error().error_response().status(), crate::metrics::UNMATCHED.to_owned(), None),
+			               };
+			               let outcomes = outcomes.unwrap_or_default();
+			               let mut fields = Map::new();
+			               fields.insert("method".to_owned(), json!(method));
+			               fields.insert("route".to_owned(), json!(route));
+			               fields.insert("status".to_owned(), json!(status.as_u16()));
+			               fields.insert("latency_ms".to_owned(), json!(started.elapsed().as_millis() as u64));
+			               fields.insert("client_ip".to_owned(), json!(client_ip));
+			               fields.insert("captcha".to_owned(), json!(outcomes.captcha));
+			               fields.insert("db".to_owned(), json!(outcomes.db));
+			               with_id(&id, || access(fields));
+
+			               let header = HeaderValue::from_str(&id).ok()
+			                                                     .map(|value| (HeaderName::from_static(REQUEST_ID_HEADER), value));
+			               match res {
+				               Ok(mut res) => {
+					               if let Some((name, value)) = header {
+						               res.headers_mut().insert(name, value);
+					               }
+					               Ok(res)
+				               },
+			                   // answered outside of the app, but still with the id
+			                   Err(err) => Err(crate::api::WithHeaders::new(err, header.into_iter().collect()).into()),
+			               }
+			              });
+		Box::new(fut)
+	}
+}
+
+fn access(fields: Map<String, Value>) {
+	match format() {
+		Format::Json => log::info!(target: ACCESS_TARGET, "{}", Value::Object(fields)),
+		Format::Text => {
+			let line = fields.iter()
+			                 .map(|(key, value)| match value {
+				                 Value::String(s) => format!("{}={}", key, s),
+			                     Value::Null => format!("{}=-", key),
+			                     value => format!("{}={}", key, value),
+			                 })
+			                 .collect::<Vec<_>>()
+			                 .join(" ");
+			log::info!(target: ACCESS_TARGET, "{}", line);
+		},
+	}
+}
+
+
+/// Sets the current request id on this thread whenever the inner future runs.
+struct Scoped<F> {
+	id: String,
+	inner: F,
+}
+
+impl<F> Scoped<F> {
+	fn new<C: FnOnce() -> F>(id: String, create: C) -> Self {
+		let inner = with_id(&id, create);
+		Scoped { id, inner }
+	}
+}
+
+fn with_id<T, C: FnOnce() -> T>(id: &str, f: C) -> T {
+	let previous = CURRENT.with(|current| current.replace(Some(id.to_owned())));
+	let result = f();
+	CURRENT.with(|current| *current.borrow_mut() = previous);
+	result
+}
+
+impl<F: Future> Future for Scoped<F> {
+	type Item = F::Item;
+	type Error = F::Error;
+
+	fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+		let inner = &mut self.inner;
+		with_id(&self.id, || inner.poll())
+	}
+}
+
+
+#[test]
+fn request_id_test() {
+	assert!(valid_request_id("7f3c2a9e-1b2c-4d5e-8f90-a1b2c3d4e5f6"));
+	assert!(!valid_request_id(""));
+	assert!(!valid_request_id("a b"));
+	assert!(!valid_request_id(&"a".repeat(MAX_REQUEST_ID_LEN + 1)));
+	assert_eq!(generate_request_id().len(), 32);
+}
+
+#[test]
+fn error_request_id_test() {
+	use actix_web::{test, web, App, HttpResponse};
+
+	// a middleware failing inside the request log
+	let refuse = |_, _: &mut _| futures::future::err::<ServiceResponse, _>(actix_web::error::ErrorTooManyRequests("slow down"));
+	let mut app = test::init_service(App::new().wrap_fn(refuse)
+	                                           .wrap(RequestLog)
+	                                           .service(web::resource("/").to(|| HttpResponse::Ok())));
+	let req = test::TestRequest::with_header(REQUEST_ID_HEADER, "abc-1").to_request();
+	let resp: HttpResponse = test::block_on(app.call(req)).expect_err("an error").into();
+	assert_eq!(resp.status().as_u16(), 429);
+	assert_eq!(resp.headers().get(REQUEST_ID_HEADER).unwrap(), "abc-1");
+}
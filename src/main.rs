@@ -6,87 +6,283 @@
 #[macro_use]
 extern crate diesel;
 
-use std::env;
 use dotenv::dotenv;
 use futures::prelude::*;
 
-use actix_cors::Cors;
+use actix_web::http::Method;
 use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
 use actix_web::Error;
 
 mod db;
+mod config;
+mod cors;
 mod state;
 mod api;
 mod recaptcha;
+mod cli;
+mod payout;
+mod vesting;
+mod referral;
+mod tasks;
+mod admin;
+mod stats;
+mod metrics;
+mod health;
+mod logging;
+mod telemetry;
+mod client_ip;
+mod ratelimit;
+mod geoip;
+mod screening;
+mod reputation;
+mod attempts;
+mod sybil;
+mod risk;
+mod tls;
+mod shutdown;
+mod systemd;
+mod uds;
 
 
 fn main() -> Result<(), std::io::Error> {
 	dotenv().ok();
-	env_logger::init();
-
-	println!("PWD: {:?}", std::env::current_dir().unwrap());
-
-	let rekey = env::var("RECAPTCHA_KEY").expect("RECAPTCHA_KEY must be set");
-	let listen_url = env::var("LISTEN_URL").expect("LISTEN_URL must be set");
-	let cors_origin = env::var("CORS_ORIGIN").expect("CORS_ORIGIN must be set");
-
-	let database_url = {
-		use std::env::set_var;
-		let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-		set_var("DB_READ_URL", &database_url);
-		set_var("DB_WRITE_URL", &database_url);
-		database_url
+	let config = match config::Config::load() {
+		Ok(config) => config,
+		Err(err) => {
+			eprintln!("config: {}", err);
+			std::process::exit(2);
+		},
 	};
-
-	println!("starting with config:");
-	println!("recaptcha key: {}", rekey);
-	println!("listenig url: {}", listen_url);
-	println!("database url: {}", database_url);
-	println!("CORS origin: {}", cors_origin);
-
-	let _sys = actix::System::new("actix_sys");
+	logging::init(&config.log);
+	telemetry::init(&config.telemetry);
+
+	if let Some(result) = cli::run(&config) {
+		return result.map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err.to_string()));
+	}
+
+	if let Err(err) = config.validate() {
+		eprintln!("invalid config:\n{}", err);
+		std::process::exit(2);
+	}
+
+	log::info!("working directory: {:?}", std::env::current_dir().unwrap());
+
+	let listen_url = config.server.listen_url.clone();
+	let hsts = tls::Settings::from_config(&config.tls).expect("validated config").and_then(|settings| settings.hsts);
+	let cors_policy = std::sync::Arc::new(cors::Policy::from_config(&config.cors).expect("validated config"));
+	let metrics_url = config.server.metrics_url.clone();
+	let features = config.features;
+
+	log::info!("starting with config:");
+	log::info!("recaptcha key: {}", if config.captcha.key.as_ref().map(String::is_empty).unwrap_or(true) { "empty" } else { "set" });
+	log::info!("listening url: {:?}", listen_url);
+	log::info!("tls listening url: {:?}", config.tls.listen_url);
+	log::info!("database url: {}", redact_url(config.database.url.as_ref().map(String::as_str).unwrap_or_default()));
+	log::info!("database pool: {}", config.database.pool_size);
+	log::info!("CORS origins: {:?}", config.cors.origins);
+	log::info!("metrics url: {:?}", metrics_url);
+	log::info!("unix socket: {:?}", config.server.unix_socket);
+	log::info!("features: {:?}", features);
+
+	let sys = actix::System::new("actix_sys");
+	telemetry::Exporter::start();
 	// let state = web::Data::new(Mutex::new(dbx::db_init()));
-	initialize_state(&database_url);
-
-	let serv = HttpServer::new(move || {
-		           App::new().wrap(Cors::new()
-		                           .allowed_origin(&cors_origin)
-		                           .allowed_origin("https://akropolis.io")
-		                           .allowed_origin("https://*.akropolis.io")
-		                           .allowed_methods(vec!["GET", "POST", "OPTION"])
-		                           .send_wildcard()
-		                           .max_age(3600))
+	initialize_state(config);
+	geoip::Reloader::start();
+	screening::Reloader::start();
+	reputation::Reloader::start();
+	tls::Reloader::start();
+
+	// the public API, served by `HttpServer` on TCP and by `uds` on unix sockets
+	let app = move || {
+		           let cors = |methods: &[Method]| cors::Cors::new(std::sync::Arc::clone(&cors_policy), methods);
+		           App::new().wrap(tls::Hsts::new(hsts.clone()))
+		                     .wrap(metrics::RequestMetrics)
+		                     .wrap(telemetry::Tracing)
+		                     .wrap(logging::RequestLog)
 		         //  .register_data(state.clone())
 		         //  .data(web::JsonConfig::default().limit(4096))
 		          .service(web::resource("/1.0/").data(web::JsonConfig::default().limit(4096))
+		                                         .wrap(cors(&[Method::GET, Method::POST]))
 		                                      // .route(web::get().to_async(search))
 		                                         .route(web::get().to_async(search))
 		                                         .route(web::post().to_async(register))
 		                                         .route(web::head().to(|| HttpResponse::MethodNotAllowed())))
 		          // query fallbacks:
-		          .service(web::resource("/1.0/get").route(web::get().to_async(search_query)))
-		          .service(web::resource("/1.0/set").route(web::get().to_async(register_query)))
-		          .service(web::resource("/1.0/recaptcha_test/")
-		                                                       .route(web::get().to_async(recaptcha_test))
-		                                                       .route(web::post().to_async(recaptcha_test))
-		                  )
-		          }).bind(listen_url)?;
-
-	println!("starting");
-	serv.run()?;
-	println!("exitting");
-	// _sys.run()
+		          .configure(|cfg| {
+			          if features.query_fallbacks {
+				          cfg.service(web::resource("/1.0/get").wrap(cors(&[Method::GET])).route(web::get().to_async(search_query)))
+				             .service(web::resource("/1.0/set").wrap(cors(&[Method::GET])).route(web::get().to_async(register_query)));
+			          }
+			         })
+		          .service(web::resource("/1.0/stats").wrap(cors(&[Method::GET])).route(web::get().to(campaign_stats)))
+		          .service(web::resource("/1.0/form-token").wrap(cors(&[Method::GET])).route(web::get().to(risk::form_token)))
+		          .service(web::resource("/healthz").route(web::get().to(health::healthz))
+		                                            .route(web::head().to(health::healthz)))
+		          .service(web::resource("/readyz").route(web::get().to(health::readyz))
+		                                           .route(web::head().to(health::readyz)))
+		          .configure(|cfg| {
+			          if features.tasks {
+				          cfg.service(web::resource("/1.0/tasks").wrap(cors(&[Method::GET])).route(web::get().to(task_list)))
+				             .service(web::resource("/1.0/submissions").data(web::JsonConfig::default().limit(16384))
+				                                                      .wrap(cors(&[Method::GET, Method::POST]))
+				                                                      .route(web::get().to_async(submission_list))
+				                                                      .route(web::post().to_async(submit)));
+			          }
+			          if features.admin {
+				          cfg.service(admin::scope());
+			          }
+			          if features.recaptcha_test {
+				          cfg.service(web::resource("/1.0/recaptcha_test/").wrap(cors(&[Method::GET, Method::POST]))
+				                                                           .route(web::get().to_async(recaptcha_test))
+				                                                           .route(web::post().to_async(recaptcha_test)));
+			          }
+			         })
+		          };
+	let server_config = &state::State::get().config().server;
+	let shutdown_timeout = server_config.shutdown_timeout_secs;
+	// before binding, a running instance keeps its unix socket
+	let _pid_file = match &server_config.pid_file {
+		Some(path) => Some(shutdown::PidFile::create(path)?),
+		None => None,
+	};
+	let mut activated = systemd::Activated::take()?;
+	let mut servers = Vec::new();
+
+	let mut serv = HttpServer::new(app.clone()).disable_signals().shutdown_timeout(shutdown_timeout);
+	let mut bound = false;
+	let https = activated.tcp("https")?;
+	let redirect = activated.tcp("redirect")?;
+	if state::State::get().tls().is_none() && !(https.is_empty() && redirect.is_empty()) {
+		return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "sockets named https or redirect need the tls section"));
+	}
+	if let Some(certs) = state::State::get().tls() {
+		let acceptor = || certs.acceptor().map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err.to_string()));
+		if https.is_empty() {
+			serv = serv.bind_ssl(&certs.settings().listen_url, acceptor()?)?;
+		}
+		for listener in https {
+			serv = serv.listen_ssl(listener, acceptor()?)?;
+		}
+		bound = true;
+
+		let redirect_url = certs.settings().redirect_url.as_ref().filter(|_| redirect.is_empty());
+		if !redirect.is_empty() || redirect_url.is_some() {
+			let mut redirect_server = HttpServer::new(|| App::new().default_service(web::to(tls::redirect)))
+				.workers(1)
+				.disable_signals()
+				.shutdown_timeout(shutdown_timeout);
+			if let Some(redirect_url) = redirect_url {
+				redirect_server = redirect_server.bind(redirect_url)?;
+			}
+			for listener in redirect {
+				redirect_server = redirect_server.listen(listener)?;
+			}
+			servers.push(redirect_server.start());
+		}
+	}
+
+	// metrics are kept off the public listener
+	let metrics = activated.tcp("metrics")?;
+	let metrics_url = metrics_url.filter(|_| metrics.is_empty());
+	if !metrics.is_empty() || metrics_url.is_some() {
+		let mut metrics_server =
+			HttpServer::new(|| App::new().service(web::resource("/metrics").route(web::get().to(metrics::handler))))
+				.workers(1)
+				.disable_signals()
+				.shutdown_timeout(shutdown_timeout);
+		if let Some(metrics_url) = metrics_url {
+			metrics_server = metrics_server.bind(metrics_url)?;
+		}
+		for listener in metrics {
+			metrics_server = metrics_server.listen(listener)?;
+		}
+		servers.push(metrics_server.start());
+	}
+
+	// any other passed socket serves the public API instead of the configured plain listeners
+	let (tcp, unix) = activated.public();
+	let activated_public = !tcp.is_empty() || !unix.is_empty();
+	if let Some(listen_url) = listen_url.filter(|_| !activated_public) {
+		serv = serv.bind(listen_url)?;
+		bound = true;
+	}
+	for listener in tcp {
+		serv = serv.listen(listener)?;
+		bound = true;
+	}
+	let mut unix_listeners = Vec::new();
+	for listener in unix {
+		unix_listeners.push(uds::serve(listener, None, app.clone())?);
+	}
+	if let Some(path) = server_config.unix_socket.as_ref().filter(|_| !activated_public) {
+		let mode = uds::parse_mode(&server_config.unix_socket_mode).expect("validated config");
+		unix_listeners.push(uds::serve(uds::bind(path, mode)?, Some(path.clone()), app.clone())?);
+	}
+
+	log::info!("starting");
+	if bound {
+		servers.push(serv.start());
+	}
+	shutdown::Shutdown::start(servers,
+	                          unix_listeners,
+	                          std::time::Duration::from_secs(server_config.shutdown_delay_secs),
+	                          std::time::Duration::from_secs(shutdown_timeout));
+	systemd::Watchdog::start();
+	systemd::notify("READY=1");
+	sys.run()?;
+	log::info!("exiting");
 	Ok(())
 }
 
+/// Database url without the password, for logging.
+fn redact_url(database_url: &str) -> String {
+	match url::Url::parse(database_url) {
+		Ok(mut url) => {
+			if url.password().is_some() {
+				let _ = url.set_password(Some("***"));
+			}
+			url.into_string()
+		},
+		Err(_) => database_url.to_owned(),
+	}
+}
+
 
-pub fn initialize_state(database_url: &str) {
+/// Builds the state from a validated config.
+pub fn initialize_state(config: config::Config) {
 	// #[cfg(not(feature = "dbpool"))]
 	// let sqldb = db::initialize();
-	let pool_size = if cfg!(feature = "sqlite") { 1 } else { 4 };
+	let database_url = config.database.url().expect("validated config");
 	// let conn = db::establish_connection(database_url);
-	let conn = db::establish_connection_pool(pool_size, database_url);
-	state::State::initialize(state::State::new(conn));
+	let conn = db::establish_connection_pool(&config.database, database_url);
+	let referral = referral::Settings::from_config(&config.referral).expect("validated config");
+	let admin_session = admin::session::Settings::from_config(&config.admin);
+	let stats = stats::Cache::from_config(&config.stats);
+	let ratelimit = ratelimit::Limiter::from_config(&config.rate_limit, std::sync::Arc::clone(&conn)).expect("validated config");
+	let client_ip = client_ip::Settings::from_config(&config.client_ip).expect("validated config");
+	let geoip = geoip::Settings::from_config(&config.geoip).and_then(geoip::Geoip::new).expect("invalid geoip database");
+	let screening = screening::Screening::from_config(&config.screening).expect("invalid screening lists");
+	let reputation = reputation::Settings::from_config(&config.reputation).map_err(failure::err_msg)
+	                                                                      .and_then(reputation::Reputation::new)
+	                                                                      .expect("invalid reputation lists");
+	let risk = risk::Settings::from_config(&config.risk).expect("validated config");
+	let tls = tls::Settings::from_config(&config.tls).expect("validated config")
+	                                                 .map(tls::Certs::new)
+	                                                 .transpose()
+	                                                 .expect("invalid tls certificate");
+	state::State::initialize(state::State::new(config,
+	                                           conn,
+	                                           referral,
+	                                           admin_session,
+	                                           stats,
+	                                           ratelimit,
+	                                           client_ip,
+	                                           geoip,
+	                                           screening,
+	                                           reputation,
+	                                           risk,
+	                                           tls));
 }
 
 
@@ -97,7 +293,7 @@ fn recaptcha_test(data: web::Json<api::Get>, req: HttpRequest) -> impl Future<It
 	let fut = recaptcha_future(data.0.recaptcha, req);
 
 	let fut = fut.map(|result| {
-		             println!("result: {:?}", result);
+		             log::debug!("result: {:?}", result);
 		             match result {
 			             Ok(_) => HttpResponse::NotFound().json(api::ApiError::UserNotFound.to_resp()),
 		               Err(err) => HttpResponse::NotFound().json(err.to_resp()),
@@ -107,6 +303,92 @@ fn recaptcha_test(data: web::Json<api::Get>, req: HttpRequest) -> impl Future<It
 }
 
 
+/// Coarse numbers, `/admin/stats` has the details.
+fn campaign_stats() -> HttpResponse {
+	let state = state::State::get();
+	let conn = state.get_pool().get().unwrap();
+
+	match state.stats().get(&conn) {
+		Ok(stats) => HttpResponse::Ok().json(stats::PublicStats::from(&*stats)),
+		Err(err) => {
+			log::error!("stats: {:?}", err);
+			HttpResponse::InternalServerError().json(api::ApiError::Internal.to_resp())
+		},
+	}
+}
+
+
+fn task_list() -> HttpResponse {
+	let state = state::State::get();
+	let conn = state.get_pool().get().unwrap();
+
+	let _query = db::instrument("tasks");
+	match tasks::active_tasks(&conn) {
+		Ok(list) => HttpResponse::Ok().json(list),
+		Err(err) => {
+			log::error!("tasks: {:?}", err);
+			HttpResponse::InternalServerError().json(api::ApiError::Internal.to_resp())
+		},
+	}
+}
+
+
+fn submission_list(query: web::Query<api::Get>, req: HttpRequest) -> impl Future<Item = HttpResponse, Error = Error> {
+	log::debug!("req: {:?}, query: {:?}", req, query);
+
+	let fut = recaptcha_future(query.recaptcha.clone(), req);
+
+	fut.map(move |result| {
+		   log::debug!("recaptcha result: {:?}", result);
+		   match result {
+			   Ok(_) => {
+			     let state = state::State::get();
+			     if let Some(hit) = state.screening().screen(&query.address) {
+				     log::info!("submissions: blocked by {}", hit.list);
+				     return HttpResponse::NotFound().json(api::ApiError::AddressBlocked.to_resp());
+				    }
+			     let conn = state.get_pool().get().unwrap();
+
+			     let _query = db::instrument("submissions");
+			     match tasks::user_submissions(&conn, &query.address) {
+				     Ok(list) => HttpResponse::Ok().json(api::SubmissionsResp::from(list)),
+				     Err(err) => {
+					     log::error!("submissions: {:?}", err);
+					     HttpResponse::InternalServerError().json(api::ApiError::Internal.to_resp())
+					    },
+			     }
+			    },
+		     Err(err) => HttpResponse::NotFound().json(err.to_resp()),
+		   }
+		  })
+}
+
+fn submit(data: web::Json<api::Submit>, req: HttpRequest) -> impl Future<Item = HttpResponse, Error = Error> {
+	log::debug!("req: {:?}, data: {:?}", req, data);
+
+	let fut = recaptcha_future(data.recaptcha.clone(), req);
+
+	fut.map(move |result| {
+		   log::debug!("recaptcha result: {:?}", result);
+		   match result {
+			   Ok(_) => {
+			     let state = state::State::get();
+			     let conn = state.get_pool().get().unwrap();
+
+			     let _query = db::instrument("submit");
+			     match tasks::submit(&conn, &data.address, data.task_id, &data.proof_url, &data.proof_text)
+			           .and_then(|_| tasks::user_submissions(&conn, &data.address).map_err(tasks::Error::from))
+			     {
+				     Ok(list) => HttpResponse::Created().json(api::SubmissionsResp::from(list)),
+				     Err(err) => HttpResponse::NotFound().json(api::ApiError::from(err).to_resp()),
+			     }
+			    },
+		     Err(err) => HttpResponse::NotFound().json(err.to_resp()),
+		   }
+		  })
+}
+
+
 fn search_query(query: web::Query<api::Get>, req: HttpRequest) -> impl Future<Item = HttpResponse, Error = Error> {
 	log::debug!("req: {:?}, query: {:?}", req, query);
 	search(web::Json(query.into_inner()), req)
@@ -115,13 +397,24 @@ fn search_query(query: web::Query<api::Get>, req: HttpRequest) -> impl Future<It
 fn search(data: web::Json<api::Get>, req: HttpRequest) -> impl Future<Item = HttpResponse, Error = Error> {
 	log::debug!("req: {:?}, data: {:?}", req, data);
 
-	let fut = recaptcha_future(data.0.recaptcha.clone(), req);
+	if let Err(resp) = ratelimit::check_ip(&req, ratelimit::Action::Lookup) {
+		return futures::future::Either::A(futures::future::ok(resp));
+	}
 
-	fut.map(move |result| {
+	let fut = recaptcha_future(data.0.recaptcha.clone(), req.clone());
+
+	futures::future::Either::B(fut.map(move |result| {
 		   log::debug!("recaptcha result: {:?}", result);
 		   match result {
 			   Ok(_) => {
+			     if let Err(resp) = ratelimit::check_address(ratelimit::Action::Lookup, &data.0.address) {
+				     return resp;
+				    }
 			     let state = state::State::get();
+			     if let Some(hit) = state.screening().screen(&data.0.address) {
+				     log::info!("get: blocked by {}", hit.list);
+				     return HttpResponse::NotFound().json(api::ApiError::AddressBlocked.to_resp());
+				    }
 			     let conn = state.get_pool().get().unwrap();
 
 			     let user = {
@@ -129,7 +422,9 @@ fn search(data: web::Json<api::Get>, req: HttpRequest) -> impl Future<Item = Htt
 				     use db::schema::users::dsl::{address, users};
 				     use diesel::prelude::{ExpressionMethods, QueryDsl, RunQueryDsl};
 
+				     let _query = db::instrument("lookup");
 				     let result = users.filter(address.eq(&data.0.address)).first::<User>(&conn);
+				     logging::db_outcome(&req, db_outcome(&result));
 				     match result {
 					     Ok(user) => {
 					       log::debug!("get: found: {:?}", user);
@@ -147,12 +442,40 @@ fn search(data: web::Json<api::Get>, req: HttpRequest) -> impl Future<Item = Htt
 				    } else if !user.not_resident {
 				     HttpResponse::NotFound().json(api::ApiError::UserIsResident.to_resp())
 				    } else {
-				     HttpResponse::Ok().json(api::Resp::from(user))
+				     user_resp(&conn, user, HttpResponse::Ok())
 				    }
 			    },
 		     Err(err) => HttpResponse::NotFound().json(err.to_resp()),
 		   }
-		  })
+		  }))
+}
+
+
+/// DB step of a lookup for the access log.
+fn db_outcome<T>(result: &diesel::QueryResult<T>) -> &'static str {
+	match result {
+		Ok(_) => "ok",
+		Err(diesel::result::Error::NotFound) => "not_found",
+		Err(_) => "error",
+	}
+}
+
+
+/// Responds with the user and its amounts vested at the moment.
+fn user_resp(conn: &db::TheConnection, user: db::models::User, mut resp: actix_web::dev::HttpResponseBuilder) -> HttpResponse {
+	let query = db::instrument("vesting");
+	let schedule = vesting::find_schedule(conn, user.id);
+	drop(query);
+	match schedule {
+		Ok(schedule) => {
+			let now = chrono::Utc::now().naive_utc();
+			resp.json(api::Resp::from(api::User::new(user, schedule.as_ref(), now)))
+		},
+		Err(err) => {
+			log::error!("vesting: {:?}", err);
+			HttpResponse::InternalServerError().json(api::ApiError::Internal.to_resp())
+		},
+	}
 }
 
 
@@ -165,103 +488,272 @@ fn register_query(query: web::Query<api::Reg>, req: HttpRequest)
 fn register(data: web::Json<api::Reg>, req: HttpRequest) -> impl Future<Item = HttpResponse, Error = Error> {
 	log::debug!("req: {:?}, data: {:?}", req, data);
 
-	let fut = recaptcha_future(data.0.recaptcha.clone(), req);
+	if let Err(resp) = ratelimit::check_ip(&req, ratelimit::Action::Register) {
+		return futures::future::Either::A(futures::future::ok(resp));
+	}
+
+	let ip = client_ip::resolve(&req);
+	let reputation = state::State::get().reputation().check(ip);
+	if reputation.blocked() {
+		log::info!("reg: blocked network {:?}", reputation.list);
+		let resp = HttpResponse::NotFound().json(api::ApiError::NetworkBlocked.to_resp());
+		return futures::future::Either::A(futures::future::ok(record_attempt(&req, &data, ip, &reputation, None, resp)));
+	}
+
+	let fut = recaptcha_future(data.0.recaptcha.clone(), req.clone());
+
+	futures::future::Either::B(fut.and_then(move |result| {
+		   use futures::future::Either;
 
-	fut.map(move |result| {
 		   log::debug!("recaptcha result: {:?}", result);
-		   match result {
-			   Ok(_) => {
-			     if !data.not_resident {
-				     HttpResponse::NotFound().json(api::ApiError::UserIsResident.to_resp())
-				    } else if !data.terms {
-				     HttpResponse::NotFound().json(api::ApiError::TermsNotAccepted.to_resp())
-				    } else {
-				     let state = state::State::get();
-				     let conn = state.get_pool().get().unwrap();
-
-				     let user = {
-					     use db::models::User;
-					     use db::schema::users::dsl::{address, users};
-					     use diesel::prelude::{ExpressionMethods, QueryDsl, RunQueryDsl};
-
-					     let result = users.filter(address.eq(&data.address)).first::<User>(&conn);
-					     match result {
-						     Ok(user) => {
-						       log::debug!("reg: found: {:?}", user);
-						       user
-						      },
-					       Err(err) => {
-						       log::debug!("reg: not found: {:?}", err);
-						       return HttpResponse::NotFound().json(api::ApiError::UserNotFound.to_resp());
-						      },
-					     }
-					    };
-
-				     {
-					     use diesel::prelude::*;
-					     use db::schema::users::dsl::{id, not_resident, terms_signed, users};
-
-					     let updated_row =
-						     diesel::update(users.filter(id.eq(user.id))).set((
+		   let result = result.and_then(|score| {
+			                      if reputation.score_ok(score) {
+				                      Ok(score)
+				                     } else {
+				                      log::info!("reg: score {:?} too low for {:?}", score, reputation.list);
+				                      Err(api::ApiError::RecaptchaErr(vec![recaptcha::Code::LowScore].into_iter().collect()))
+				                     }
+		                     });
+		   let score = match result {
+			   Ok(score) => score,
+			   Err(_) => {
+			     let resp = register_verified(&data, &req, result);
+			     return Either::A(futures::future::ok(record_attempt(&req, &data, ip, &reputation, None, resp)));
+			    },
+		   };
+		   if let Err(resp) = ratelimit::check_address(ratelimit::Action::Register, &data.address) {
+			   return Either::A(futures::future::ok(record_attempt(&req, &data, ip, &reputation, None, resp)));
+		   }
+
+		   let settings = state::State::get().risk();
+		   let now = chrono::Utc::now().timestamp();
+		   let mut signals = risk::Signals::new(&req, &data, score, &reputation, now);
+		   let mut assessment = risk::assess(settings, &signals);
+		   let challenge = data.challenge.clone().filter(|challenge| !challenge.is_empty());
+		   let challenge_key = state::State::get().config().captcha.challenge_key.clone();
+		   // a step-up response is resent with the challenge and the same form token,
+		   // which only a passed challenge uses up so a failed one can be retried
+		   let step_up = assessment.decision == risk::Decision::StepUp && challenge_key.is_some();
+		   if !step_up && !signals.take_form_token() {
+			   assessment = risk::assess(settings, &signals);
+		   }
+		   log::debug!("reg: risk {:?}", assessment);
+		   match (assessment.decision, challenge_key, challenge) {
+			   (risk::Decision::Allow, _, _) => {
+			     let resp = register_verified(&data, &req, Ok(score));
+			     Either::A(futures::future::ok(record_attempt(&req, &data, ip, &reputation, Some(&assessment), resp)))
+			    },
+			   (risk::Decision::StepUp, Some(key), Some(challenge)) => {
+			     Either::B(verify_captcha(&key, challenge, req.clone()).map(move |challenged| {
+				                                                         let mut assessment = assessment;
+				                                                         let result = challenged.and_then(|_| {
+					                                                                                let mut signals = risk::Signals::new(&req, &data, score, &reputation, now);
+					                                                                                if !signals.take_form_token() {
+						                                                                                assessment = risk::assess(settings, &signals);
+						                                                                                if assessment.decision == risk::Decision::Deny {
+							                                                                                log::info!("reg: refused at risk {} {:?}", assessment.score, assessment.factors);
+							                                                                                return Err(api::ApiError::RiskDenied);
+						                                                                                }
+					                                                                                }
+					                                                                                Ok(score)
+				                                                                               });
+				                                                         let resp = register_verified(&data, &req, result);
+				                                                         record_attempt(&req, &data, ip, &reputation, Some(&assessment), resp)
+				                                                        }))
+			    },
+			   (risk::Decision::StepUp, Some(_), None) => {
+			     log::info!("reg: step-up at risk {}", assessment.score);
+			     let resp = HttpResponse::NotFound().json(api::ApiError::StepUpRequired.to_resp());
+			     Either::A(futures::future::ok(record_attempt(&req, &data, ip, &reputation, Some(&assessment), resp)))
+			    },
+			   _ => {
+			     log::info!("reg: refused at risk {} {:?}", assessment.score, assessment.factors);
+			     let resp = HttpResponse::NotFound().json(api::ApiError::RiskDenied.to_resp());
+			     Either::A(futures::future::ok(record_attempt(&req, &data, ip, &reputation, Some(&assessment), resp)))
+			    },
+		   }
+		  }))
+}
+
+/// Records the attempt and passes the response on.
+fn record_attempt(req: &HttpRequest,
+                  data: &api::Reg,
+                  ip: Option<std::net::IpAddr>,
+                  reputation: &reputation::Verdict,
+                  risk: Option<&risk::Assessment>,
+                  resp: HttpResponse)
+                  -> HttpResponse {
+	if let Ok(conn) = state::State::get().get_pool().get() {
+		attempts::record(&conn, req, data, ip, reputation.list.as_ref().map(String::as_str), risk, attempts::outcome(&resp));
+	}
+	resp
+}
+
+/// Registration after the captcha and network checks.
+fn register_verified(data: &api::Reg, req: &HttpRequest, result: Result<Option<f64>, api::ApiError>) -> HttpResponse {
+	match result {
+		Ok(_) => {
+		  let residency = state::State::get().geoip().residency(client_ip::resolve(req));
+		  let blocked = state::State::get().screening().screen(&data.address);
+		  if !data.not_resident {
+			  HttpResponse::NotFound().json(api::ApiError::UserIsResident.to_resp())
+			 } else if !data.terms {
+			  HttpResponse::NotFound().json(api::ApiError::TermsNotAccepted.to_resp())
+			 } else if residency.rejected() {
+			  log::info!("reg: rejected, client in {:?}", residency.country);
+			  HttpResponse::NotFound().json(api::ApiError::RestrictedCountry.to_resp())
+			 } else if let Some(hit) = blocked {
+			  log::info!("reg: blocked by {}", hit.list);
+			  HttpResponse::NotFound().json(api::ApiError::AddressBlocked.to_resp())
+			 } else {
+			  let state = state::State::get();
+			  let conn = state.get_pool().get().unwrap();
+
+			  let user = {
+				  use db::models::User;
+				  use db::schema::users::dsl::{address, users};
+				  use diesel::prelude::{ExpressionMethods, QueryDsl, RunQueryDsl};
+
+				  let _query = db::instrument("lookup");
+				  let result = users.filter(address.eq(&data.address)).first::<User>(&conn);
+				  logging::db_outcome(req, db_outcome(&result));
+				  match result {
+					  Ok(user) => {
+					    log::debug!("reg: found: {:?}", user);
+					    user
+					   },
+				    Err(err) => {
+					    log::debug!("reg: not found: {:?}", err);
+					    return HttpResponse::NotFound().json(api::ApiError::UserNotFound.to_resp());
+					   },
+				  }
+				 };
+
+			  {
+				  use diesel::prelude::*;
+				  use db::schema::users::dsl::{country_flagged, id, not_resident, registered_at, registered_country, terms_signed,
+				                               users};
+
+				  let query = db::instrument("register");
+				  let res = conn.transaction::<_, referral::Error, _>(|| {
+					  let updated_row =
+						  diesel::update(users.filter(id.eq(user.id))).set((
 							not_resident.eq(data.not_resident),
 							terms_signed.eq(data.terms),
+							country_flagged.eq(user.country_flagged || residency.flagged()),
 						));
 
-					     log::debug!("updating user: #{} <- {:?}", user.id, data);
-					     let res = updated_row.execute(&conn);
-					     log::debug!("updated user: #{} with {:?}", user.id, res);
-
-					     HttpResponse::Found().json(api::Resp::from(user))
-					    }
-				    }
-		     },
-		     Err(err) => HttpResponse::NotFound().json(err.to_resp()),
-		   }
-		  })
+					  log::debug!("updating user: #{} <- {:?}", user.id, data);
+					  let res = updated_row.execute(&conn)?;
+					  log::debug!("updated user: #{} with {:?}", user.id, res);
+					  // the country of the first registration, a later one from elsewhere must not replace it
+					  diesel::update(users.filter(id.eq(user.id)).filter(registered_at.is_null()))
+						  .set((registered_at.eq(Some(chrono::Utc::now().naive_utc())), registered_country.eq(&residency.country)))
+						  .execute(&conn)?;
+
+					  match data.referrer.as_ref().filter(|referrer| !referrer.is_empty()) {
+						  Some(referrer) => referral::refer(&conn, state.referral(), &user, referrer),
+						  None => Ok(()),
+					  }
+					 });
+
+				  drop(query);
+				  logging::db_outcome(req, match &res {
+					  Err(referral::Error::Db(_)) => "error",
+					  _ => "ok",
+				  });
+
+				  match res {
+					  Ok(_) => user_resp(&conn, user, HttpResponse::Found()),
+					  Err(err) => HttpResponse::NotFound().json(api::ApiError::from(err).to_resp()),
+				  }
+				 }
+			 }
+	  },
+	  Err(err) => HttpResponse::NotFound().json(err.to_resp()),
+	}
 }
 
 
+/// Verifies the captcha, `Ok` has the score of reCAPTCHA v3.
 fn recaptcha_future(recaptcha: String, req: HttpRequest)
-                    -> impl Future<Item = Result<(), api::ApiError>, Error = Error> {
+                    -> impl Future<Item = Result<Option<f64>, api::ApiError>, Error = Error> {
+	let key = state::State::get().config().captcha.key.as_ref().map(String::as_str).unwrap_or_default();
+	verify_captcha(key, recaptcha, req)
+}
+
+/// Verifies a captcha response against the secret `key`.
+fn verify_captcha(key: &str, recaptcha: String, req: HttpRequest)
+                  -> impl Future<Item = Result<Option<f64>, api::ApiError>, Error = Error> {
 	use actix_web::client::Client;
+	use futures::future::Either;
+
+	if !recaptcha::breaker().allow() {
+		log::warn!("recaptcha: circuit open, failing fast");
+		logging::captcha_outcome(&req, "unavailable");
+		return Either::A(futures::future::err(actix_web::error::ErrorServiceUnavailable("reCAPTCHA is unavailable")));
+	}
 
 	let client = Client::default();
 	let url = {
-		let key = env::var("RECAPTCHA_KEY").expect("RECAPTCHA_KEY must be set");
-		let addr: Option<std::net::IpAddr> = match req.connection_info().remote().map(|addr| addr.parse()) {
-			Some(Ok(addr)) => Some(addr),
-			_ => None,
-		};
-		recaptcha::url(&key, &recaptcha, addr.as_ref())
+		let addr = client_ip::resolve(&req);
+		recaptcha::url(key, &recaptcha, addr.as_ref())
 	};
 
-	client.get(url.as_ref())
+	let mut span = telemetry::Span::start("recaptcha siteverify", telemetry::Kind::Client);
+	span.set_attribute("http.method", "GET");
+	span.set_attribute("net.peer.name", url.host_str().unwrap_or_default());
+	let send_req = req.clone();
+	let fut = client.get(url.as_ref())
 	      .send()
 	      .map(|resp| {
 		      log::debug!("OK resp: {:?}", resp);
 		      resp
 		     })
 	      .from_err()
-	      .map_err(|err| {
+	      .map_err(move |err| {
 		      log::error!("ERR resp: {:?}", err);
+		      metrics::get().recaptcha_error();
+		      recaptcha::breaker().failure();
+		      logging::captcha_outcome(&send_req, "error");
 		      err
 		     })
-	      .and_then(|mut response| {
+	      .and_then(move |mut response| {
 		      log::debug!("OK got resp: {:?}", response);
+		      let parse_req = req.clone();
 		      response.json::<recaptcha::RecaptchaResponse>()
 		              .from_err()
-		              .and_then(|response| {
+		              .and_then(move |response| {
 			              log::debug!("OK parsed resp: {:?}", response);
+			              let score = response.score;
 			              let res = match (response.success, response.error_codes) {
-				              (true, _) => Ok(()),
+				              (true, _) => Ok(score),
 			                (false, Some(errors)) => Err(api::ApiError::RecaptchaErr(errors)),
 			                (false, _) => Err(api::ApiError::RecaptchaErr(Default::default())),
 			              };
+			              recaptcha::breaker().success();
+			              match &res {
+				              Ok(_) => metrics::get().recaptcha_success(),
+			                Err(api::ApiError::RecaptchaErr(codes)) => metrics::get().recaptcha_failure(codes),
+			                Err(_) => (),
+			              }
+			              logging::captcha_outcome(&req, if res.is_ok() { "success" } else { "rejected" });
 			              futures::future::ok(res)
 			             })
-		              .map_err(|err| {
+		              .map_err(move |err| {
 			              log::error!("ERR parse: {:?}", err);
+			              metrics::get().recaptcha_error();
+			              recaptcha::breaker().failure();
+			              logging::captcha_outcome(&parse_req, "error");
 			              err
 			             })
 		     })
+	      .then(move |res| {
+		      match &res {
+			      Ok(result) => span.set_attribute("recaptcha.success", result.is_ok()),
+		        Err(_) => span.set_error(),
+		      }
+		      res
+		     });
+	Either::B(fut)
 }
@@ -0,0 +1,258 @@
+//! Prometheus metrics, served by a separate listener on `METRICS_LISTEN_URL`.
+//!
+//! - `bounty_http_requests_total` and `bounty_http_request_duration_seconds` by route, method and status
+//! - `bounty_recaptcha_checks_total` by outcome: `success`, a `recaptcha::Code` or `error` when unreachable
+//! - `bounty_db_pool_*`: pool size, idle and in-use connections, checkout wait and timeouts
+//! - `bounty_db_query_duration_seconds` by query
+
+use std::time::{Duration, Instant};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::{header, StatusCode};
+use actix_web::{Error, HttpResponse};
+use futures::future::{ok, FutureResult};
+use futures::{Future, Poll};
+use once_cell::sync::OnceCell;
+use prometheus::{Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+                 TextEncoder};
+
+use crate::recaptcha::Code;
+use crate::state::State;
+
+
+static METRICS: OnceCell<Metrics> = OnceCell::INIT;
+
+
+pub struct Metrics {
+	registry: Registry,
+	requests: IntCounterVec,
+	request_duration: HistogramVec,
+	recaptcha: IntCounterVec,
+	pool_connections: IntGaugeVec,
+	pool_max_size: IntGauge,
+	pool_wait: Histogram,
+	pool_timeouts: IntCounter,
+	query_duration: HistogramVec,
+}
+
+pub fn get() -> &'static Metrics { METRICS.get_or_init(|| Metrics::new().expect("invalid metrics")) }
+
+impl Metrics {
+	fn new() -> prometheus::Result<Self> {
+		let registry = Registry::new();
+
+		let requests = IntCounterVec::new(Opts::new("bounty_http_requests_total", "HTTP requests"),
+		                                  &["route", "method", "status"])?;
+		let request_duration =
+			HistogramVec::new(HistogramOpts::new("bounty_http_request_duration_seconds", "HTTP request latency"),
+			                  &["route", "method", "status"])?;
+		let recaptcha = IntCounterVec::new(Opts::new("bounty_recaptcha_checks_total", "reCAPTCHA verifications"),
+		                                   &["outcome"])?;
+		let pool_connections = IntGaugeVec::new(Opts::new("bounty_db_pool_connections", "Pooled DB connections"),
+		                                        &["state"])?;
+		let pool_max_size = IntGauge::new("bounty_db_pool_max_size", "Max size of the DB pool")?;
+		let pool_wait = Histogram::with_opts(HistogramOpts::new("bounty_db_pool_wait_seconds",
+		                                                        "Time to check out a DB connection"))?;
+		let pool_timeouts = IntCounter::new("bounty_db_pool_timeouts_total", "DB connection checkout timeouts")?;
+		let query_duration = HistogramVec::new(HistogramOpts::new("bounty_db_query_duration_seconds", "DB query latency"),
+		                                       &["query"])?;
+
+		registry.register(Box::new(requests.clone()))?;
+		registry.register(Box::new(request_duration.clone()))?;
+		registry.register(Box::new(recaptcha.clone()))?;
+		registry.register(Box::new(pool_connections.clone()))?;
+		registry.register(Box::new(pool_max_size.clone()))?;
+		registry.register(Box::new(pool_wait.clone()))?;
+		registry.register(Box::new(pool_timeouts.clone()))?;
+		registry.register(Box::new(query_duration.clone()))?;
+
+		Ok(Self { registry,
+		          requests,
+		          request_duration,
+		          recaptcha,
+		          pool_connections,
+		          pool_max_size,
+		          pool_wait,
+		          pool_timeouts,
+		          query_duration })
+	}
+
+	pub fn observe_request(&self, route: &str, method: &str, status: u16, elapsed: Duration) {
+		let status = status.to_string();
+		let labels = [route, method, status.as_str()];
+		self.requests.with_label_values(&labels).inc();
+		self.request_duration.with_label_values(&labels).observe(seconds(elapsed));
+	}
+
+	pub fn recaptcha_success(&self) { self.recaptcha.with_label_values(&["success"]).inc(); }
+
+	/// Every code of a failed verification, `unknown` if there is none.
+	pub fn recaptcha_failure<'a, I: IntoIterator<Item = &'a Code>>(&self, codes: I) {
+		let mut empty = true;
+		for code in codes {
+			let outcome = match code {
+				Code::Unknown(_) => "unknown".to_owned(),
+				code => code.to_string(),
+			};
+			self.recaptcha.with_label_values(&[&outcome]).inc();
+			empty = false;
+		}
+		if empty {
+			self.recaptcha.with_label_values(&["unknown"]).inc();
+		}
+	}
+
+	/// Verifier could not be reached or answered garbage.
+	pub fn recaptcha_error(&self) { self.recaptcha.with_label_values(&["error"]).inc(); }
+
+	/// Times a query until dropped.
+	pub fn query_timer(&self, query: &str) -> QueryTimer {
+		QueryTimer { histogram: self.query_duration.with_label_values(&[query]),
+		             started: Instant::now() }
+	}
+
+	/// Text exposition with the pool gauges updated.
+	pub fn render(&self) -> prometheus::Result<(String, Vec<u8>)> {
+		let pool = State::get().get_pool();
+		let state = pool.state();
+		let in_use = state.connections - state.idle_connections;
+		self.pool_connections.with_label_values(&["total"]).set(i64::from(state.connections));
+		self.pool_connections.with_label_values(&["idle"]).set(i64::from(state.idle_connections));
+		self.pool_connections.with_label_values(&["in_use"]).set(i64::from(in_use));
+		self.pool_max_size.set(i64::from(pool.max_size()));
+
+		let encoder = TextEncoder::new();
+		let mut buffer = Vec::new();
+		encoder.encode(&self.registry.gather(), &mut buffer)?;
+		Ok((encoder.format_type().to_owned(), buffer))
+	}
+}
+
+fn seconds(duration: Duration) -> f64 { duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9 }
+
+
+pub struct QueryTimer {
+	histogram: Histogram,
+	started: Instant,
+}
+
+impl Drop for QueryTimer {
+	fn drop(&mut self) { self.histogram.observe(seconds(self.started.elapsed())); }
+}
+
+
+/// Pool events for the wait time and timeouts.
+#[derive(Debug)]
+pub struct PoolEvents;
+
+impl r2d2::HandleEvent for PoolEvents {
+	fn handle_checkout(&self, event: r2d2::event::CheckoutEvent) {
+		get().pool_wait.observe(seconds(event.duration()));
+	}
+
+	fn handle_timeout(&self, _: r2d2::event::TimeoutEvent) { get().pool_timeouts.inc(); }
+}
+
+
+/// Label of paths the router did not match, a label per scanner request would be unbounded.
+pub const UNMATCHED: &str = "unmatched";
+
+/// Route label of a routed request: its path with the matched segments back as `{name}`,
+/// so there are no more labels than resources.
+pub fn route_label<B>(res: &ServiceResponse<B>) -> String {
+	if is_default_not_found(res) {
+		return UNMATCHED.to_owned();
+	}
+	let params = res.request().match_info();
+	res.request()
+	   .path()
+	   .split('/')
+	   .map(|segment| match params.iter().find(|(_, value)| !segment.is_empty() && *value == segment) {
+		   Some((name, _)) => format!("{{{}}}", name),
+		   None => segment.to_owned(),
+	   })
+	   .collect::<Vec<_>>()
+	   .join("/")
+}
+
+
+/// Middleware counting requests and their latency.
+pub struct RequestMetrics;
+
+impl<S, B> Transform<S> for RequestMetrics
+	where S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+	      S::Future: 'static,
+	      B: 'static
+{
+	type Request = ServiceRequest;
+	type Response = ServiceResponse<B>;
+	type Error = Error;
+	type InitError = ();
+	type Transform = RequestMetricsMiddleware<S>;
+	type Future = FutureResult<Self::Transform, Self::InitError>;
+
+	fn new_transform(&self, service: S) -> Self::Future { ok(RequestMetricsMiddleware { service }) }
+}
+
+pub struct RequestMetricsMiddleware<S> {
+	service: S,
+}
+
+impl<S, B> Service for RequestMetricsMiddleware<S>
+	where S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+	      S::Future: 'static,
+	      B: 'static
+{
+	type Request = ServiceRequest;
+	type Response = ServiceResponse<B>;
+	type Error = Error;
+	type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+	fn poll_ready(&mut self) -> Poll<(), Self::Error> { self.service.poll_ready() }
+
+	fn call(&mut self, req: ServiceRequest) -> Self::Future {
+		let started = Instant::now();
+		let method = req.method().to_string();
+
+		Box::new(self.service.call(req).then(move |res| {
+			                                   // the request of an error is gone with its route
+			                                   let (status, route) = match &res {
+				                                   Ok(res) => (res.status(), route_label(res)),
+			                                       Err(err) => (err.as_response_error().error_response().status(), UNMATCHED.to_owned()),
+			                                   };
+			                                   get().observe_request(&route, &method, status.as_u16(), started.elapsed());
+			                                   res
+			                                  }))
+	}
+}
+
+/// The router's own 404 has no body, the API answers its 404s with JSON.
+fn is_default_not_found<B>(res: &ServiceResponse<B>) -> bool {
+	res.status() == StatusCode::NOT_FOUND && !res.headers().contains_key(header::CONTENT_TYPE)
+}
+
+
+/// `GET /metrics` on the metrics listener.
+pub fn handler() -> HttpResponse {
+	match get().render() {
+		Ok((content_type, body)) => HttpResponse::Ok().content_type(content_type).body(body),
+		Err(err) => {
+			log::error!("metrics: {:?}", err);
+			HttpResponse::InternalServerError().finish()
+		},
+	}
+}
+
+
+#[test]
+fn route_label_test() {
+	use actix_web::test::TestRequest;
+
+	let label = |req: TestRequest, res: HttpResponse| route_label(&req.to_srv_response(res));
+	assert_eq!(label(TestRequest::with_uri("/1.0/"), HttpResponse::Ok().finish()), "/1.0/");
+	assert_eq!(label(TestRequest::with_uri("/admin/users/42/amount").param("id", "42"), HttpResponse::Ok().finish()),
+	           "/admin/users/{id}/amount");
+	assert_eq!(label(TestRequest::with_uri("/admin/ui/users/7").param("id", "7"),
+	                 HttpResponse::NotFound().json(crate::api::ApiError::UserNotFound.to_resp())),
+	           "/admin/ui/users/{id}");
+	assert_eq!(label(TestRequest::with_uri("/wp-login.php"), HttpResponse::NotFound().finish()), UNMATCHED);
+}
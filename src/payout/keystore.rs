@@ -0,0 +1,275 @@
+//! Web3 Secret Storage (keystore V3) decryption.
+//! See https://github.com/ethereum/wiki/wiki/Web3-Secret-Storage-Definition
+
+use std::num::NonZeroU32;
+use std::path::Path;
+use serde::Deserialize;
+use failure::{bail, ensure, format_err, Error};
+use secp256k1::SecretKey;
+
+use super::tx::{address_of, keccak256, parse_address};
+
+
+/// Bytes scrypt may allocate, geth's default n = 2^18 with r = 8 takes 256 MiB
+const MAX_SCRYPT_MEMORY: u128 = 1 << 30;
+/// Derived key length: the AES-128 key and the MAC key
+const DKLEN: usize = 32;
+
+#[derive(Debug, Deserialize)]
+struct KeyFile {
+	version: u32,
+	address: Option<String>,
+	#[serde(alias = "Crypto")]
+	crypto: Crypto,
+}
+
+#[derive(Debug, Deserialize)]
+struct Crypto {
+	cipher: String,
+	cipherparams: CipherParams,
+	ciphertext: String,
+	kdf: String,
+	kdfparams: KdfParams,
+	mac: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CipherParams {
+	iv: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum KdfParams {
+	Scrypt {
+		dklen: usize,
+		n: u32,
+		r: u32,
+		p: u32,
+		salt: String,
+	},
+	Pbkdf2 {
+		dklen: usize,
+		c: u32,
+		prf: String,
+		salt: String,
+	},
+}
+
+
+pub fn load<P: AsRef<Path>>(path: P, password: &str) -> Result<SecretKey, Error> {
+	let json = std::fs::read_to_string(path.as_ref())
+		.map_err(|err| format_err!("cannot read keystore {}: {}", path.as_ref().display(), err))?;
+	decrypt(&json, password)
+}
+
+pub fn decrypt(json: &str, password: &str) -> Result<SecretKey, Error> {
+	let file: KeyFile = serde_json::from_str(json)?;
+	ensure!(file.version == 3, "unsupported keystore version {}", file.version);
+
+	let crypto = file.crypto;
+	let derived = derive_key(&crypto, password.as_bytes())?;
+
+	let ciphertext = hex::decode(&crypto.ciphertext)?;
+	let mut mac_data = derived[16..32].to_vec();
+	mac_data.extend_from_slice(&ciphertext);
+	let mac = hex::decode(&crypto.mac)?;
+	if ring::constant_time::verify_slices_are_equal(&keccak256(&mac_data), &mac).is_err() {
+		bail!("keystore MAC mismatch: wrong password?");
+	}
+
+	let secret = match crypto.cipher.as_str() {
+		"aes-128-ctr" => {
+			use aes::cipher::{KeyIvInit, StreamCipher};
+
+			let iv = hex::decode(&crypto.cipherparams.iv)?;
+			ensure!(iv.len() == 16, "invalid iv length");
+			let mut data = ciphertext;
+			let mut cipher = ctr::Ctr128BE::<aes::Aes128>::new(derived[..16].into(), iv.as_slice().into());
+			cipher.apply_keystream(&mut data);
+			data
+		},
+		cipher => bail!("unsupported cipher {}", cipher),
+	};
+
+	let key = SecretKey::from_slice(&secret)?;
+
+	if let Some(address) = file.address {
+		ensure!(parse_address(&address)? == address_of(&key),
+		        "keystore address does not match the decrypted key");
+	}
+
+	Ok(key)
+}
+
+fn derive_key(crypto: &Crypto, password: &[u8]) -> Result<Vec<u8>, Error> {
+	// checked before the key buffer is allocated
+	let dklen = match &crypto.kdfparams {
+		KdfParams::Scrypt { dklen, .. } => *dklen,
+		KdfParams::Pbkdf2 { dklen, .. } => *dklen,
+	};
+	ensure!(dklen == DKLEN, "unsupported dklen {}", dklen);
+
+	match (crypto.kdf.as_str(), &crypto.kdfparams) {
+		("scrypt", KdfParams::Scrypt { dklen, n, r, p, salt }) => {
+			ensure!(*n > 1 && n.is_power_of_two(), "scrypt n must be a power of two");
+			ensure!(*r > 0 && *p > 0, "scrypt r and p must be positive");
+			let memory = |count: u32| u128::from(count) * u128::from(*r) * 128;
+			ensure!(memory(*n) <= MAX_SCRYPT_MEMORY && memory(*p) <= MAX_SCRYPT_MEMORY,
+			        "scrypt params need too much memory");
+			let mut derived = vec![0; *dklen];
+			scrypt::derive(password, &hex::decode(salt)?, *n as usize, *r as usize, *p as usize, &mut derived);
+			Ok(derived)
+		},
+		("pbkdf2", KdfParams::Pbkdf2 { dklen, c, prf, salt }) => {
+			ensure!(prf == "hmac-sha256", "unsupported prf {}", prf);
+			let iterations = NonZeroU32::new(*c).ok_or_else(|| format_err!("pbkdf2 c must be positive"))?;
+			let mut derived = vec![0; *dklen];
+			ring::pbkdf2::derive(&ring::digest::SHA256, iterations, &hex::decode(salt)?, password, &mut derived);
+			Ok(derived)
+		},
+		(kdf, _) => bail!("unsupported kdf {}", kdf),
+	}
+}
+
+
+/// scrypt (RFC 7914). The scrypt crate refuses `n >= 2^(16 r)`,
+/// which the test vector of the keystore definition uses.
+mod scrypt {
+	use std::num::NonZeroU32;
+
+	/// `n` is a power of two.
+	pub fn derive(password: &[u8], salt: &[u8], n: usize, r: usize, p: usize, out: &mut [u8]) {
+		let once = NonZeroU32::new(1).expect("non-zero");
+		let mut blocks = vec![0; p * 128 * r];
+		ring::pbkdf2::derive(&ring::digest::SHA256, once, salt, password, &mut blocks);
+		for block in blocks.chunks_mut(128 * r) {
+			romix(block, n, r);
+		}
+		ring::pbkdf2::derive(&ring::digest::SHA256, once, &blocks, password, out);
+	}
+
+	fn romix(block: &mut [u8], n: usize, r: usize) {
+		let words = 32 * r;
+		let mut x = block.chunks(4)
+		                 .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+		                 .collect::<Vec<_>>();
+		let mut mixed = vec![0; words];
+		let mut v = Vec::with_capacity(n * words);
+		for _ in 0..n {
+			v.extend_from_slice(&x);
+			block_mix(&x, &mut mixed, r);
+			std::mem::swap(&mut x, &mut mixed);
+		}
+		for _ in 0..n {
+			let j = x[words - 16] as usize & (n - 1);
+			for (word, other) in x.iter_mut().zip(&v[j * words..(j + 1) * words]) {
+				*word ^= other;
+			}
+			block_mix(&x, &mut mixed, r);
+			std::mem::swap(&mut x, &mut mixed);
+		}
+		for (bytes, word) in block.chunks_mut(4).zip(&x) {
+			bytes.copy_from_slice(&word.to_le_bytes());
+		}
+	}
+
+	fn block_mix(input: &[u32], output: &mut [u32], r: usize) {
+		let mut x = [0; 16];
+		x.copy_from_slice(&input[(2 * r - 1) * 16..]);
+		for (i, chunk) in input.chunks(16).enumerate() {
+			for (word, other) in x.iter_mut().zip(chunk) {
+				*word ^= other;
+			}
+			salsa20_8(&mut x);
+			// even blocks go to the first half, odd ones to the second
+			let at = (i / 2 + i % 2 * r) * 16;
+			output[at..at + 16].copy_from_slice(&x);
+		}
+	}
+
+	fn salsa20_8(block: &mut [u32; 16]) {
+		let mut x = *block;
+		for _ in 0..4 {
+			// column round, then row round
+			for &(a, b, c, d) in &[(0, 4, 8, 12),
+			                       (5, 9, 13, 1),
+			                       (10, 14, 2, 6),
+			                       (15, 3, 7, 11),
+			                       (0, 1, 2, 3),
+			                       (5, 6, 7, 4),
+			                       (10, 11, 8, 9),
+			                       (15, 12, 13, 14)]
+			{
+				x[b] ^= x[a].wrapping_add(x[d]).rotate_left(7);
+				x[c] ^= x[b].wrapping_add(x[a]).rotate_left(9);
+				x[d] ^= x[c].wrapping_add(x[b]).rotate_left(13);
+				x[a] ^= x[d].wrapping_add(x[c]).rotate_left(18);
+			}
+		}
+		for (word, mixed) in block.iter_mut().zip(&x) {
+			*word = word.wrapping_add(*mixed);
+		}
+	}
+}
+
+
+#[test]
+fn scrypt_test() {
+	// RFC 7914
+	let mut derived = [0; 64];
+	scrypt::derive(b"password", b"NaCl", 1024, 8, 16, &mut derived);
+	assert_eq!(hex::encode(&derived[..]),
+	           "fdbabe1c9d3472007856e7190d01e9fe7c6ad7cbc8237830e77376634b3731622eaf30d92e22a3886ff109279d9830da\
+	            c727afb94a83ee6d8360cbdfa2cc0640");
+
+	// test vector of the keystore definition
+	let json = r#"{
+		"crypto": {
+			"cipher": "aes-128-ctr",
+			"cipherparams": { "iv": "83dbcc02d8ccb40e466191a123791e0e" },
+			"ciphertext": "d172bf743a674da9cdad04534d56926ef8358534d458fffccd4e6ad2fbde479c",
+			"kdf": "scrypt",
+			"kdfparams": {
+				"dklen": 32,
+				"n": 262144,
+				"p": 8,
+				"r": 1,
+				"salt": "ab0c7876052600dd703518d6fc3fe8984592145b591fc8fb5c6d43190334ba19"
+			},
+			"mac": "2103ac29920d71da29f15d75b4a16dbe95cfd7ff8faea1056c33131d846e3097"
+		},
+		"id": "3198bc9c-6672-5ab3-d995-4942343ae5b6",
+		"version": 3
+	}"#;
+	let key = decrypt(json, "testpassword").unwrap();
+	assert_eq!(hex::encode(&key[..]), "7a28b5ba57c53603b0b07b56bba752f7784bf506fa95edc395f5cf6c7514fe9d");
+	assert!(decrypt(json, "wrong").unwrap_err().to_string().contains("MAC mismatch"));
+}
+
+#[test]
+fn pbkdf2_test() {
+	// test vector of the keystore definition
+	let json = r#"{
+		"crypto": {
+			"cipher": "aes-128-ctr",
+			"cipherparams": { "iv": "6087dab2f9fdbbfaddc31a909735c1e6" },
+			"ciphertext": "5318b4d5bcd28de64ee5559e671353e16f075ecae9f99c7a79a38af5f869aa46",
+			"kdf": "pbkdf2",
+			"kdfparams": {
+				"c": 262144,
+				"dklen": 32,
+				"prf": "hmac-sha256",
+				"salt": "ae3cd4e7013836a3df6bd7241b12db061dbe2c6785853cce422d148a624ce0bd"
+			},
+			"mac": "517ead924a9d0dc3124507e3393d175ce3ff7c1e96529c6c555ce9e51205e9b2"
+		},
+		"id": "3198bc9c-6672-5ab3-d995-4942343ae5b6",
+		"version": 3
+	}"#;
+	let key = decrypt(json, "testpassword").unwrap();
+	assert_eq!(hex::encode(&key[..]), "7a28b5ba57c53603b0b07b56bba752f7784bf506fa95edc395f5cf6c7514fe9d");
+
+	let huge = json.replace(r#""dklen": 32"#, r#""dklen": 1099511627776"#);
+	assert!(decrypt(&huge, "testpassword").unwrap_err().to_string().contains("dklen"));
+}
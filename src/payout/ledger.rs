@@ -0,0 +1,110 @@
+//! Append-only CSV ledger of signed payouts: `nonce,address,amount,tx_hash`.
+//! Re-running the command with the same ledger skips addresses already signed
+//! and continues from the next nonce.
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use failure::{format_err, Error};
+
+
+const HEADER: &str = "nonce,address,amount,tx_hash";
+
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+	pub nonce: u64,
+	/// Lowercase `0x`-prefixed address
+	pub address: String,
+	/// Amount in token base units
+	pub amount: u128,
+	pub tx_hash: String,
+}
+
+pub struct Ledger {
+	path: PathBuf,
+	entries: Vec<Entry>,
+}
+
+impl Ledger {
+	pub fn open<P: Into<PathBuf>>(path: P) -> Result<Self, Error> {
+		let path = path.into();
+		let mut entries = Vec::new();
+
+		if path.exists() {
+			let file = BufReader::new(File::open(&path)?);
+			for (n, line) in file.lines().enumerate() {
+				let line = line?;
+				if line.is_empty() || line == HEADER {
+					continue;
+				}
+				let entry = parse_line(&line).ok_or_else(|| {
+					                             format_err!("{}:{}: malformed ledger line", path.display(), n + 1)
+					                            })?;
+				entries.push(entry);
+			}
+		}
+
+		Ok(Self { path, entries })
+	}
+
+	pub fn entries(&self) -> &[Entry] { &self.entries }
+
+	pub fn next_nonce(&self) -> Option<u64> { self.entries.iter().map(|e| e.nonce + 1).max() }
+
+	pub fn contains(&self, address: &str) -> bool {
+		let address = address.to_lowercase();
+		self.entries.iter().any(|e| e.address == address)
+	}
+
+	pub fn append(&mut self, entry: Entry) -> Result<(), Error> {
+		let new = !self.path.exists();
+		let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+		if new {
+			writeln!(file, "{}", HEADER)?;
+		}
+		writeln!(file, "{},{},{},{}", entry.nonce, entry.address, entry.amount, entry.tx_hash)?;
+		file.sync_data()?;
+		self.entries.push(entry);
+		Ok(())
+	}
+}
+
+fn parse_line(line: &str) -> Option<Entry> {
+	let mut parts = line.split(',');
+	let entry = Entry { nonce: parts.next()?.parse().ok()?,
+	                    address: parts.next()?.to_lowercase(),
+	                    amount: parts.next()?.parse().ok()?,
+	                    tx_hash: parts.next()?.to_owned() };
+	Some(entry)
+}
+
+
+#[test]
+fn resume_test() {
+	let path = std::env::temp_dir().join(format!("bounty-ledger-{}.csv", std::process::id()));
+	let _ = std::fs::remove_file(&path);
+
+	let mut ledger = Ledger::open(&path).unwrap();
+	assert_eq!(ledger.next_nonce(), None);
+	for (nonce, address) in [(7, "0x00000000000000000000000000000000000000aa"),
+	                         (8, "0x00000000000000000000000000000000000000bb")].iter()
+	{
+		ledger.append(Entry { nonce: *nonce,
+		                      address: address.to_string(),
+		                      amount: 1000,
+		                      tx_hash: format!("0x{:064x}", nonce) })
+		      .unwrap();
+	}
+
+	let ledger = Ledger::open(&path).unwrap();
+	assert_eq!(ledger.entries().len(), 2);
+	assert_eq!(ledger.next_nonce(), Some(9));
+	assert!(ledger.contains("0x00000000000000000000000000000000000000AA"));
+	assert!(!ledger.contains("0x00000000000000000000000000000000000000cc"));
+	assert_eq!(std::fs::read_to_string(&path).unwrap().lines().next(), Some(HEADER));
+
+	std::fs::write(&path, "nonce,address,amount,tx_hash\n7,0xaa,not a number,0x\n").unwrap();
+	assert!(Ledger::open(&path).is_err());
+	std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,261 @@
+//! Offline payouts: signs ERC-20 transfers for eligible allocations
+//! with a key from a keystore file, without talking to a node.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use failure::{bail, format_err, Error};
+use diesel::prelude::*;
+use serde::Serialize;
+
+use crate::cli::{self, Args};
+use crate::config::Config;
+use crate::db::TheConnection;
+use crate::db::models::{PayoutStatus, User, VestingSchedule};
+use crate::screening::Screening;
+
+pub mod keystore;
+pub mod ledger;
+pub mod reconcile;
+pub mod tx;
+
+use self::ledger::{Entry, Ledger};
+use self::tx::{Fee, Transaction};
+
+
+const DEFAULT_DECIMALS: u32 = 18;
+const DEFAULT_GAS_LIMIT: u64 = 100_000;
+
+
+/// Users who accepted the terms, declared non-residency, have something to receive,
+/// were not found ineligible and have no payout in flight, with their vesting schedules.
+pub fn eligible_allocations(conn: &TheConnection) -> QueryResult<Vec<(User, Option<VestingSchedule>)>> {
+	use crate::db::schema::users::dsl::*;
+	use crate::db::schema::vesting_schedules;
+
+	users.left_join(vesting_schedules::table)
+	     .filter(terms_signed.eq(true))
+	     .filter(not_resident.eq(true))
+	     .filter(amount.gt(0))
+	     .filter(ineligible.eq(false))
+	     .filter(payout_status.ne(PayoutStatus::Sent.as_str()))
+	     .order(id.asc())
+	     .load::<(User, Option<VestingSchedule>)>(conn)
+}
+
+/// An eligible allocation left out because its address is blocked.
+#[derive(Debug, Clone, Serialize)]
+pub struct Withheld {
+	pub user_id: i32,
+	pub address: String,
+	pub amount: i64,
+	/// Vested and not paid yet
+	pub due: i64,
+	pub list: String,
+	pub reason: Option<String>,
+}
+
+/// Splits allocations into the ones to pay and the ones the screening lists withhold.
+pub fn screen_allocations(allocations: Vec<(User, Option<VestingSchedule>)>,
+                          screening: &Screening,
+                          now: chrono::NaiveDateTime)
+                          -> (Vec<(User, Option<VestingSchedule>)>, Vec<Withheld>) {
+	let mut withheld = Vec::new();
+	let allowed = allocations.into_iter()
+	                         .filter(|(user, schedule)| match screening.screen(&user.address) {
+		                         Some(hit) => {
+		                           withheld.push(Withheld { user_id: user.id,
+		                                                    address: user.address.clone(),
+		                                                    amount: user.amount,
+		                                                    due: crate::vesting::due(schedule.as_ref(),
+		                                                                             user.amount,
+		                                                                             user.paid_amount,
+		                                                                             now),
+		                                                    list: hit.list,
+		                                                    reason: hit.reason });
+		                           false
+		                          },
+		                         None => true,
+		                        })
+	                         .collect();
+	(allowed, withheld)
+}
+
+fn write_withheld(withheld: &[Withheld], path: Option<&str>) -> Result<(), Error> {
+	let out: Box<dyn Write> = match path {
+		Some(path) => Box::new(std::fs::File::create(path)?),
+		None => Box::new(std::io::stdout()),
+	};
+	let mut writer = csv::Writer::from_writer(out);
+	for entry in withheld {
+		writer.serialize(entry)?;
+	}
+	writer.flush()?;
+	Ok(())
+}
+
+
+/// Whole tokens to token base units.
+pub fn to_base_units(amount: i64, decimals: u32) -> Result<u128, Error> {
+	if amount < 0 {
+		bail!("negative amount {}", amount);
+	}
+	10u128.checked_pow(decimals)
+	      .and_then(|unit| unit.checked_mul(amount as u128))
+	      .ok_or_else(|| format_err!("amount {} overflows with {} decimals", amount, decimals))
+}
+
+
+/// `sign-payouts` command.
+///
+/// Options:
+/// - `--keystore PATH` keystore V3 file; password from `--password-file PATH` or `KEYSTORE_PASSWORD`
+/// - `--token ADDRESS` ERC-20 contract, `--decimals N` (default 18)
+/// - `--chain-id N`, `--nonce N` (first nonce, taken from the ledger when resuming)
+/// - `--gas-limit N` and either `--gas-price WEI` (EIP-155)
+///   or `--max-fee WEI` with `--max-priority-fee WEI` (EIP-1559)
+/// - `--out PATH` raw transactions, `--ledger PATH` nonce ledger (one per payout round)
+/// - `--withheld PATH` allocations blocked by the screening lists as CSV
+///
+/// Each allocation gets the amount vested so far minus what was already paid.
+/// Addresses on the `screening.blocklists` are never signed for.
+pub fn sign_payouts(config: &Config, args: &Args) -> Result<(), Error> {
+	let password = match args.get("password-file") {
+		Some(path) => std::fs::read_to_string(path)?.trim_end_matches(&['\r', '\n'][..]).to_owned(),
+		None => std::env::var("KEYSTORE_PASSWORD")
+			.map_err(|_| format_err!("--password-file or KEYSTORE_PASSWORD is required"))?,
+	};
+	let key = keystore::load(args.require("keystore")?, &password)?;
+	let sender = tx::address_of(&key);
+
+	let token = tx::parse_address(args.require("token")?)?;
+	let decimals = args.parse_opt("decimals")?.unwrap_or(DEFAULT_DECIMALS);
+	let chain_id: u64 = args.parse_required("chain-id")?;
+	let gas_limit = args.parse_opt("gas-limit")?.unwrap_or(DEFAULT_GAS_LIMIT);
+
+	let fee = match (args.parse_opt("gas-price")?, args.parse_opt("max-fee")?) {
+		(Some(gas_price), None) => Fee::Legacy { gas_price },
+		(None, Some(max_fee)) => {
+			let max_priority_fee = args.parse_required("max-priority-fee")?;
+			if max_priority_fee > max_fee {
+				bail!("--max-priority-fee is greater than --max-fee");
+			}
+			Fee::Eip1559 { max_priority_fee, max_fee }
+		},
+		_ => bail!("exactly one of --gas-price or --max-fee is required"),
+	};
+
+	let out_path = args.get("out").unwrap_or("payouts.txt");
+	let mut ledger = Ledger::open(args.get("ledger").unwrap_or("payouts.ledger.csv"))?;
+
+	let mut nonce = match (ledger.next_nonce(), args.parse_opt::<u64>("nonce")?) {
+		(Some(next), Some(nonce)) if next != nonce => {
+			bail!("ledger continues at nonce {} but --nonce {} given", next, nonce)
+		},
+		(Some(next), _) => next,
+		(None, Some(nonce)) => nonce,
+		(None, None) => bail!("--nonce is required for a new ledger"),
+	};
+
+	let conn = cli::establish_connection(config)?;
+	let now = chrono::Utc::now().naive_utc();
+	let (allocations, withheld) = screen_allocations(eligible_allocations(&conn)?, &Screening::from_config(&config.screening)?, now);
+	log::info!("signing from {} with chain id {}, {} eligible allocations, {} withheld",
+	           tx::format_address(&sender),
+	           chain_id,
+	           allocations.len(),
+	           withheld.len());
+	if let Some(path) = args.get("withheld") {
+		write_withheld(&withheld, Some(path))?;
+	}
+
+	let mut out = OpenOptions::new().create(true).append(true).open(out_path)?;
+	let mut signed_count = 0;
+
+	for (user, schedule) in allocations {
+		let due = crate::vesting::due(schedule.as_ref(), user.amount, user.paid_amount, now);
+		if due == 0 {
+			continue;
+		}
+
+		let to = match tx::parse_address(&user.address) {
+			Ok(to) => to,
+			Err(err) => {
+				log::warn!("skipping user #{} with invalid address {:?}: {}", user.id, user.address, err);
+				continue;
+			},
+		};
+		let address = tx::format_address(&to);
+		if ledger.contains(&address) {
+			log::debug!("already signed: {}", address);
+			continue;
+		}
+
+		let amount = to_base_units(due, decimals)?;
+		let transaction = Transaction { chain_id,
+		                                nonce,
+		                                fee,
+		                                gas_limit,
+		                                to: token,
+		                                value: 0,
+		                                data: tx::erc20_transfer(&to, amount) };
+		let signed = transaction.sign(&key)?;
+
+		writeln!(out, "0x{}", hex::encode(&signed.raw))?;
+		out.sync_data()?;
+		ledger.append(Entry { nonce,
+		                      address,
+		                      amount,
+		                      tx_hash: format!("0x{}", hex::encode(&signed.hash)) })?;
+
+		nonce += 1;
+		signed_count += 1;
+	}
+
+	log::info!("signed {} transactions, next nonce {}", signed_count, nonce);
+	Ok(())
+}
+
+
+/// `withheld-report [--out PATH]` command: eligible allocations the screening lists withhold
+/// as CSV, stdout by default.
+pub fn withheld_report(config: &Config, args: &Args) -> Result<(), Error> {
+	let conn = cli::establish_connection(config)?;
+	let now = chrono::Utc::now().naive_utc();
+	let (_, withheld) = screen_allocations(eligible_allocations(&conn)?, &Screening::from_config(&config.screening)?, now);
+	log::info!("{} allocations withheld, {} AKT due",
+	           withheld.len(),
+	           withheld.iter().map(|entry| entry.due).sum::<i64>());
+	write_withheld(&withheld, args.get("out"))
+}
+
+
+/// `import-payouts` command.
+///
+/// Options:
+/// - `--csv PATH` executed transfers, see `reconcile`
+/// - `--decimals N` (default 18)
+/// - `--report PATH` mismatches as CSV, stdout by default
+/// - `--dry-run` only report, do not update allocations
+pub fn import_payouts(config: &Config, args: &Args) -> Result<(), Error> {
+	let decimals = args.parse_opt("decimals")?.unwrap_or(DEFAULT_DECIMALS);
+	let transfers = reconcile::read_transfers(std::fs::File::open(args.require("csv")?)?)?;
+
+	let conn = cli::establish_connection(config)?;
+	let report = reconcile::reconcile(&conn, &transfers, decimals, args.flag("dry-run"))?;
+
+	log::info!("{} transfers, {} applied, {} mismatches",
+	           transfers.len(),
+	           report.applied,
+	           report.mismatches.len());
+
+	let out: Box<dyn Write> = match args.get("report") {
+		Some(path) => Box::new(std::fs::File::create(path)?),
+		None => Box::new(std::io::stdout()),
+	};
+	let mut writer = csv::Writer::from_writer(out);
+	for mismatch in &report.mismatches {
+		writer.serialize(mismatch)?;
+	}
+	writer.flush()?;
+	Ok(())
+}
@@ -0,0 +1,375 @@
+//! Reconciliation of executed transfers against allocations.
+//!
+//! The input is a CSV with a header and at least `address,amount,tx_hash` columns
+//! (the payout ledger fits). Optional columns: `status` (`sent`, `confirmed` or `failed`,
+//! default `confirmed`) and `paid_at` (`%Y-%m-%d %H:%M:%S`, default now).
+
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use failure::{format_err, Error};
+use serde::{Deserialize, Serialize};
+
+use crate::db::TheConnection;
+use crate::db::models::{NewPayoutTransfer, PayoutStatus, PayoutTransfer, User, VestingSchedule};
+use super::to_base_units;
+
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Transfer {
+	pub address: String,
+	/// Amount in token base units
+	pub amount: String,
+	pub tx_hash: String,
+	#[serde(default)]
+	pub status: Option<PayoutStatus>,
+	#[serde(default)]
+	pub paid_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MismatchKind {
+	WrongAmount,
+	UnknownAddress,
+	DuplicatePayment,
+	/// A failed transfer for an allocation already paid by another one
+	FailedAfterPayment,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Mismatch {
+	/// Line in the input, header is line 1
+	pub line: usize,
+	pub kind: MismatchKind,
+	pub address: String,
+	pub tx_hash: String,
+	pub detail: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+	/// Number of allocations updated
+	pub applied: usize,
+	pub mismatches: Vec<Mismatch>,
+}
+
+
+pub fn read_transfers<R: Read>(input: R) -> Result<Vec<(usize, Transfer)>, Error> {
+	let mut reader = csv::Reader::from_reader(input);
+	let mut transfers = Vec::new();
+	for (n, record) in reader.deserialize().enumerate() {
+		let transfer: Transfer = record.map_err(|err| format_err!("line {}: {}", n + 2, err))?;
+		transfers.push((n + 2, transfer));
+	}
+	Ok(transfers)
+}
+
+
+/// Transactions imported before by lowercase hash: user id, status and tokens transferred.
+pub type Imported = HashMap<String, (i32, PayoutStatus, i64)>;
+
+/// An imported transaction and the payout state of its allocation afterwards.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Update {
+	pub user_id: i32,
+	/// Lowercase transaction hash
+	pub tx_hash: String,
+	pub status: PayoutStatus,
+	/// Tokens the transaction transferred
+	pub amount: i64,
+	/// Imported before with another status
+	pub known: bool,
+	pub payout_status: String,
+	pub payout_tx: Option<String>,
+	pub paid_amount: Option<i64>,
+	pub paid_at: Option<NaiveDateTime>,
+}
+
+
+/// Matches transfers against allocations and records their payout status.
+/// A transfer is expected to pay what is vested and not paid yet at import time.
+/// Wrong amounts are recorded and reported, duplicates, unknown addresses
+/// and failures of already paid allocations are only reported.
+/// Every transaction is kept, importing one again only records a `sent` one turning `confirmed` or `failed`.
+pub fn reconcile(conn: &TheConnection,
+                 transfers: &[(usize, Transfer)],
+                 decimals: u32,
+                 dry_run: bool)
+                 -> Result<Report, Error> {
+	use crate::db::schema::payout_transfers::dsl as t;
+	use crate::db::schema::users::dsl::*;
+	use crate::db::schema::vesting_schedules;
+
+	let now = Utc::now().naive_utc();
+
+	conn.transaction::<_, Error, _>(|| {
+		    let mut by_address = users.left_join(vesting_schedules::table)
+		                              .load::<(User, Option<VestingSchedule>)>(conn)?
+		                              .into_iter()
+		                              .map(|row| (row.0.address.to_lowercase(), row))
+		                              .collect();
+		    let mut imported = t::payout_transfers.load::<PayoutTransfer>(conn)?
+		                                          .into_iter()
+		                                          .map(|transfer| {
+			                                          let status = transfer.status.parse().unwrap_or(PayoutStatus::Confirmed);
+			                                          (transfer.tx_hash, (transfer.user_id, status, transfer.amount))
+			                                         })
+		                                          .collect();
+		    let (updates, mismatches) = match_transfers(&mut by_address, &mut imported, transfers, decimals, now)?;
+
+		    if !dry_run {
+			    for update in &updates {
+				    diesel::update(users.filter(id.eq(update.user_id))).set((payout_status.eq(&update.payout_status),
+				                                                             payout_tx.eq(&update.payout_tx),
+				                                                             paid_amount.eq(update.paid_amount),
+				                                                             paid_at.eq(update.paid_at)))
+				                                                       .execute(conn)?;
+				    if update.known {
+					    diesel::update(t::payout_transfers.filter(t::tx_hash.eq(&update.tx_hash)))
+						    .set(t::status.eq(update.status.as_str()))
+						    .execute(conn)?;
+				    } else {
+					    diesel::insert_into(t::payout_transfers).values(&NewPayoutTransfer { user_id: update.user_id,
+					                                                                         tx_hash: &update.tx_hash,
+					                                                                         amount: update.amount,
+					                                                                         status: update.status.as_str(),
+					                                                                         imported_at: now })
+					                                            .execute(conn)?;
+				    }
+			    }
+		    }
+		    Ok(Report { applied: updates.len(),
+		                mismatches })
+	    })
+}
+
+/// Matches transfers in order against the allocations by lowercase address,
+/// each update is applied to `by_address` and `imported` before the next line.
+fn match_transfers(by_address: &mut HashMap<String, (User, Option<VestingSchedule>)>,
+                   imported: &mut Imported,
+                   transfers: &[(usize, Transfer)],
+                   decimals: u32,
+                   now: NaiveDateTime)
+                   -> Result<(Vec<Update>, Vec<Mismatch>), Error> {
+	let unit = 10u128.checked_pow(decimals)
+	                 .ok_or_else(|| format_err!("too many decimals: {}", decimals))?;
+	let mut paid_now = HashSet::new();
+	let mut updates = Vec::new();
+	let mut mismatches = Vec::new();
+
+	for (line, transfer) in transfers {
+		let mismatch = |kind, detail: String| {
+			Mismatch { line: *line,
+			           kind,
+			           address: transfer.address.clone(),
+			           tx_hash: transfer.tx_hash.clone(),
+			           detail }
+		};
+
+		let key = transfer.address.trim().to_lowercase();
+		let (user, schedule) = match by_address.get_mut(&key) {
+			Some(row) => row,
+			None => {
+				mismatches.push(mismatch(MismatchKind::UnknownAddress, "no allocation".to_owned()));
+				continue;
+			},
+		};
+
+		let tx_hash = transfer.tx_hash.trim().to_lowercase();
+		let status = transfer.status.unwrap_or(PayoutStatus::Confirmed);
+		let latest = user.payout_tx.as_ref().map_or(false, |tx| tx.eq_ignore_ascii_case(&tx_hash));
+		let mut total_paid = user.paid_amount;
+		let mut time_paid = user.paid_at;
+
+		let (amount, known) = match imported.get(&tx_hash) {
+			Some(&(user_id, _, _)) if user_id != user.id => {
+				mismatches.push(mismatch(MismatchKind::DuplicatePayment, format!("recorded for user #{}", user_id)));
+				continue;
+			},
+			// the broadcast transaction was mined or dropped
+			Some(&(_, PayoutStatus::Sent, amount)) if status == PayoutStatus::Confirmed || status == PayoutStatus::Failed => {
+				if status == PayoutStatus::Failed {
+					total_paid = user.paid_amount.map(|paid| paid - amount);
+					paid_now.remove(&key);
+				}
+				(amount, true)
+			},
+			Some(&(_, PayoutStatus::Confirmed, _)) if status == PayoutStatus::Failed => {
+				mismatches.push(mismatch(MismatchKind::FailedAfterPayment, format!("{} is recorded as confirmed", tx_hash)));
+				continue;
+			},
+			Some(_) => {
+				log::debug!("line {}: {} already recorded", line, tx_hash);
+				continue;
+			},
+			None => {
+				let paid: u128 = transfer.amount
+				                         .trim()
+				                         .parse()
+				                         .map_err(|err| format_err!("line {}: amount: {}", line, err))?;
+				if status == PayoutStatus::Failed {
+					// a failed retry must not undo the transfer that went through
+					if let PayoutStatus::Sent | PayoutStatus::Confirmed = user.payout_status() {
+						let previous = user.payout_tx.clone().unwrap_or_default();
+						mismatches.push(mismatch(MismatchKind::FailedAfterPayment,
+						                         format!("already {} by {}", user.payout_status, previous)));
+						continue;
+					}
+				} else {
+					let due = crate::vesting::due(schedule.as_ref(), user.amount, user.paid_amount, now);
+					if paid_now.contains(&key) || due == 0 {
+						let previous = user.payout_tx.clone().unwrap_or_default();
+						mismatches.push(mismatch(MismatchKind::DuplicatePayment, format!("already paid by {}", previous)));
+						continue;
+					}
+					paid_now.insert(key.clone());
+
+					// a vesting allocation may be paid in parts, otherwise the whole amount is expected
+					let expected = to_base_units(due, decimals)?;
+					if paid > expected || (schedule.is_none() && paid != expected) {
+						mismatches.push(mismatch(MismatchKind::WrongAmount, format!("expected {}, paid {}", expected, paid)));
+					}
+					total_paid = Some(user.paid_amount.unwrap_or(0) + (paid / unit) as i64);
+					time_paid = Some(transfer.paid_at.unwrap_or(now));
+				}
+				((paid / unit) as i64, false)
+			},
+		};
+
+		// the allocation shows its last transaction
+		if !known || latest {
+			user.payout_status = status.as_str().to_owned();
+			user.payout_tx = Some(transfer.tx_hash.trim().to_owned());
+		}
+		user.paid_amount = total_paid;
+		user.paid_at = time_paid;
+		imported.insert(tx_hash.clone(), (user.id, status, amount));
+		updates.push(Update { user_id: user.id,
+		                      tx_hash,
+		                      status,
+		                      amount,
+		                      known,
+		                      payout_status: user.payout_status.clone(),
+		                      payout_tx: user.payout_tx.clone(),
+		                      paid_amount: total_paid,
+		                      paid_at: time_paid });
+	}
+
+	Ok((updates, mismatches))
+}
+
+
+#[cfg(test)]
+fn test_user(user_id: i32, address: &str, amount: i64) -> User {
+	User { id: user_id,
+	       terms_signed: true,
+	       not_resident: true,
+	       address: address.to_owned(),
+	       amount,
+	       payout_status: PayoutStatus::Pending.as_str().to_owned(),
+	       payout_tx: None,
+	       paid_amount: None,
+	       paid_at: None,
+	       registered_at: None,
+	       registered_country: None,
+	       country_flagged: false,
+	       ineligible: false }
+}
+
+#[cfg(test)]
+fn test_transfer(line: usize, address: &str, amount: &str, tx_hash: &str, status: Option<PayoutStatus>) -> (usize, Transfer) {
+	(line,
+	 Transfer { address: address.to_owned(),
+	            amount: amount.to_owned(),
+	            tx_hash: tx_hash.to_owned(),
+	            status,
+	            paid_at: None })
+}
+
+#[test]
+fn match_transfers_test() {
+	use self::PayoutStatus::*;
+
+	let now = chrono::NaiveDate::from_ymd(2019, 9, 1).and_hms(12, 0, 0);
+	let mut by_address: HashMap<_, _> = vec![test_user(1, "0xAA", 100), test_user(2, "0xbb", 50), test_user(3, "0xdd", 10)]
+		.into_iter()
+		.map(|user| (user.address.to_lowercase(), (user, None)))
+		.collect();
+	let mut imported = Imported::new();
+	let transfers = vec![test_transfer(2, "0xaa", "100", "0x01", Some(Confirmed)),
+	                     // stale failed retry
+	                     test_transfer(3, "0xaa", "100", "0x02", Some(Failed)),
+	                     // paid twice
+	                     test_transfer(4, "0xAA", "100", "0x03", None),
+	                     // already recorded
+	                     test_transfer(5, "0xaa", "100", "0x01", None),
+	                     test_transfer(6, "0xbb", "40", "0x04", None),
+	                     test_transfer(7, "0xcc", "1", "0x05", None),
+	                     // a failure of a pending allocation is recorded without paying anything
+	                     test_transfer(8, "0xdd", "10", "0x06", Some(Failed))];
+
+	let (updates, mismatches) = match_transfers(&mut by_address, &mut imported, &transfers, 0, now).unwrap();
+	assert_eq!(updates.iter()
+	                  .map(|update| (update.user_id, update.tx_hash.as_str(), update.status, update.paid_amount))
+	                  .collect::<Vec<_>>(),
+	           vec![(1, "0x01", Confirmed, Some(100)), (2, "0x04", Confirmed, Some(40)), (3, "0x06", Failed, None)]);
+	assert_eq!(mismatches.iter().map(|mismatch| (mismatch.line, mismatch.kind)).collect::<Vec<_>>(),
+	           vec![(3, MismatchKind::FailedAfterPayment),
+	                (4, MismatchKind::DuplicatePayment),
+	                (6, MismatchKind::WrongAmount),
+	                (7, MismatchKind::UnknownAddress)]);
+	assert_eq!(by_address["0xaa"].0.payout_status, "confirmed");
+	assert_eq!(by_address["0xaa"].0.payout_tx.as_ref().map(String::as_str), Some("0x01"));
+	assert_eq!(by_address["0xaa"].0.paid_at, Some(now));
+
+	// the broadcast ledger first, then what happened to each transaction
+	let transfers = vec![test_transfer(2, "0xee", "10", "0x20", Some(Sent)),
+	                     test_transfer(3, "0xff", "10", "0x30", Some(Sent)),
+	                     test_transfer(2, "0xee", "10", "0x20", Some(Confirmed)),
+	                     test_transfer(3, "0xff", "10", "0x30", Some(Failed)),
+	                     test_transfer(4, "0xff", "10", "0x31", Some(Confirmed)),
+	                     test_transfer(5, "0xee", "10", "0x20", Some(Failed))];
+	by_address.insert("0xee".to_owned(), (test_user(4, "0xee", 10), None));
+	by_address.insert("0xff".to_owned(), (test_user(5, "0xff", 10), None));
+	let (updates, mismatches) = match_transfers(&mut by_address, &mut imported, &transfers, 0, now).unwrap();
+	assert_eq!(updates.iter()
+	                  .map(|update| (update.tx_hash.as_str(), update.known, update.payout_status.as_str(), update.paid_amount))
+	                  .collect::<Vec<_>>(),
+	           vec![("0x20", false, "sent", Some(10)),
+	                ("0x30", false, "sent", Some(10)),
+	                ("0x20", true, "confirmed", Some(10)),
+	                ("0x30", true, "failed", Some(0)),
+	                ("0x31", false, "confirmed", Some(10))]);
+	assert_eq!(mismatches.iter().map(|mismatch| (mismatch.line, mismatch.kind)).collect::<Vec<_>>(),
+	           vec![(5, MismatchKind::FailedAfterPayment)]);
+}
+
+#[test]
+fn reimport_test() {
+	let start = chrono::NaiveDate::from_ymd(2019, 1, 1).and_hms(0, 0, 0);
+	let day = chrono::Duration::days(1);
+	let schedule = VestingSchedule { id: 1,
+	                                 user_id: 1,
+	                                 start_at: start,
+	                                 cliff_secs: 0,
+	                                 duration_secs: (day * 100).num_seconds(),
+	                                 period_secs: 0 };
+	let mut by_address = HashMap::new();
+	by_address.insert("0xaa".to_owned(), (test_user(1, "0xaa", 100), Some(schedule)));
+	let mut imported = Imported::new();
+
+	let first_round = [test_transfer(2, "0xaa", "50", "0x10", None)];
+	let second_round = [test_transfer(2, "0xaa", "25", "0x11", None)];
+	match_transfers(&mut by_address, &mut imported, &first_round, 0, start + day * 50).unwrap();
+	match_transfers(&mut by_address, &mut imported, &second_round, 0, start + day * 75).unwrap();
+	assert_eq!(by_address["0xaa"].0.paid_amount, Some(75));
+
+	// 25 are due, but not by the first round again
+	let (updates, mismatches) =
+		match_transfers(&mut by_address, &mut imported, &first_round, 0, start + day * 100).unwrap();
+	assert!(updates.is_empty() && mismatches.is_empty());
+	assert_eq!(by_address["0xaa"].0.paid_amount, Some(75));
+	assert_eq!(by_address["0xaa"].0.payout_tx.as_ref().map(String::as_str), Some("0x11"));
+}
@@ -0,0 +1,222 @@
+//! ERC-20 `transfer` transactions: RLP encoding and signing,
+//! EIP-155 legacy and EIP-1559 (type 2) envelopes.
+
+use failure::{bail, Error};
+use secp256k1::{Message, PublicKey, Secp256k1, SecretKey};
+
+
+pub type Address = [u8; 20];
+pub type Hash = [u8; 32];
+
+/// `transfer(address,uint256)`
+const TRANSFER_SELECTOR: [u8; 4] = [0xa9, 0x05, 0x9c, 0xbb];
+const EIP1559_TX_TYPE: u8 = 0x02;
+
+
+pub fn keccak256(data: &[u8]) -> Hash { tiny_keccak::keccak256(data) }
+
+pub fn parse_address(s: &str) -> Result<Address, Error> {
+	let hex_str = s.trim();
+	let hex_str = hex_str.trim_start_matches("0x").trim_start_matches("0X");
+	if hex_str.len() != 40 {
+		bail!("invalid address length: {}", s);
+	}
+	let bytes = hex::decode(hex_str)?;
+	let mut address = [0; 20];
+	address.copy_from_slice(&bytes);
+	Ok(address)
+}
+
+pub fn format_address(address: &Address) -> String { format!("0x{}", hex::encode(address)) }
+
+/// Address derived from the secret key: last 20 bytes of keccak of the public key.
+pub fn address_of(key: &SecretKey) -> Address {
+	let secp = Secp256k1::signing_only();
+	let public = PublicKey::from_secret_key(&secp, key).serialize_uncompressed();
+	let hash = keccak256(&public[1..]);
+	let mut address = [0; 20];
+	address.copy_from_slice(&hash[12..]);
+	address
+}
+
+
+/// ABI-encoded call data for `transfer(to, amount)`.
+pub fn erc20_transfer(to: &Address, amount: u128) -> Vec<u8> {
+	let mut data = Vec::with_capacity(4 + 32 + 32);
+	data.extend_from_slice(&TRANSFER_SELECTOR);
+	data.extend_from_slice(&[0; 12]);
+	data.extend_from_slice(to);
+	data.extend_from_slice(&[0; 16]);
+	data.extend_from_slice(&amount.to_be_bytes());
+	data
+}
+
+
+#[derive(Debug, Clone, Copy)]
+pub enum Fee {
+	/// EIP-155 transaction with a fixed gas price.
+	Legacy { gas_price: u128 },
+	/// EIP-1559 transaction.
+	Eip1559 { max_priority_fee: u128, max_fee: u128 },
+}
+
+#[derive(Debug, Clone)]
+pub struct Transaction {
+	pub chain_id: u64,
+	pub nonce: u64,
+	pub fee: Fee,
+	pub gas_limit: u64,
+	pub to: Address,
+	pub value: u128,
+	pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SignedTransaction {
+	pub raw: Vec<u8>,
+	pub hash: Hash,
+}
+
+impl Transaction {
+	pub fn sign(&self, key: &SecretKey) -> Result<SignedTransaction, Error> {
+		let raw = match self.fee {
+			Fee::Legacy { gas_price } => {
+				let fields = vec![rlp::uint(self.nonce.into()),
+				                  rlp::uint(gas_price),
+				                  rlp::uint(self.gas_limit.into()),
+				                  rlp::bytes(&self.to),
+				                  rlp::uint(self.value),
+				                  rlp::bytes(&self.data)];
+
+				let mut unsigned = fields.clone();
+				unsigned.extend(vec![rlp::uint(self.chain_id.into()), rlp::uint(0), rlp::uint(0)]);
+				let (recid, r, s) = sign_hash(&keccak256(&rlp::list(&unsigned)), key)?;
+
+				let v = u128::from(self.chain_id) * 2 + 35 + u128::from(recid);
+				let mut signed = fields;
+				signed.extend(vec![rlp::uint(v), rlp::bytes(rlp::trim(&r)), rlp::bytes(rlp::trim(&s))]);
+				rlp::list(&signed)
+			},
+			Fee::Eip1559 { max_priority_fee, max_fee } => {
+				let fields = vec![rlp::uint(self.chain_id.into()),
+				                  rlp::uint(self.nonce.into()),
+				                  rlp::uint(max_priority_fee),
+				                  rlp::uint(max_fee),
+				                  rlp::uint(self.gas_limit.into()),
+				                  rlp::bytes(&self.to),
+				                  rlp::uint(self.value),
+				                  rlp::bytes(&self.data),
+				                  // empty access list
+				                  rlp::list(&[])];
+
+				let mut unsigned = vec![EIP1559_TX_TYPE];
+				unsigned.extend(rlp::list(&fields));
+				let (recid, r, s) = sign_hash(&keccak256(&unsigned), key)?;
+
+				let mut signed = fields;
+				signed.extend(vec![rlp::uint(recid.into()), rlp::bytes(rlp::trim(&r)), rlp::bytes(rlp::trim(&s))]);
+				let mut raw = vec![EIP1559_TX_TYPE];
+				raw.extend(rlp::list(&signed));
+				raw
+			},
+		};
+
+		let hash = keccak256(&raw);
+		Ok(SignedTransaction { raw, hash })
+	}
+}
+
+
+fn sign_hash(hash: &Hash, key: &SecretKey) -> Result<(u8, [u8; 32], [u8; 32]), Error> {
+	let secp = Secp256k1::signing_only();
+	let message = Message::from_slice(hash)?;
+	let (recid, sig) = secp.sign_recoverable(&message, key).serialize_compact();
+
+	let mut r = [0; 32];
+	let mut s = [0; 32];
+	r.copy_from_slice(&sig[..32]);
+	s.copy_from_slice(&sig[32..]);
+	Ok((recid.to_i32() as u8, r, s))
+}
+
+
+/// Minimal RLP encoder, enough for transactions.
+mod rlp {
+	pub fn trim(data: &[u8]) -> &[u8] {
+		let start = data.iter().position(|b| *b != 0).unwrap_or(data.len());
+		&data[start..]
+	}
+
+	pub fn uint(value: u128) -> Vec<u8> { bytes(trim(&value.to_be_bytes())) }
+
+	pub fn bytes(data: &[u8]) -> Vec<u8> {
+		if data.len() == 1 && data[0] < 0x80 {
+			return data.to_vec();
+		}
+		let mut out = header(0x80, data.len());
+		out.extend_from_slice(data);
+		out
+	}
+
+	pub fn list(items: &[Vec<u8>]) -> Vec<u8> {
+		let payload: Vec<u8> = items.concat();
+		let mut out = header(0xc0, payload.len());
+		out.extend(payload);
+		out
+	}
+
+	fn header(offset: u8, len: usize) -> Vec<u8> {
+		if len < 56 {
+			vec![offset + len as u8]
+		} else {
+			let len_bytes = (len as u64).to_be_bytes();
+			let len_bytes = trim(&len_bytes);
+			let mut out = vec![offset + 55 + len_bytes.len() as u8];
+			out.extend_from_slice(len_bytes);
+			out
+		}
+	}
+}
+
+
+#[test]
+fn eip155_test() {
+	// Example from EIP-155.
+	let key = SecretKey::from_slice(&[0x46; 32]).unwrap();
+	let tx = Transaction { chain_id: 1,
+	                       nonce: 9,
+	                       fee: Fee::Legacy { gas_price: 20_000_000_000 },
+	                       gas_limit: 21000,
+	                       to: [0x35; 20],
+	                       value: 1_000_000_000_000_000_000,
+	                       data: Vec::new() };
+
+	let signed = tx.sign(&key).unwrap();
+	assert_eq!(hex::encode(&signed.raw),
+	           "f86c098504a817c800825208943535353535353535353535353535353535353535880de0b6b3a7640000\
+	            8025a028ef61340bd939bc2195fe537567866003e1a15d3c71ff63e1590620aa636276a067cbe9d8997f76\
+	            1aecb703304b3800ccf555c9f3dc64214b297fb1966a3b6d83");
+}
+
+#[test]
+fn eip1559_test() {
+	// DAI transfer, checked against an independent implementation.
+	let key = SecretKey::from_slice(&[0x46; 32]).unwrap();
+	let tx = Transaction { chain_id: 1,
+	                       nonce: 9,
+	                       fee: Fee::Eip1559 { max_priority_fee: 2_000_000_000,
+	                                           max_fee: 100_000_000_000 },
+	                       gas_limit: 100_000,
+	                       to: parse_address("0x6B175474E89094C44Da98b954EedeAC495271d0F").unwrap(),
+	                       value: 0,
+	                       data: erc20_transfer(&[0x35; 20], 1500 * 10u128.pow(18)) };
+
+	let signed = tx.sign(&key).unwrap();
+	assert_eq!(hex::encode(&signed.raw),
+	           "02f8b10109847735940085174876e800830186a0946b175474e89094c44da98b954eedeac495271d0f80b844\
+	            a9059cbb000000000000000000000000353535353535353535353535353535353535353500000000000000\
+	            000000000000000000000000000000005150ae84a8cdf00000c080a007cecda75774d0c0af915671dd5b59\
+	            3774273a4eb2efcf2ec8114f7af1896679a06db5418ffabcfc779e8ad6af498181cf3f7bb37ef013ff0413\
+	            061d7889752958");
+	assert_eq!(hex::encode(signed.hash), "ffc73ed27cd95f38054dc91c82afee37719a15f4dd49fcc9e7cf67f21119cfa4");
+}
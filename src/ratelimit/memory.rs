@@ -0,0 +1,54 @@
+//! Buckets in process memory, limits are per instance.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::{Decision, Limit, Store};
+
+
+/// Full buckets are dropped every this many takes
+const SWEEP_EVERY: u64 = 4096;
+
+
+struct Bucket {
+	tokens: f64,
+	updated: Instant,
+	full_at: Instant,
+}
+
+#[derive(Default)]
+struct Buckets {
+	buckets: HashMap<String, Bucket>,
+	takes: u64,
+}
+
+#[derive(Default)]
+pub struct MemoryStore {
+	inner: Mutex<Buckets>,
+}
+
+impl Store for MemoryStore {
+	fn take(&self, bucket: &str, limit: &Limit) -> Result<Decision, failure::Error> {
+		let now = Instant::now();
+		let mut inner = self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+		inner.takes += 1;
+		if inner.takes % SWEEP_EVERY == 0 {
+			inner.buckets.retain(|_, bucket| bucket.full_at > now);
+		}
+
+		let (tokens, elapsed) = match inner.buckets.get(bucket) {
+			Some(found) => (found.tokens, now.duration_since(found.updated)),
+			None => (limit.capacity, Duration::from_secs(0)),
+		};
+		let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+		let (tokens, decision) = limit.take(tokens, elapsed);
+		let full_at = now + Duration::from_millis((limit.seconds_to_full(tokens) * 1000.0) as u64);
+		inner.buckets.insert(bucket.to_owned(),
+		                     Bucket { tokens,
+		                              updated: now,
+		                              full_at });
+		Ok(decision)
+	}
+}
@@ -0,0 +1,287 @@
+//! Token bucket rate limits per client IP and per target address,
+//! separately for lookups and registrations.
+//!
+//! Limits in `[rate_limit]` are `N/SECS`: bursts of `N`, refilled at `N` per `SECS`, or `off`.
+//! - `lookup_ip` (default `30/60`), `lookup_address` (`10/60`)
+//! - `register_ip` (`10/60`), `register_address` (`5/3600`)
+//! - `store`: `memory` (default, per instance) or `postgres` (shared by instances)
+//!
+//! IPv6 clients are limited per /64, a single host usually gets the whole prefix.
+
+use std::net::IpAddr;
+use std::time::Duration;
+use actix_web::http::{header, StatusCode};
+use actix_web::{HttpRequest, HttpResponse};
+
+use crate::api::ApiError;
+use crate::state::State;
+
+mod memory;
+#[cfg(feature = "postgres")]
+mod postgres;
+
+pub use self::memory::MemoryStore;
+#[cfg(feature = "postgres")]
+pub use self::postgres::PostgresStore;
+
+
+/// How long `Limiter::take_once` remembers a bucket
+pub const ONCE_SECS: i64 = 24 * 3600;
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limit {
+	/// Burst size
+	pub capacity: f64,
+	/// Tokens per second
+	pub rate: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Decision {
+	Allowed,
+	Limited { retry_after: Duration },
+}
+
+impl Limit {
+	/// Refills a bucket with `tokens` last updated `elapsed` seconds ago and takes a token.
+	/// Returns the tokens left.
+	pub fn take(&self, tokens: f64, elapsed: f64) -> (f64, Decision) {
+		let tokens = (tokens + elapsed.max(0.0) * self.rate).min(self.capacity);
+		if tokens >= 1.0 {
+			(tokens - 1.0, Decision::Allowed)
+		} else {
+			let wait = ((1.0 - tokens) / self.rate).ceil().max(1.0);
+			(tokens, Decision::Limited { retry_after: Duration::from_secs(wait as u64) })
+		}
+	}
+
+	/// Seconds until a bucket with `tokens` is full again.
+	pub fn seconds_to_full(&self, tokens: f64) -> f64 { ((self.capacity - tokens) / self.rate).max(0.0) }
+}
+
+/// `N/SECS` or `off`.
+fn parse_limit(name: &str, value: &str) -> Result<Option<Limit>, String> {
+	if value == "off" {
+		return Ok(None);
+	}
+	let mut parts = value.splitn(2, '/');
+	let count: u32 = parts.next()
+	                      .and_then(|count| count.trim().parse().ok())
+	                      .filter(|count| *count > 0)
+	                      .ok_or_else(|| format!("{}: expected N/SECS or off, got {:?}", name, value))?;
+	let secs: u32 = parts.next()
+	                     .and_then(|secs| secs.trim().parse().ok())
+	                     .filter(|secs| *secs > 0)
+	                     .ok_or_else(|| format!("{}: expected N/SECS or off, got {:?}", name, value))?;
+	Ok(Some(Limit { capacity: f64::from(count),
+	                rate: f64::from(count) / f64::from(secs) }))
+}
+
+
+/// Where the buckets live.
+pub trait Store: Send + Sync {
+	/// Takes a token from the bucket named `bucket`.
+	fn take(&self, bucket: &str, limit: &Limit) -> Result<Decision, failure::Error>;
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+	Lookup,
+	Register,
+}
+
+impl Action {
+	fn as_str(&self) -> &'static str {
+		match self {
+			Action::Lookup => "lookup",
+			Action::Register => "register",
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StoreKind {
+	Memory,
+	#[cfg(feature = "postgres")]
+	Postgres,
+}
+
+impl std::str::FromStr for StoreKind {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"memory" => Ok(StoreKind::Memory),
+			#[cfg(feature = "postgres")]
+			"postgres" => Ok(StoreKind::Postgres),
+			_ => Err(format!("rate_limit.store: unsupported store {:?}", s)),
+		}
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+	pub store: StoreKind,
+	pub lookup_ip: Option<Limit>,
+	pub lookup_address: Option<Limit>,
+	pub register_ip: Option<Limit>,
+	pub register_address: Option<Limit>,
+}
+
+impl Settings {
+	pub fn from_config(config: &crate::config::RateLimit) -> Result<Self, String> {
+		Ok(Self { store: config.store.parse()?,
+		          lookup_ip: parse_limit("rate_limit.lookup_ip", &config.lookup_ip)?,
+		          lookup_address: parse_limit("rate_limit.lookup_address", &config.lookup_address)?,
+		          register_ip: parse_limit("rate_limit.register_ip", &config.register_ip)?,
+		          register_address: parse_limit("rate_limit.register_address", &config.register_address)? })
+	}
+}
+
+
+pub struct Limiter {
+	settings: Settings,
+	store: Box<dyn Store>,
+}
+
+impl Limiter {
+	pub fn new(settings: Settings, store: Box<dyn Store>) -> Self { Self { settings, store } }
+
+	/// Settings and store from the `[rate_limit]` section.
+	pub fn from_config(config: &crate::config::RateLimit, pool: crate::db::TheConnectionPool) -> Result<Self, String> {
+		let settings = Settings::from_config(config)?;
+		let store: Box<dyn Store> = match settings.store {
+			StoreKind::Memory => Box::new(MemoryStore::default()),
+			#[cfg(feature = "postgres")]
+			StoreKind::Postgres => Box::new(PostgresStore::new(pool)),
+		};
+		#[cfg(not(feature = "postgres"))]
+		let _ = pool;
+		Ok(Self::new(settings, store))
+	}
+
+	/// Checked before the captcha, anyone can send requests from their IP.
+	pub fn check_ip(&self, action: Action, ip: Option<IpAddr>) -> Decision {
+		let limit = match action {
+			Action::Lookup => self.settings.lookup_ip,
+			Action::Register => self.settings.register_ip,
+		};
+		match ip {
+			Some(ip) => self.take(limit, &format!("{}:ip:{}", action.as_str(), ip_key(ip))),
+			None => Decision::Allowed,
+		}
+	}
+
+	/// Checked after the captcha passed, so nobody can use up the bucket of someone else's address.
+	pub fn check_address(&self, action: Action, address: &str) -> Decision {
+		let limit = match action {
+			Action::Lookup => self.settings.lookup_address,
+			Action::Register => self.settings.register_address,
+		};
+		self.take(limit, &format!("{}:address:{}", action.as_str(), address.trim().to_lowercase()))
+	}
+
+	/// Whether `bucket` is taken for the first time in `ONCE_SECS`, for single use tokens.
+	pub fn take_once(&self, bucket: &str) -> bool {
+		let once = Limit { capacity: 1.0,
+		                   rate: 1.0 / ONCE_SECS as f64 };
+		self.take(Some(once), bucket) == Decision::Allowed
+	}
+
+	/// Store failures let the request through.
+	fn take(&self, limit: Option<Limit>, bucket: &str) -> Decision {
+		let limit = match limit {
+			Some(limit) => limit,
+			None => return Decision::Allowed,
+		};
+		match self.store.take(bucket, &limit) {
+			Ok(Decision::Allowed) => Decision::Allowed,
+			Ok(limited) => {
+				log::info!("rate limited: {}", bucket);
+				limited
+			},
+			Err(err) => {
+				log::error!("rate limit store: {:?}", err);
+				Decision::Allowed
+			},
+		}
+	}
+}
+
+/// IPv4 as is, IPv6 by /64. IPv4-mapped IPv6 counts as IPv4.
+pub fn ip_key(ip: IpAddr) -> String {
+	match crate::client_ip::canonical(ip) {
+		IpAddr::V4(ip) => ip.to_string(),
+		IpAddr::V6(ip) => {
+			let segments = ip.segments();
+			let prefix = std::net::Ipv6Addr::new(segments[0], segments[1], segments[2], segments[3], 0, 0, 0, 0);
+			format!("{}/64", prefix)
+		},
+	}
+}
+
+
+/// Checks the client IP of the request, `Err` is the 429 response.
+pub fn check_ip(req: &HttpRequest, action: Action) -> Result<(), HttpResponse> {
+	limited_resp(State::get().ratelimit().check_ip(action, crate::client_ip::resolve(req)))
+}
+
+/// Checks the address of a request whose captcha passed, `Err` is the 429 response.
+pub fn check_address(action: Action, address: &str) -> Result<(), HttpResponse> {
+	limited_resp(State::get().ratelimit().check_address(action, address))
+}
+
+fn limited_resp(decision: Decision) -> Result<(), HttpResponse> {
+	match decision {
+		Decision::Allowed => Ok(()),
+		Decision::Limited { retry_after } => {
+			Err(HttpResponse::build(StatusCode::TOO_MANY_REQUESTS).header(header::RETRY_AFTER, retry_after.as_secs().to_string())
+			                                                      .json(ApiError::RateLimited.to_resp()))
+		},
+	}
+}
+
+
+#[test]
+fn bucket_test() {
+	let limit = Limit { capacity: 2.0, rate: 0.5 };
+	let (tokens, decision) = limit.take(2.0, 0.0);
+	assert_eq!((tokens, decision), (1.0, Decision::Allowed));
+	let (tokens, decision) = limit.take(tokens, 0.0);
+	assert_eq!((tokens, decision), (0.0, Decision::Allowed));
+	let (tokens, decision) = limit.take(tokens, 1.0);
+	assert_eq!(decision, Decision::Limited { retry_after: Duration::from_secs(1) });
+	let (_, decision) = limit.take(tokens, 1.0);
+	assert_eq!(decision, Decision::Allowed);
+	// refill is capped
+	assert_eq!(limit.take(0.0, 3600.0).0, 1.0);
+}
+
+#[test]
+fn limiter_test() {
+	let once = Some(Limit { capacity: 1.0, rate: 0.001 });
+	let limiter = Limiter::new(Settings { store: StoreKind::Memory,
+	                                      lookup_ip: None,
+	                                      lookup_address: None,
+	                                      register_ip: once,
+	                                      register_address: once },
+	                           Box::new(MemoryStore::default()));
+	let ip = Some("203.0.113.7".parse().unwrap());
+	assert_eq!(limiter.check_ip(Action::Register, ip), Decision::Allowed);
+	assert_ne!(limiter.check_ip(Action::Register, ip), Decision::Allowed);
+	// the address bucket is separate
+	assert_eq!(limiter.check_address(Action::Register, "0xAA"), Decision::Allowed);
+	assert_ne!(limiter.check_address(Action::Register, "0xaa"), Decision::Allowed);
+	assert_eq!(limiter.check_ip(Action::Lookup, ip), Decision::Allowed);
+	assert!(limiter.take_once("form_token:1"));
+	assert!(!limiter.take_once("form_token:1"));
+}
+
+#[test]
+fn ip_key_test() {
+	assert_eq!(ip_key("203.0.113.7".parse().unwrap()), "203.0.113.7");
+	assert_eq!(ip_key("2001:db8:1:2:3:4:5:6".parse().unwrap()), "2001:db8:1:2::/64");
+	assert_eq!(ip_key("::ffff:203.0.113.7".parse().unwrap()), "203.0.113.7");
+}
@@ -0,0 +1,61 @@
+//! Buckets in the `rate_limits` table, shared by all instances using the database.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+
+use crate::db::TheConnectionPool;
+use super::{Decision, Limit, Store};
+
+
+/// Idle buckets are deleted every this many takes
+const SWEEP_EVERY: u64 = 4096;
+/// Longer than any sensible refill period
+const SWEEP_AFTER_HOURS: i64 = 24;
+
+
+pub struct PostgresStore {
+	pool: TheConnectionPool,
+	takes: AtomicU64,
+}
+
+impl PostgresStore {
+	pub fn new(pool: TheConnectionPool) -> Self {
+		Self { pool,
+		       takes: AtomicU64::new(0) }
+	}
+}
+
+impl Store for PostgresStore {
+	fn take(&self, name: &str, limit: &Limit) -> Result<Decision, failure::Error> {
+		use crate::db::schema::rate_limits::dsl::*;
+
+		let conn = self.pool.get()?;
+		let now = Utc::now().naive_utc();
+
+		if self.takes.fetch_add(1, Ordering::Relaxed) % SWEEP_EVERY == 0 {
+			diesel::delete(rate_limits.filter(updated_at.lt(now - Duration::hours(SWEEP_AFTER_HOURS)))).execute(&conn)?;
+		}
+
+		let decision = conn.transaction::<_, diesel::result::Error, _>(|| {
+			                   diesel::insert_into(rate_limits).values((bucket.eq(name),
+			                                                            tokens.eq(limit.capacity),
+			                                                            updated_at.eq(now)))
+			                                                   .on_conflict(bucket)
+			                                                   .do_nothing()
+			                                                   .execute(&conn)?;
+			                   // the row lock serializes instances taking from the same bucket
+			                   let (current, updated) = rate_limits.filter(bucket.eq(name))
+			                                                       .select((tokens, updated_at))
+			                                                       .for_update()
+			                                                       .first::<(f64, NaiveDateTime)>(&conn)?;
+			                   let elapsed = (now - updated).num_milliseconds() as f64 / 1000.0;
+			                   let (left, decision) = limit.take(current, elapsed);
+			                   diesel::update(rate_limits.filter(bucket.eq(name))).set((tokens.eq(left),
+			                                                                            updated_at.eq(now)))
+			                                                                      .execute(&conn)?;
+			                   Ok(decision)
+		                   })?;
+		Ok(decision)
+	}
+}
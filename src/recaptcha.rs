@@ -1,15 +1,29 @@
 use std::fmt;
 use std::net::IpAddr;
 use std::collections::HashSet;
-use serde::{Deserializer, Deserialize};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use once_cell::sync::OnceCell;
+use serde::{Deserializer, Deserialize, Serialize};
 use url::Url;
 
 
+/// Consecutive failures to reach the verifier that open the circuit
+const FAILURE_THRESHOLD: u32 = 5;
+/// How long requests fail fast before the verifier is tried again
+const OPEN_FOR: Duration = Duration::from_secs(30);
+
+static BREAKER: OnceCell<Breaker> = OnceCell::INIT;
+
+
 #[derive(Debug, Deserialize)]
 pub struct RecaptchaResponse {
 	pub success: bool,
 	#[serde(rename = "error-codes")]
 	pub error_codes: Option<HashSet<Code>>,
+	/// reCAPTCHA v3 only, 1.0 is very likely a human
+	#[serde(default)]
+	pub score: Option<f64>,
 }
 
 #[derive(PartialEq, Eq, Hash, Debug)]
@@ -19,6 +33,8 @@ pub enum Code {
 	MissingResponse,
 	InvalidResponse,
 	BadRequest,
+	/// Ours: the score is below what the client's network requires
+	LowScore,
 	Unknown(String),
 }
 
@@ -31,6 +47,7 @@ impl fmt::Display for Code {
 			Code::MissingResponse => "missing-input-response",
 			Code::InvalidResponse => "invalid-input-response",
 			Code::BadRequest => "bad-request",
+			Code::LowScore => "low-score",
 			Code::Unknown(code) => code,
 		};
 		write!(f, "{}", s)
@@ -70,10 +87,117 @@ pub fn url(key: &str, response: &str, user_ip: Option<&IpAddr>) -> Url {
 }
 
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Circuit {
+	Closed,
+	/// Failing fast
+	Open,
+	/// One request tries the verifier, the others still fail fast
+	HalfOpen,
+}
+
+/// Circuit breaker for the verifier. Only transport and parse errors count as failures,
+/// a rejected token means the verifier works.
+#[derive(Debug, Default)]
+pub struct Breaker {
+	inner: Mutex<BreakerState>,
+}
+
+#[derive(Debug, Default)]
+struct BreakerState {
+	failures: u32,
+	opened_at: Option<Instant>,
+	/// When the half-open probe was let through
+	probe_at: Option<Instant>,
+}
+
+pub fn breaker() -> &'static Breaker { BREAKER.get_or_init(Breaker::default) }
+
+impl Breaker {
+	fn lock(&self) -> std::sync::MutexGuard<BreakerState> { self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) }
+
+	pub fn circuit(&self) -> Circuit {
+		match self.lock().opened_at {
+			None => Circuit::Closed,
+			Some(at) if at.elapsed() < OPEN_FOR => Circuit::Open,
+			Some(_) => Circuit::HalfOpen,
+		}
+	}
+
+	/// Whether a request may call the verifier. While half-open only one probe is let through,
+	/// another one once it is `OPEN_FOR` old without an outcome.
+	pub fn allow(&self) -> bool {
+		let mut state = self.lock();
+		match state.opened_at {
+			None => true,
+			Some(at) if at.elapsed() < OPEN_FOR => false,
+			Some(_) => {
+				if state.probe_at.map_or(false, |at| at.elapsed() < OPEN_FOR) {
+					return false;
+				}
+				state.probe_at = Some(Instant::now());
+				true
+			},
+		}
+	}
+
+	pub fn success(&self) {
+		let mut state = self.lock();
+		state.failures = 0;
+		state.opened_at = None;
+		state.probe_at = None;
+	}
+
+	pub fn failure(&self) {
+		let mut state = self.lock();
+		state.failures += 1;
+		state.probe_at = None;
+		if state.failures >= FAILURE_THRESHOLD {
+			if state.opened_at.is_none() {
+				log::error!("recaptcha: circuit open after {} failures", state.failures);
+			}
+			state.opened_at = Some(Instant::now());
+		}
+	}
+}
+
+
+#[test]
+fn breaker_test() {
+	let breaker = Breaker::default();
+	for _ in 1..FAILURE_THRESHOLD {
+		breaker.failure();
+	}
+	assert_eq!(breaker.circuit(), Circuit::Closed);
+	breaker.failure();
+	assert_eq!(breaker.circuit(), Circuit::Open);
+	assert!(!breaker.allow());
+	breaker.success();
+	assert_eq!(breaker.circuit(), Circuit::Closed);
+
+	// half-open after `OPEN_FOR`: a single probe,
+	// unless the monotonic clock is younger than that, as on a freshly booted machine
+	let opened_at = match Instant::now().checked_sub(OPEN_FOR) {
+		Some(at) => at,
+		None => return,
+	};
+	for _ in 0..FAILURE_THRESHOLD {
+		breaker.failure();
+	}
+	breaker.lock().opened_at = Some(opened_at);
+	assert_eq!(breaker.circuit(), Circuit::HalfOpen);
+	assert!(breaker.allow());
+	assert!(!breaker.allow());
+	breaker.failure();
+	assert_eq!(breaker.circuit(), Circuit::Open);
+	assert!(!breaker.allow());
+}
+
 #[test]
 fn decoding_test() {
 	extern crate serde_json as json;
-	use error::Code::*;
+	use self::Code::*;
 
 	let resp = json::from_str::<RecaptchaResponse>(
 	                                               r#"{
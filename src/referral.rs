@@ -0,0 +1,216 @@
+//! Referrals: who referred whom and the bonus credited to the referrer's allocation.
+
+use std::collections::HashSet;
+use chrono::Utc;
+use diesel::prelude::*;
+use serde::Serialize;
+
+use crate::api::ApiError;
+use crate::db::TheConnection;
+use crate::db::models::{NewReferral, User};
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BonusRule {
+	None,
+	/// AKT tokens per referee
+	Flat(i64),
+	/// Percent of the referee's amount
+	Percent(u32),
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+	pub rule: BonusRule,
+	/// Max total bonus per referrer
+	pub cap: Option<i64>,
+}
+
+impl Settings {
+	/// `[referral]`: `bonus` is `flat:N` or `percent:N`, no bonus when unset,
+	/// `bonus_cap` the max total bonus per referrer.
+	pub fn from_config(config: &crate::config::Referral) -> Result<Self, String> {
+		let rule = match &config.bonus {
+			Some(rule) => rule.parse().map_err(|err| format!("referral.bonus: {}", err))?,
+			None => BonusRule::None,
+		};
+		Ok(Self { rule,
+		          cap: config.bonus_cap })
+	}
+
+	/// Bonus for a referee with `amount` when the referrer already earned `earned`.
+	pub fn bonus(&self, amount: i64, earned: i64) -> i64 {
+		let bonus = match self.rule {
+			BonusRule::None => 0,
+			BonusRule::Flat(bonus) => bonus,
+			BonusRule::Percent(percent) => (i128::from(amount) * i128::from(percent) / 100) as i64,
+		};
+		let bonus = match self.cap {
+			Some(cap) => bonus.min(cap - earned),
+			None => bonus,
+		};
+		bonus.max(0)
+	}
+}
+
+impl std::str::FromStr for BonusRule {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let mut parts = s.splitn(2, ':');
+		let kind = parts.next().unwrap_or_default().trim();
+		let value = parts.next().unwrap_or_default().trim();
+		let err = |_| format!("invalid referral bonus rule: {}", s);
+		match kind {
+			"none" | "" => Ok(BonusRule::None),
+			"flat" => value.parse().map(BonusRule::Flat).map_err(err),
+			"percent" => value.parse().map(BonusRule::Percent).map_err(err),
+			_ => Err(format!("invalid referral bonus rule: {}", s)),
+		}
+	}
+}
+
+
+#[derive(Debug)]
+pub enum Error {
+	SelfReferral,
+	Cycle,
+	UnknownReferrer,
+	Db(diesel::result::Error),
+}
+
+impl From<diesel::result::Error> for Error {
+	fn from(error: diesel::result::Error) -> Self { Error::Db(error) }
+}
+
+impl From<Error> for ApiError {
+	fn from(error: Error) -> Self {
+		match error {
+			Error::SelfReferral => ApiError::SelfReferral,
+			Error::Cycle => ApiError::ReferralCycle,
+			Error::UnknownReferrer => ApiError::UnknownReferrer,
+			Error::Db(err) => {
+				log::error!("referral: {:?}", err);
+				ApiError::Internal
+			},
+		}
+	}
+}
+
+
+/// Records that `referee` was referred by the user with `referrer_address`
+/// and credits the bonus to the referrer's amount.
+/// Only the first referrer of a referee is stored.
+/// Should be called inside a transaction.
+pub fn refer(conn: &TheConnection, settings: &Settings, referee: &User, referrer_address: &str) -> Result<(), Error> {
+	use crate::db::lower;
+	use crate::db::schema::referrals::dsl as r;
+	use crate::db::schema::users::dsl as u;
+
+	if referrer_address.trim().eq_ignore_ascii_case(referee.address.trim()) {
+		return Err(Error::SelfReferral);
+	}
+
+	let existing = r::referrals.filter(r::referee_id.eq(referee.id))
+	                           .select(r::referrer_id)
+	                           .first::<i32>(conn)
+	                           .optional()?;
+	if existing.is_some() {
+		log::debug!("referral: #{} already referred by #{:?}", referee.id, existing);
+		return Ok(());
+	}
+
+	// addresses are stored as submitted, the checksum case must not matter
+	let query = u::users.filter(lower(u::address).eq(referrer_address.trim().to_lowercase()));
+	// the row lock serializes referees of the same referrer, each sees the bonuses credited before
+	// and the cap holds; sqlite has a single connection
+	#[cfg(feature = "postgres")]
+	let query = query.for_update();
+	let referrer = query.first::<User>(conn)
+	                    .optional()?
+	                    .ok_or(Error::UnknownReferrer)?;
+	if referrer.id == referee.id {
+		return Err(Error::SelfReferral);
+	}
+
+	// walk up the chain of referrers of the referrer
+	let mut visited = HashSet::new();
+	let mut current = referrer.id;
+	while visited.insert(current) {
+		if current == referee.id {
+			return Err(Error::Cycle);
+		}
+		match r::referrals.filter(r::referee_id.eq(current))
+		                  .select(r::referrer_id)
+		                  .first::<i32>(conn)
+		                  .optional()?
+		{
+			Some(next) => current = next,
+			None => break,
+		}
+	}
+
+	let earned: i64 = r::referrals.filter(r::referrer_id.eq(referrer.id))
+	                              .select(r::bonus)
+	                              .load::<i64>(conn)?
+	                              .iter()
+	                              .sum();
+	let bonus = settings.bonus(referee.amount, earned);
+
+	diesel::insert_into(r::referrals).values(&NewReferral { referee_id: referee.id,
+	                                                        referrer_id: referrer.id,
+	                                                        bonus,
+	                                                        created_at: Utc::now().naive_utc() })
+	                                 .execute(conn)?;
+	if bonus > 0 {
+		diesel::update(u::users.filter(u::id.eq(referrer.id))).set(u::amount.eq(u::amount + bonus))
+		                                                      .execute(conn)?;
+	}
+	log::debug!("referral: #{} referred by #{}, bonus {}", referee.id, referrer.id, bonus);
+	Ok(())
+}
+
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReferrerStats {
+	/// Ethereum address of the referrer
+	pub address: String,
+	pub referees: i64,
+	/// AKT tokens earned
+	pub bonus: i64,
+}
+
+pub fn stats(conn: &TheConnection) -> QueryResult<Vec<ReferrerStats>> {
+	use crate::db::schema::referrals::dsl as r;
+	use crate::db::schema::users::dsl as u;
+	use diesel::dsl::sql;
+	use diesel::sql_types::BigInt;
+
+	let rows = r::referrals.inner_join(u::users.on(u::id.eq(r::referrer_id)))
+	                       .group_by(u::address)
+	                       // diesel 1.4 does not mix aggregates with grouped columns
+	                       .select((u::address,
+	                                sql::<BigInt>("CAST(COUNT(*) AS BIGINT)"),
+	                                sql::<BigInt>("CAST(COALESCE(SUM(referrals.bonus), 0) AS BIGINT)")))
+	                       .order(u::address.asc())
+	                       .load::<(String, i64, i64)>(conn)?;
+
+	Ok(rows.into_iter()
+	       .map(|(address, referees, bonus)| ReferrerStats { address, referees, bonus })
+	       .collect())
+}
+
+
+#[test]
+fn bonus_test() {
+	let flat = Settings { rule: "flat:100".parse().unwrap(),
+	                      cap: Some(250) };
+	assert_eq!(flat.bonus(1000, 0), 100);
+	assert_eq!(flat.bonus(1000, 200), 50);
+	assert_eq!(flat.bonus(1000, 250), 0);
+
+	let percent = Settings { rule: "percent:5".parse().unwrap(),
+	                         cap: None };
+	assert_eq!(percent.bonus(1000, 10_000), 50);
+	assert!("bogus:1".parse::<BonusRule>().is_err());
+}
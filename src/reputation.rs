@@ -0,0 +1,319 @@
+//! IP reputation: Tor exits, hosting providers and other ranges that mostly send bots.
+//!
+//! `reputation.lists` (`REPUTATION_LISTS`, comma separated) has `name:policy:path` entries, policy one of
+//! - `block`: registrations are refused with error `912`
+//! - `score=0.7`: the reCAPTCHA v3 score must be at least this, responses without a score fail
+//! - `flag`: only recorded
+//!
+//! A list file has an address or CIDR per line, the first one on the line counts, so plain exit lists,
+//! `ExitAddress` lines of the Tor exit list and `cidr,asn,name` CSVs all work. `#` starts a comment.
+//! When several lists match the strictest policy wins. Files are checked for changes every
+//! `reputation.reload_secs` (default 300).
+
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use actix::prelude::*;
+use failure::{format_err, Error};
+
+use crate::client_ip::{canonical, Cidr};
+use crate::state::State;
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Policy {
+	Flag,
+	MinScore(f64),
+	Block,
+}
+
+impl Policy {
+	/// Block beats any score, a higher score beats a lower one, anything beats a flag.
+	fn strictness(&self) -> f64 {
+		match self {
+			Policy::Flag => 0.0,
+			Policy::MinScore(score) => 1.0 + score,
+			Policy::Block => 3.0,
+		}
+	}
+}
+
+impl std::str::FromStr for Policy {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"block" => Ok(Policy::Block),
+			"flag" => Ok(Policy::Flag),
+			_ if s.starts_with("score=") => {
+				s["score=".len()..].parse::<f64>()
+				                   .ok()
+				                   .filter(|score| *score >= 0.0 && *score <= 1.0)
+				                   .map(Policy::MinScore)
+				                   .ok_or_else(|| format!("invalid score in {:?}", s))
+			},
+			_ => Err(format!("unknown policy {:?}", s)),
+		}
+	}
+}
+
+
+#[derive(Debug, Clone)]
+pub struct ListSettings {
+	pub name: String,
+	pub policy: Policy,
+	pub path: PathBuf,
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+	pub lists: Vec<ListSettings>,
+	pub reload_interval: Duration,
+}
+
+impl std::str::FromStr for ListSettings {
+	type Err = String;
+
+	/// `name:policy:path`
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let mut parts = s.splitn(3, ':');
+		match (parts.next(), parts.next(), parts.next()) {
+			(Some(name), Some(policy), Some(path)) if !name.is_empty() && !path.is_empty() => {
+				Ok(ListSettings { name: name.to_owned(),
+				                  policy: policy.parse()?,
+				                  path: PathBuf::from(path) })
+			},
+			_ => Err(format!("expected name:policy:path, got {:?}", s)),
+		}
+	}
+}
+
+impl Settings {
+	pub fn from_config(config: &crate::config::Reputation) -> Result<Self, String> {
+		let lists = config.lists
+		                  .iter()
+		                  .map(|item| item.trim().parse().map_err(|err| format!("reputation.lists: {}", err)))
+		                  .collect::<Result<Vec<_>, _>>()?;
+		Ok(Self { lists,
+		          reload_interval: Duration::from_secs(config.reload_secs.max(1)) })
+	}
+}
+
+
+/// Address ranges sorted by start and merged, looked up by binary search.
+#[derive(Debug, Default)]
+pub struct RangeSet {
+	v4: Vec<(u32, u32)>,
+	v6: Vec<(u128, u128)>,
+}
+
+impl RangeSet {
+	pub fn from_cidrs<I: IntoIterator<Item = Cidr>>(cidrs: I) -> Self {
+		let mut v4 = Vec::new();
+		let mut v6 = Vec::new();
+		for cidr in cidrs {
+			match cidr.network() {
+				IpAddr::V4(network) => {
+					let host_bits = 32 - u32::from(cidr.prefix());
+					let start = u32::from(network) & if host_bits == 32 { 0 } else { !0u32 << host_bits };
+					let end = start | if host_bits == 32 { !0 } else { !(!0u32 << host_bits) };
+					v4.push((start, end));
+				},
+				IpAddr::V6(network) => {
+					let host_bits = 128 - u32::from(cidr.prefix());
+					let start = u128::from(network) & if host_bits == 128 { 0 } else { !0u128 << host_bits };
+					let end = start | if host_bits == 128 { !0 } else { !(!0u128 << host_bits) };
+					v6.push((start, end));
+				},
+			}
+		}
+		Self { v4: merge(v4),
+		       v6: merge(v6) }
+	}
+
+	pub fn contains(&self, ip: IpAddr) -> bool {
+		match canonical(ip) {
+			IpAddr::V4(ip) => find(&self.v4, u32::from(ip)),
+			IpAddr::V6(ip) => find(&self.v6, u128::from(ip)),
+		}
+	}
+
+	pub fn len(&self) -> usize { self.v4.len() + self.v6.len() }
+
+	pub fn is_empty(&self) -> bool { self.len() == 0 }
+}
+
+fn merge<T: Ord + Copy + std::ops::Add<Output = T> + From<u8>>(mut ranges: Vec<(T, T)>) -> Vec<(T, T)> {
+	ranges.sort();
+	let mut merged: Vec<(T, T)> = Vec::with_capacity(ranges.len());
+	for (start, end) in ranges {
+		match merged.last_mut() {
+			// overlapping or adjacent, `last.1 + 1` can not overflow when it is below `start`
+			Some(last) if start <= last.1 || start == last.1 + T::from(1) => {
+				if end > last.1 {
+					last.1 = end;
+				}
+			},
+			_ => merged.push((start, end)),
+		}
+	}
+	merged
+}
+
+fn find<T: Ord + Copy>(ranges: &[(T, T)], ip: T) -> bool {
+	// the last range starting at or before the address
+	match ranges.binary_search_by(|(start, _)| start.cmp(&ip)) {
+		Ok(_) => true,
+		Err(0) => false,
+		Err(pos) => ip <= ranges[pos - 1].1,
+	}
+}
+
+fn parse_ranges(content: &str) -> Vec<Cidr> {
+	content.lines()
+	       .map(|line| line.split('#').next().unwrap_or_default())
+	       .filter_map(|line| {
+		       line.split(|c: char| c == ',' || c.is_whitespace())
+		           .find_map(|token| token.trim().parse::<Cidr>().ok())
+		      })
+	       .collect()
+}
+
+
+struct List {
+	name: String,
+	policy: Policy,
+	ranges: RangeSet,
+}
+
+/// The strictest list the client IP is on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Verdict {
+	pub list: Option<String>,
+	pub policy: Option<Policy>,
+}
+
+impl Verdict {
+	pub fn blocked(&self) -> bool { self.policy == Some(Policy::Block) }
+
+	/// Checks the reCAPTCHA score against the list's minimum.
+	pub fn score_ok(&self, score: Option<f64>) -> bool {
+		match self.policy {
+			Some(Policy::MinScore(min)) => score.map(|score| score >= min).unwrap_or(false),
+			_ => true,
+		}
+	}
+}
+
+
+pub struct Reputation {
+	settings: Settings,
+	lists: RwLock<Arc<Vec<List>>>,
+	modified: Mutex<Vec<Option<SystemTime>>>,
+}
+
+impl Reputation {
+	/// Loads the lists, a missing or unreadable file fails startup.
+	pub fn new(settings: Settings) -> Result<Self, Error> {
+		let reputation = Self { settings,
+		                        lists: RwLock::new(Arc::new(Vec::new())),
+		                        modified: Mutex::new(Vec::new()) };
+		reputation.reload()?;
+		Ok(reputation)
+	}
+
+	pub fn settings(&self) -> &Settings { &self.settings }
+
+	/// Reloads all lists if any file changed. A failed reload keeps the previous lists.
+	pub fn reload(&self) -> Result<bool, Error> {
+		let modified = self.settings
+		                   .lists
+		                   .iter()
+		                   .map(|list| std::fs::metadata(&list.path).ok().and_then(|meta| meta.modified().ok()))
+		                   .collect::<Vec<_>>();
+		let mut last = self.modified.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+		if !last.is_empty() && *last == modified {
+			return Ok(false);
+		}
+		let mut lists = Vec::with_capacity(self.settings.lists.len());
+		for list in &self.settings.lists {
+			let content = std::fs::read_to_string(&list.path).map_err(|err| {
+				                                                  format_err!("{}: {}", list.path.display(), err)
+				                                                 })?;
+			let ranges = RangeSet::from_cidrs(parse_ranges(&content));
+			log::info!("reputation: {} has {} ranges", list.name, ranges.len());
+			lists.push(List { name: list.name.clone(),
+			                  policy: list.policy,
+			                  ranges });
+		}
+		*self.lists.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = Arc::new(lists);
+		*last = modified;
+		Ok(true)
+	}
+
+	pub fn check(&self, ip: Option<IpAddr>) -> Verdict {
+		let ip = match ip {
+			Some(ip) => ip,
+			None => return Verdict { list: None, policy: None },
+		};
+		let lists = Arc::clone(&self.lists.read().unwrap_or_else(|poisoned| poisoned.into_inner()));
+		let mut strictest: Option<&List> = None;
+		for list in lists.iter().filter(|list| list.ranges.contains(ip)) {
+			if strictest.map(|found| list.policy.strictness() > found.policy.strictness()).unwrap_or(true) {
+				strictest = Some(list);
+			}
+		}
+		Verdict { list: strictest.map(|list| list.name.clone()),
+		          policy: strictest.map(|list| list.policy) }
+	}
+}
+
+
+/// Checks the list files for changes in the current system.
+pub struct Reloader {
+	interval: Duration,
+}
+
+impl Reloader {
+	pub fn start() {
+		let reputation = State::get().reputation();
+		if !reputation.settings().lists.is_empty() {
+			Actor::start(Reloader { interval: reputation.settings().reload_interval });
+		}
+	}
+}
+
+impl Actor for Reloader {
+	type Context = Context<Self>;
+
+	fn started(&mut self, ctx: &mut Self::Context) {
+		ctx.run_interval(self.interval, |_, _| {
+			   if let Err(err) = State::get().reputation().reload() {
+				   log::error!("reputation: reload failed, keeping the loaded lists: {:?}", err);
+			   }
+		   });
+	}
+}
+
+
+#[test]
+fn range_set_test() {
+	let content = "# exits\nExitAddress 198.51.100.7 2019-08-01 10:00:00\n203.0.113.0/25,64496,Hosting\n203.0.113.128/25\n\
+	               2001:db8::/32\nnonsense\n";
+	let ranges = RangeSet::from_cidrs(parse_ranges(content));
+	assert_eq!(ranges.len(), 3); // the two /25 merge
+	let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+	assert!(ranges.contains(ip("198.51.100.7")));
+	assert!(!ranges.contains(ip("198.51.100.8")));
+	assert!(ranges.contains(ip("203.0.113.200")));
+	assert!(ranges.contains(ip("::ffff:203.0.113.1")));
+	assert!(ranges.contains(ip("2001:db8:ffff::1")));
+	assert!(!ranges.contains(ip("2001:db9::1")));
+	assert!(RangeSet::from_cidrs(vec!["0.0.0.0/0".parse().unwrap()]).contains(ip("255.255.255.255")));
+
+	assert_eq!("score=0.7".parse(), Ok(Policy::MinScore(0.7)));
+	assert!("score=2".parse::<Policy>().is_err());
+	let verdict = Verdict { list: Some("hosting".to_owned()), policy: Some(Policy::MinScore(0.7)) };
+	assert!(verdict.score_ok(Some(0.9)) && !verdict.score_ok(Some(0.5)) && !verdict.score_ok(None));
+}
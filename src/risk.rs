@@ -0,0 +1,299 @@
+//! Risk score of a registration from 0 (clean) to 100, summed from the factors below.
+//!
+//! | factor | points |
+//! |---|---|
+//! | `captcha_score` | `(1 - score) * 40` for reCAPTCHA v3, nothing for v2 |
+//! | `honeypot` | 50 when the hidden `website` or `email` field is filled |
+//! | `form_token` | 20 missing, 30 invalid, 40 used before, 40 submitted under `risk.min_form_secs` (3), 10 older than `risk.max_form_secs` (3600) |
+//! | `headers` | 20 no User-Agent, 30 an automation User-Agent, 10 no Accept-Language |
+//! | `ip_reputation` | 15 on a `flag` list, 10 on a `score=` list |
+//!
+//! At `risk.step_up` (40) the client has to solve an interactive challenge checked with
+//! `captcha.challenge_key` (without the key step-up is a denial), at `risk.deny` (70) the registration is refused.
+//! Form tokens come from `GET /1.0/form-token`, signed with `risk.token_secret`
+//! (random per process when unset, so set it when running several instances).
+//! A token is bound to the client IP it was issued to (IPv6 by /64) and is used up by a registration,
+//! except one answered with the step-up, which comes back with the challenge and the same token:
+//! only a passed challenge uses it up, a failed one may be retried.
+//! Uses are remembered in the rate limit store for a day, older tokens are invalid.
+
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use actix_web::http::header;
+use actix_web::{HttpRequest, HttpResponse};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Serialize;
+
+use crate::api;
+use crate::reputation::{Policy, Verdict};
+use crate::state::State;
+
+
+/// Bytes of the HMAC kept in a form token
+const TAG_LEN: usize = 16;
+/// Random bytes in a form token, so each one can be used once
+const NONCE_LEN: usize = 12;
+
+/// Substrings of User-Agents sent by scripts and headless browsers, lowercase
+const AUTOMATION_AGENTS: &[&str] =
+	&["curl/", "wget/", "python-requests", "python-urllib", "go-http-client", "okhttp", "headlesschrome", "phantomjs", "selenium", "puppeteer"];
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Decision {
+	Allow,
+	StepUp,
+	Deny,
+}
+
+impl Decision {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Decision::Allow => "allow",
+			Decision::StepUp => "step_up",
+			Decision::Deny => "deny",
+		}
+	}
+}
+
+
+pub struct Settings {
+	pub step_up: u32,
+	pub deny: u32,
+	pub min_form_secs: i64,
+	pub max_form_secs: i64,
+	token_key: hmac::SigningKey,
+}
+
+impl Settings {
+	pub fn from_config(config: &crate::config::Risk) -> Result<Self, String> {
+		if config.step_up > config.deny {
+			return Err("risk.step_up is above risk.deny".to_owned());
+		}
+		let secret = match &config.token_secret {
+			Some(secret) if !secret.is_empty() => secret.clone().into_bytes(),
+			_ => {
+				let mut secret = vec![0; 32];
+				SystemRandom::new().fill(&mut secret).map_err(|_| "risk.token_secret: no randomness for a secret".to_owned())?;
+				secret
+			},
+		};
+		Ok(Self { step_up: config.step_up,
+		          deny: config.deny,
+		          min_form_secs: config.min_form_secs,
+		          max_form_secs: config.max_form_secs,
+		          token_key: hmac::SigningKey::new(&ring::digest::SHA256, &secret) })
+	}
+
+	pub fn decide(&self, score: u32) -> Decision {
+		if score >= self.deny {
+			Decision::Deny
+		} else if score >= self.step_up {
+			Decision::StepUp
+		} else {
+			Decision::Allow
+		}
+	}
+
+	/// A token for the client at `ip` with the time the form was rendered and a nonce.
+	pub fn issue_token(&self, ip: Option<IpAddr>, now: i64) -> String {
+		let mut nonce = [0; NONCE_LEN];
+		// a failing generator leaves tokens only as unique as their time
+		let _ = SystemRandom::new().fill(&mut nonce);
+		let payload = format!("{}.{}", now, hex::encode(&nonce));
+		format!("{}.{}", payload, self.token_tag(&payload, ip))
+	}
+
+	/// The nonce and seconds since the token was issued, `None` if it is forged, malformed,
+	/// issued to another client IP or too old for its use to be remembered.
+	pub fn verify_token<'t>(&self, token: &'t str, ip: Option<IpAddr>, now: i64) -> Option<(&'t str, i64)> {
+		let token = token.trim();
+		let split = token.rfind('.')?;
+		let (payload, tag) = (&token[..split], hex::decode(&token[split + 1..]).ok()?);
+		let expected = hex::decode(self.token_tag(payload, ip)).ok()?;
+		ring::constant_time::verify_slices_are_equal(&expected, &tag).ok()?;
+
+		let mut parts = payload.splitn(2, '.');
+		let issued = parts.next()?.parse::<i64>().ok()?;
+		let nonce = parts.next()?;
+		Some((nonce, now - issued)).filter(|(_, age)| *age <= crate::ratelimit::ONCE_SECS)
+	}
+
+	fn token_tag(&self, payload: &str, ip: Option<IpAddr>) -> String {
+		let client = ip.map(crate::ratelimit::ip_key).unwrap_or_default();
+		let tag = hmac::sign(&self.token_key, format!("{}.{}", payload, client).as_bytes());
+		hex::encode(&tag.as_ref()[..TAG_LEN])
+	}
+}
+
+
+/// The form token sent with a registration.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FormToken {
+	Missing,
+	/// Forged, malformed or for another client
+	Invalid,
+	/// Used by an earlier registration
+	Reused,
+	/// Seconds since it was issued
+	Age(i64),
+}
+
+
+/// What a registration looked like, gathered by the handler.
+pub struct Signals<'a> {
+	/// reCAPTCHA v3 score
+	pub captcha_score: Option<f64>,
+	pub honeypot_filled: bool,
+	pub form_token: FormToken,
+	form_nonce: Option<&'a str>,
+	pub user_agent: Option<&'a str>,
+	pub accept_language: bool,
+	pub reputation: &'a Verdict,
+}
+
+impl<'a> Signals<'a> {
+	pub fn new(req: &'a HttpRequest, data: &'a api::Reg, captcha_score: Option<f64>, reputation: &'a Verdict, now: i64) -> Self {
+		let filled = |field: &Option<String>| field.as_ref().map(|value| !value.trim().is_empty()).unwrap_or(false);
+		let (form_token, form_nonce) = match data.form_token.as_ref().filter(|token| !token.trim().is_empty()) {
+			None => (FormToken::Missing, None),
+			Some(token) => match State::get().risk().verify_token(token, crate::client_ip::resolve(req), now) {
+				Some((nonce, age)) => (FormToken::Age(age), Some(nonce)),
+				None => (FormToken::Invalid, None),
+			},
+		};
+		Self { captcha_score,
+		       honeypot_filled: filled(&data.website) || filled(&data.email),
+		       form_token,
+		       form_nonce,
+		       user_agent: req.headers().get(header::USER_AGENT).and_then(|value| value.to_str().ok()),
+		       accept_language: req.headers().contains_key(header::ACCEPT_LANGUAGE),
+		       reputation }
+	}
+}
+
+impl Signals<'_> {
+	/// Uses up the form token. Returns `false` when it was used before, it is `Reused` then.
+	pub fn take_form_token(&mut self) -> bool {
+		match self.form_nonce.take() {
+			Some(nonce) if !State::get().ratelimit().take_once(&format!("form_token:{}", nonce)) => {
+				self.form_token = FormToken::Reused;
+				false
+			},
+			_ => true,
+		}
+	}
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Assessment {
+	pub score: u32,
+	/// Points per factor, only the ones that added something
+	pub factors: BTreeMap<&'static str, u32>,
+	pub decision: Decision,
+}
+
+impl Assessment {
+	pub fn factors_json(&self) -> String { serde_json::to_string(&self.factors).unwrap_or_default() }
+}
+
+pub fn assess(settings: &Settings, signals: &Signals) -> Assessment {
+	let mut factors = BTreeMap::new();
+	let mut add = |name: &'static str, points: u32| {
+		if points > 0 {
+			*factors.entry(name).or_insert(0) += points;
+		}
+	};
+
+	if let Some(score) = signals.captcha_score {
+		add("captcha_score", ((1.0 - score.max(0.0).min(1.0)) * 40.0).round() as u32);
+	}
+	if signals.honeypot_filled {
+		add("honeypot", 50);
+	}
+	add("form_token", match signals.form_token {
+		FormToken::Missing => 20,
+		FormToken::Invalid => 30,
+		FormToken::Reused => 40,
+		FormToken::Age(age) if age < settings.min_form_secs => 40,
+		FormToken::Age(age) if age > settings.max_form_secs => 10,
+		FormToken::Age(_) => 0,
+	});
+	match signals.user_agent.map(str::to_lowercase) {
+		None => add("headers", 20),
+		Some(agent) => {
+			if AUTOMATION_AGENTS.iter().any(|automation| agent.contains(automation)) {
+				add("headers", 30);
+			}
+		},
+	}
+	if !signals.accept_language {
+		add("headers", 10);
+	}
+	add("ip_reputation", match signals.reputation.policy {
+		Some(Policy::Flag) => 15,
+		Some(Policy::MinScore(_)) => 10,
+		_ => 0,
+	});
+
+	let score = factors.values().sum::<u32>().min(100);
+	Assessment { score,
+	             decision: settings.decide(score),
+	             factors }
+}
+
+
+#[derive(Serialize)]
+struct TokenResp {
+	token: String,
+}
+
+/// `GET /1.0/form-token`, fetched when the registration form is shown.
+pub fn form_token(req: HttpRequest) -> HttpResponse {
+	let token = State::get().risk().issue_token(crate::client_ip::resolve(&req), chrono::Utc::now().timestamp());
+	HttpResponse::Ok().header(header::CACHE_CONTROL, "no-store").json(TokenResp { token })
+}
+
+
+#[test]
+fn assess_test() {
+	let settings = Settings::from_config(&crate::config::Risk { token_secret: Some("test".to_owned()),
+	                                                           ..Default::default() }).unwrap();
+	let ip = "203.0.113.7".parse().ok();
+	let token = settings.issue_token(ip, 1_000);
+	let nonce = token.split('.').nth(1).unwrap();
+	assert_eq!(settings.verify_token(&token, ip, 1_030), Some((nonce, 30)));
+	assert_ne!(settings.issue_token(ip, 1_000), token);
+	assert_eq!(settings.verify_token(&token.replace("1000.", "900."), ip, 1_030), None);
+	// issued to someone else
+	assert_eq!(settings.verify_token(&token, "198.51.100.1".parse().ok(), 1_030), None);
+	assert_eq!(settings.verify_token(&token, None, 1_030), None);
+	assert_eq!(settings.verify_token(&token, ip, 1_000 + crate::ratelimit::ONCE_SECS + 1), None);
+
+	let clean = Verdict { list: None, policy: None };
+	let mut signals = Signals { captcha_score: Some(0.9),
+	                            honeypot_filled: false,
+	                            form_token: FormToken::Age(30),
+	                            form_nonce: None,
+	                            user_agent: Some("Mozilla/5.0 (X11; Linux x86_64) Firefox/68.0"),
+	                            accept_language: true,
+	                            reputation: &clean };
+	let assessment = assess(&settings, &signals);
+	assert_eq!((assessment.score, assessment.decision), (4, Decision::Allow));
+
+	signals.user_agent = Some("python-requests/2.22");
+	signals.form_token = FormToken::Missing;
+	let assessment = assess(&settings, &signals);
+	assert_eq!((assessment.score, assessment.decision), (54, Decision::StepUp));
+	assert_eq!(assessment.factors_json(), r#"{"captcha_score":4,"form_token":20,"headers":30}"#);
+
+	signals.honeypot_filled = true;
+	assert_eq!(assess(&settings, &signals).decision, Decision::Deny);
+	signals.honeypot_filled = false;
+	signals.form_token = FormToken::Invalid;
+	assert_eq!(assess(&settings, &signals).factors["form_token"], 30);
+	signals.form_token = FormToken::Reused;
+	assert_eq!(assess(&settings, &signals).factors["form_token"], 40);
+}
@@ -0,0 +1,189 @@
+//! Screening of addresses against local blocklists (sanctions and the like) and allowlists.
+//!
+//! `[screening]`:
+//! - `blocklists`, `allowlists`: file paths
+//! - `reload_secs`: how often the files are checked for changes (default 300)
+//!
+//! A file has an address per line, or `address,reason` CSV lines. Empty lines, `#` comments
+//! and an `address,...` header are skipped. Addresses compare case-insensitively.
+//! An address on an allowlist passes even if a blocklist has it, for reviewed false positives.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use actix::prelude::*;
+use failure::{format_err, Error};
+
+use crate::state::State;
+
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+	pub blocklists: Vec<PathBuf>,
+	pub allowlists: Vec<PathBuf>,
+	pub reload_interval: Duration,
+}
+
+impl Settings {
+	pub fn from_config(config: &crate::config::Screening) -> Self {
+		Self { blocklists: config.blocklists.clone(),
+		       allowlists: config.allowlists.clone(),
+		       reload_interval: Duration::from_secs(config.reload_secs.max(1)) }
+	}
+
+	fn files(&self) -> impl Iterator<Item = &PathBuf> { self.blocklists.iter().chain(self.allowlists.iter()) }
+}
+
+
+/// Why an address is blocked.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hit {
+	/// File name of the blocklist
+	pub list: String,
+	pub reason: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct Lists {
+	blocked: HashMap<String, Hit>,
+	allowed: HashSet<String>,
+}
+
+impl Lists {
+	fn load(settings: &Settings) -> Result<Self, Error> {
+		let mut lists = Lists::default();
+		for path in &settings.blocklists {
+			let list = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+			for (address, reason) in read_list(path)? {
+				lists.blocked.entry(address).or_insert_with(|| Hit { list: list.clone(), reason });
+			}
+		}
+		for path in &settings.allowlists {
+			lists.allowed.extend(read_list(path)?.into_iter().map(|(address, _)| address));
+		}
+		Ok(lists)
+	}
+}
+
+fn read_list(path: &Path) -> Result<Vec<(String, Option<String>)>, Error> {
+	let content = std::fs::read_to_string(path).map_err(|err| format_err!("{}: {}", path.display(), err))?;
+	Ok(parse_list(&content))
+}
+
+fn parse_list(content: &str) -> Vec<(String, Option<String>)> {
+	content.lines()
+	       .map(str::trim)
+	       .filter(|line| !line.is_empty() && !line.starts_with('#'))
+	       .filter_map(|line| {
+		       let mut fields = line.splitn(2, ',');
+		       let address = normalize(fields.next()?);
+		       if address == "address" {
+			       return None;
+		       }
+		       let reason = fields.next()
+		                          .map(|reason| reason.trim().trim_matches('"').to_owned())
+		                          .filter(|reason| !reason.is_empty());
+		       Some((address, reason))
+		      })
+	       .collect()
+}
+
+fn normalize(address: &str) -> String { address.trim().trim_matches('"').to_lowercase() }
+
+
+pub struct Screening {
+	settings: Settings,
+	lists: RwLock<Arc<Lists>>,
+	modified: Mutex<Vec<Option<SystemTime>>>,
+}
+
+impl Screening {
+	/// Loads the lists, a missing or unreadable file fails startup.
+	pub fn new(settings: Settings) -> Result<Self, Error> {
+		let screening = Self { settings,
+		                       lists: RwLock::new(Arc::new(Lists::default())),
+		                       modified: Mutex::new(Vec::new()) };
+		screening.reload()?;
+		Ok(screening)
+	}
+
+	pub fn from_config(config: &crate::config::Screening) -> Result<Self, Error> { Self::new(Settings::from_config(config)) }
+
+	pub fn settings(&self) -> &Settings { &self.settings }
+
+	/// Reloads all lists if any file changed. A failed reload keeps the previous lists.
+	pub fn reload(&self) -> Result<bool, Error> {
+		let modified = self.settings
+		                   .files()
+		                   .map(|path| std::fs::metadata(path).ok().and_then(|meta| meta.modified().ok()))
+		                   .collect::<Vec<_>>();
+		let mut last = self.modified.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+		if !last.is_empty() && *last == modified {
+			return Ok(false);
+		}
+		let lists = Lists::load(&self.settings)?;
+		log::info!("screening: {} blocked and {} allowed addresses", lists.blocked.len(), lists.allowed.len());
+		*self.lists.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = Arc::new(lists);
+		*last = modified;
+		Ok(true)
+	}
+
+	/// `Some` when the address must not be served or paid.
+	pub fn screen(&self, address: &str) -> Option<Hit> {
+		let address = normalize(address);
+		let lists = Arc::clone(&self.lists.read().unwrap_or_else(|poisoned| poisoned.into_inner()));
+		if lists.allowed.contains(&address) {
+			return None;
+		}
+		lists.blocked.get(&address).cloned()
+	}
+}
+
+
+/// Checks the list files for changes in the current system.
+pub struct Reloader {
+	interval: Duration,
+}
+
+impl Reloader {
+	pub fn start() {
+		let screening = State::get().screening();
+		if screening.settings().files().next().is_some() {
+			Actor::start(Reloader { interval: screening.settings().reload_interval });
+		}
+	}
+}
+
+impl Actor for Reloader {
+	type Context = Context<Self>;
+
+	fn started(&mut self, ctx: &mut Self::Context) {
+		ctx.run_interval(self.interval, |_, _| {
+			   if let Err(err) = State::get().screening().reload() {
+				   log::error!("screening: reload failed, keeping the loaded lists: {:?}", err);
+			   }
+		   });
+	}
+}
+
+
+#[test]
+fn parse_list_test() {
+	let list = "# OFAC\n0xAbC,\"SDN list\"\naddress,reason\n\n  0xdef  \n";
+	assert_eq!(parse_list(list),
+	           vec![("0xabc".to_owned(), Some("SDN list".to_owned())), ("0xdef".to_owned(), None)]);
+
+	let mut lists = Lists::default();
+	lists.blocked.insert("0xabc".to_owned(), Hit { list: "ofac.csv".to_owned(), reason: None });
+	lists.blocked.insert("0xdef".to_owned(), Hit { list: "ofac.csv".to_owned(), reason: None });
+	lists.allowed.insert("0xdef".to_owned());
+	let screening = Screening { settings: Settings { blocklists: Vec::new(),
+	                                                 allowlists: Vec::new(),
+	                                                 reload_interval: Duration::from_secs(300) },
+	                            lists: RwLock::new(Arc::new(lists)),
+	                            modified: Mutex::new(Vec::new()) };
+	assert_eq!(screening.screen("0xABC").map(|hit| hit.list), Some("ofac.csv".to_owned()));
+	assert_eq!(screening.screen("0xDEF"), None);
+	assert_eq!(screening.screen("0x123"), None);
+}
@@ -0,0 +1,157 @@
+//! Graceful shutdown on SIGTERM or SIGINT, and the pid file.
+//!
+//! The first signal makes `/readyz` fail and tells systemd `STOPPING=1`. The listeners keep accepting
+//! for `server.shutdown_delay_secs` so load balancers see the failing readiness and move traffic away,
+//! then stop accepting, and requests in flight get `server.shutdown_timeout_secs` to finish
+//! before the process exits, connections on unix sockets included. A second signal exits right away.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use actix::prelude::*;
+use actix_web::dev::Server;
+use futures::future::join_all;
+use futures::{Future, Stream};
+use tokio_signal::unix::{Signal, SIGINT, SIGTERM};
+
+use crate::health;
+use crate::systemd;
+use crate::uds;
+
+
+/// How often open unix socket connections are counted while draining
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+
+/// Stops the servers, which must be started with `disable_signals`, and the unix socket listeners on a signal.
+pub struct Shutdown {
+	servers: Vec<Server>,
+	unix_listeners: Vec<uds::Listener>,
+	delay: Duration,
+	timeout: Duration,
+	signaled: bool,
+}
+
+impl Shutdown {
+	pub fn start(servers: Vec<Server>, unix_listeners: Vec<uds::Listener>, delay: Duration, timeout: Duration) {
+		Actor::start(Shutdown { servers,
+		                        unix_listeners,
+		                        delay,
+		                        timeout,
+		                        signaled: false });
+	}
+
+	/// Exits once the unix socket connections are closed or the timeout passed,
+	/// the servers have drained already.
+	fn wait_unix_connections(&mut self, deadline: Instant, ctx: &mut Context<Self>) {
+		ctx.run_interval(POLL_INTERVAL, move |act, _| {
+			   let open = act.unix_listeners.iter().map(uds::Listener::connections).sum::<usize>();
+			   if open == 0 || Instant::now() >= deadline {
+				   if open > 0 {
+					   log::warn!("shutdown: closing {} unix socket connections", open);
+				   }
+				   log::info!("shutdown: drained");
+				   System::current().stop();
+			   }
+		   });
+	}
+}
+
+impl Actor for Shutdown {
+	type Context = Context<Self>;
+
+	fn started(&mut self, ctx: &mut Self::Context) {
+		let signals = Signal::new(SIGTERM).flatten_stream().select(Signal::new(SIGINT).flatten_stream());
+		ctx.add_stream(signals);
+	}
+}
+
+impl StreamHandler<i32, io::Error> for Shutdown {
+	fn handle(&mut self, signal: i32, ctx: &mut Self::Context) {
+		if self.signaled {
+			log::warn!("shutdown: signal {} again, exiting without draining", signal);
+			System::current().stop();
+			return;
+		}
+		self.signaled = true;
+		log::info!("shutdown: signal {}, readiness fails from now on", signal);
+		health::begin_shutdown();
+		systemd::notify("STOPPING=1");
+
+		ctx.run_later(self.delay, |act, ctx| {
+			   log::info!("shutdown: draining requests in flight");
+			   let deadline = Instant::now() + act.timeout;
+			   act.unix_listeners.iter_mut().for_each(uds::Listener::stop);
+			   let stopped = join_all(act.servers.iter().map(|server| server.stop(true)).collect::<Vec<_>>());
+			   ctx.spawn(actix::fut::wrap_future(stopped).then(move |_, act: &mut Self, ctx| {
+				                                             act.wait_unix_connections(deadline, ctx);
+				                                             actix::fut::ok(())
+				                                            }));
+		   });
+	}
+
+	fn error(&mut self, err: io::Error, _: &mut Self::Context) -> Running {
+		log::error!("shutdown: listening for signals failed: {}", err);
+		Running::Continue
+	}
+}
+
+
+/// Holds the pid file, removing it when dropped.
+pub struct PidFile {
+	path: PathBuf,
+}
+
+impl PidFile {
+	/// Fails if the file names another process that is still running, a stale file is overwritten.
+	pub fn create(path: &Path) -> io::Result<Self> {
+		match std::fs::read_to_string(path) {
+			Ok(contents) => {
+				if let Some(pid) = contents.trim().parse().ok().filter(|&pid| is_running(pid)) {
+					return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+					                          format!("pid file {} names running process {}", path.display(), pid)));
+				}
+			},
+			Err(ref err) if err.kind() == io::ErrorKind::NotFound => {},
+			Err(err) => return Err(err),
+		}
+		std::fs::write(path, format!("{}\n", std::process::id()))?;
+		Ok(Self { path: path.to_owned() })
+	}
+}
+
+fn is_running(pid: libc::pid_t) -> bool {
+	if pid <= 0 || pid as u32 == std::process::id() {
+		return false;
+	}
+	// signal 0 only checks that the process exists, EPERM means it belongs to another user
+	let alive = unsafe { libc::kill(pid, 0) } == 0;
+	alive || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+impl Drop for PidFile {
+	fn drop(&mut self) {
+		if let Err(err) = std::fs::remove_file(&self.path) {
+			log::warn!("removing the pid file {} failed: {}", self.path.display(), err);
+		}
+	}
+}
+
+
+#[test]
+fn pid_file_test() {
+	let path = std::env::temp_dir().join(format!("bounty-{}.pid", std::process::id()));
+	let pid_file = PidFile::create(&path).unwrap();
+	assert_eq!(std::fs::read_to_string(&path).unwrap(), format!("{}\n", std::process::id()));
+	drop(pid_file);
+	assert!(!path.exists());
+
+	// a stale file is taken over, one of a running process is not
+	std::fs::write(&path, "0\n").unwrap();
+	drop(PidFile::create(&path).unwrap());
+	let parent = unsafe { libc::getppid() };
+	std::fs::write(&path, format!("{}\n", parent)).unwrap();
+	assert_eq!(PidFile::create(&path).err().unwrap().kind(), io::ErrorKind::AlreadyExists);
+	assert_eq!(std::fs::read_to_string(&path).unwrap(), format!("{}\n", parent));
+	std::fs::remove_file(&path).unwrap();
+}
@@ -5,20 +5,81 @@ static STATE: OnceCell<State> = OnceCell::INIT;
 
 
 pub struct State {
+	config: crate::config::Config,
 	pool: crate::db::TheConnectionPool,
+	referral: crate::referral::Settings,
+	admin_session: crate::admin::session::Settings,
+	stats: crate::stats::Cache,
+	ratelimit: crate::ratelimit::Limiter,
+	client_ip: crate::client_ip::Settings,
+	geoip: crate::geoip::Geoip,
+	screening: crate::screening::Screening,
+	reputation: crate::reputation::Reputation,
+	risk: crate::risk::Settings,
+	tls: Option<crate::tls::Certs>,
 }
 
 impl State {
 	pub fn get() -> &'static State { STATE.get().expect("The State is not initialized") }
 
+	pub fn try_get() -> Option<&'static State> { STATE.get() }
+
 	pub fn initialize(state: State) {
 		if STATE.set(state).is_err() {
 			panic!("Cant init State");
 		}
 	}
 
-	pub fn new(pool: crate::db::TheConnectionPool) -> Self { Self { pool } }
+	pub fn new(config: crate::config::Config,
+	           pool: crate::db::TheConnectionPool,
+	           referral: crate::referral::Settings,
+	           admin_session: crate::admin::session::Settings,
+	           stats: crate::stats::Cache,
+	           ratelimit: crate::ratelimit::Limiter,
+	           client_ip: crate::client_ip::Settings,
+	           geoip: crate::geoip::Geoip,
+	           screening: crate::screening::Screening,
+	           reputation: crate::reputation::Reputation,
+	           risk: crate::risk::Settings,
+	           tls: Option<crate::tls::Certs>)
+	           -> Self {
+		Self { config,
+		       pool,
+		       referral,
+		       admin_session,
+		       stats,
+		       ratelimit,
+		       client_ip,
+		       geoip,
+		       screening,
+		       reputation,
+		       risk,
+		       tls }
+	}
+
+	pub fn config(&self) -> &crate::config::Config { &self.config }
 
 	pub fn get_pool(&self) -> crate::db::TheConnectionPool  {
 		std::sync::Arc::clone(&self.pool)}
+
+	pub fn referral(&self) -> &crate::referral::Settings { &self.referral }
+
+	pub fn admin_session(&self) -> &crate::admin::session::Settings { &self.admin_session }
+
+	pub fn stats(&self) -> &crate::stats::Cache { &self.stats }
+
+	pub fn ratelimit(&self) -> &crate::ratelimit::Limiter { &self.ratelimit }
+
+	pub fn client_ip(&self) -> &crate::client_ip::Settings { &self.client_ip }
+
+	pub fn geoip(&self) -> &crate::geoip::Geoip { &self.geoip }
+
+	pub fn screening(&self) -> &crate::screening::Screening { &self.screening }
+
+	pub fn reputation(&self) -> &crate::reputation::Reputation { &self.reputation }
+
+	pub fn risk(&self) -> &crate::risk::Settings { &self.risk }
+
+	/// Loaded certificates when TLS is on
+	pub fn tls(&self) -> Option<&crate::tls::Certs> { self.tls.as_ref() }
 }
@@ -0,0 +1,135 @@
+//! Campaign statistics computed with aggregate SQL and cached for `stats.cache_secs`.
+//! The public endpoint gets coarse numbers, admins get everything.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Text};
+use serde::Serialize;
+
+use crate::db::TheConnection;
+
+
+/// Public counts are rounded down to this
+const COARSE_COUNT: i64 = 100;
+/// Public amounts are rounded down to this
+const COARSE_AMOUNT: i64 = 10_000;
+
+
+#[derive(Debug, Clone, Default, Serialize, QueryableByName)]
+pub struct Totals {
+	#[sql_type = "BigInt"]
+	pub allocated: i64,
+	#[sql_type = "BigInt"]
+	pub terms_accepted: i64,
+	#[sql_type = "BigInt"]
+	pub not_resident: i64,
+	/// Accepted the terms, declared non-residency, has a positive amount and was not found ineligible
+	#[sql_type = "BigInt"]
+	pub eligible: i64,
+	#[sql_type = "BigInt"]
+	pub amount_allocated: i64,
+	#[sql_type = "BigInt"]
+	pub amount_eligible: i64,
+}
+
+#[derive(Debug, Clone, Serialize, QueryableByName)]
+pub struct DailyRegistrations {
+	/// `YYYY-MM-DD`
+	#[sql_type = "Text"]
+	pub day: String,
+	#[sql_type = "BigInt"]
+	pub registrations: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Stats {
+	#[serde(flatten)]
+	pub totals: Totals,
+	pub daily: Vec<DailyRegistrations>,
+	pub computed_at: NaiveDateTime,
+}
+
+/// Stats for everyone.
+#[derive(Debug, Clone, Serialize)]
+pub struct PublicStats {
+	#[serde(flatten)]
+	pub totals: Totals,
+	pub computed_at: NaiveDateTime,
+}
+
+impl From<&Stats> for PublicStats {
+	fn from(stats: &Stats) -> Self {
+		let totals = &stats.totals;
+		Self { totals: Totals { allocated: coarse(totals.allocated, COARSE_COUNT),
+		                        terms_accepted: coarse(totals.terms_accepted, COARSE_COUNT),
+		                        not_resident: coarse(totals.not_resident, COARSE_COUNT),
+		                        eligible: coarse(totals.eligible, COARSE_COUNT),
+		                        amount_allocated: coarse(totals.amount_allocated, COARSE_AMOUNT),
+		                        amount_eligible: coarse(totals.amount_eligible, COARSE_AMOUNT) },
+		       computed_at: stats.computed_at }
+	}
+}
+
+fn coarse(value: i64, step: i64) -> i64 { value - value % step }
+
+
+const TOTALS: &str = "SELECT \
+	CAST(COUNT(*) AS BIGINT) AS allocated, \
+	CAST(COALESCE(SUM(CASE WHEN terms_signed THEN 1 ELSE 0 END), 0) AS BIGINT) AS terms_accepted, \
+	CAST(COALESCE(SUM(CASE WHEN not_resident THEN 1 ELSE 0 END), 0) AS BIGINT) AS not_resident, \
+	CAST(COALESCE(SUM(CASE WHEN terms_signed AND not_resident AND amount > 0 AND NOT ineligible THEN 1 ELSE 0 END), 0) AS BIGINT) AS eligible, \
+	CAST(COALESCE(SUM(amount), 0) AS BIGINT) AS amount_allocated, \
+	CAST(COALESCE(SUM(CASE WHEN terms_signed AND not_resident AND amount > 0 AND NOT ineligible THEN amount ELSE 0 END), 0) AS BIGINT) AS amount_eligible \
+	FROM users";
+
+const DAILY: &str = "SELECT CAST(DATE(registered_at) AS TEXT) AS day, CAST(COUNT(*) AS BIGINT) AS registrations \
+	FROM users WHERE registered_at IS NOT NULL GROUP BY 1 ORDER BY 1";
+
+pub fn compute(conn: &TheConnection) -> QueryResult<Stats> {
+	let _query = crate::db::instrument("stats");
+	let totals = diesel::sql_query(TOTALS).get_result::<Totals>(conn)?;
+	let daily = diesel::sql_query(DAILY).load::<DailyRegistrations>(conn)?;
+	Ok(Stats { totals,
+	           daily,
+	           computed_at: Utc::now().naive_utc() })
+}
+
+
+/// Last computed stats, recomputed when older than the interval.
+pub struct Cache {
+	interval: Duration,
+	cached: Mutex<Option<(Instant, Arc<Stats>)>>,
+}
+
+impl Cache {
+	pub fn new(interval: Duration) -> Self {
+		Self { interval,
+		       cached: Mutex::new(None) }
+	}
+
+	/// `stats.cache_secs`: how long results are reused, 60 by default.
+	pub fn from_config(config: &crate::config::Stats) -> Self { Self::new(Duration::from_secs(config.cache_secs)) }
+
+	/// Holds the lock while computing, so concurrent requests wait for one query instead of running their own.
+	pub fn get(&self, conn: &TheConnection) -> QueryResult<Arc<Stats>> {
+		let mut cached = self.cached.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+		if let Some((at, stats)) = cached.as_ref() {
+			if at.elapsed() < self.interval {
+				return Ok(Arc::clone(stats));
+			}
+		}
+		let stats = Arc::new(compute(conn)?);
+		*cached = Some((Instant::now(), Arc::clone(&stats)));
+		Ok(stats)
+	}
+}
+
+
+#[test]
+fn coarse_test() {
+	assert_eq!(coarse(0, COARSE_COUNT), 0);
+	assert_eq!(coarse(1_299, COARSE_COUNT), 1_200);
+	assert_eq!(coarse(123_456, COARSE_AMOUNT), 120_000);
+}
@@ -0,0 +1,383 @@
+//! Clusters of registrations sharing signals, to find farms of allocation addresses.
+//!
+//! Registrations (successful attempts) are grouped by
+//! - `ip`: the same client IP
+//! - `net`: the same IPv4 /24 or IPv6 /48
+//! - `time`: registered in a burst, each less than `--window` seconds after the previous one
+//! - `ua`: the same User-Agent
+//! - `referrer`: the same referrer address
+//!
+//! A cluster scores its weight per signal times the additional addresses in it, raised by
+//! the share of its addresses that other kinds of clusters have as well.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::Write;
+use std::net::IpAddr;
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use failure::{bail, format_err, Error};
+use serde::{Deserialize, Serialize};
+
+use crate::cli::{self, Args};
+use crate::config::Config;
+use crate::db::{lower, TheConnection};
+use crate::db::models::RegistrationAttempt;
+
+
+const DEFAULT_WINDOW_SECS: i64 = 10;
+const DEFAULT_MIN_SIZE: usize = 3;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Signal {
+	Ip,
+	Net,
+	Time,
+	Ua,
+	Referrer,
+}
+
+impl Signal {
+	fn as_str(&self) -> &'static str {
+		match self {
+			Signal::Ip => "ip",
+			Signal::Net => "net",
+			Signal::Time => "time",
+			Signal::Ua => "ua",
+			Signal::Referrer => "referrer",
+		}
+	}
+
+	/// How much sharing this signal says about common control.
+	fn weight(&self) -> f64 {
+		match self {
+			Signal::Ip => 1.0,
+			Signal::Time => 0.8,
+			Signal::Net => 0.6,
+			Signal::Referrer => 0.5,
+			Signal::Ua => 0.3,
+		}
+	}
+}
+
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Cluster {
+	/// `signal:key`, pass it to `--flag`
+	pub id: String,
+	pub signal: Signal,
+	pub key: String,
+	pub size: usize,
+	pub score: f64,
+	pub first_seen: NaiveDateTime,
+	pub last_seen: NaiveDateTime,
+	pub addresses: Vec<String>,
+}
+
+/// A cluster as a CSV row, the addresses space separated.
+#[derive(Debug, Serialize)]
+struct Row<'a> {
+	id: &'a str,
+	signal: &'a str,
+	key: &'a str,
+	size: usize,
+	score: f64,
+	first_seen: NaiveDateTime,
+	last_seen: NaiveDateTime,
+	addresses: String,
+}
+
+/// A cluster read back from a reviewed report, `--flag` acts on exactly its addresses.
+#[derive(Debug, Deserialize)]
+pub struct Reviewed {
+	pub id: String,
+	pub score: f64,
+	pub addresses: Vec<String>,
+}
+
+/// A CSV row of a reviewed report.
+#[derive(Debug, Deserialize)]
+struct ReviewedRow {
+	id: String,
+	score: f64,
+	addresses: String,
+}
+
+/// The cluster `cluster_id` from a report in either format.
+fn read_reviewed(report: &str, cluster_id: &str) -> Result<Reviewed, Error> {
+	let clusters: Vec<Reviewed> = if report.trim_start().starts_with('[') {
+		serde_json::from_str(report)?
+	} else {
+		csv::Reader::from_reader(report.as_bytes()).deserialize::<ReviewedRow>()
+		                                           .map(|row| {
+			                                           row.map(|row| Reviewed { id: row.id,
+			                                                                    score: row.score,
+			                                                                    addresses: row.addresses
+			                                                                                  .split_whitespace()
+			                                                                                  .map(str::to_owned)
+			                                                                                  .collect() })
+			                                          })
+		                                           .collect::<Result<_, _>>()?
+	};
+	clusters.into_iter()
+	        .find(|cluster| cluster.id == cluster_id)
+	        .ok_or_else(|| format_err!("no cluster {} in the report", cluster_id))
+}
+
+
+struct Group {
+	addresses: BTreeSet<String>,
+	first_seen: NaiveDateTime,
+	last_seen: NaiveDateTime,
+}
+
+impl Group {
+	fn new(at: NaiveDateTime) -> Self {
+		Self { addresses: BTreeSet::new(),
+		       first_seen: at,
+		       last_seen: at }
+	}
+
+	fn add(&mut self, address: &str, at: NaiveDateTime) {
+		self.addresses.insert(address.to_lowercase());
+		self.first_seen = self.first_seen.min(at);
+		self.last_seen = self.last_seen.max(at);
+	}
+}
+
+/// The /24 of an IPv4 or /48 of an IPv6 address.
+fn network(ip: IpAddr) -> String {
+	match crate::client_ip::canonical(ip) {
+		IpAddr::V4(ip) => {
+			let octets = ip.octets();
+			format!("{}.{}.{}.0/24", octets[0], octets[1], octets[2])
+		},
+		IpAddr::V6(ip) => {
+			let segments = ip.segments();
+			format!("{}/48", std::net::Ipv6Addr::new(segments[0], segments[1], segments[2], 0, 0, 0, 0, 0))
+		},
+	}
+}
+
+/// Clusters of at least `min_size` addresses, highest score first.
+pub fn clusters(attempts: &[RegistrationAttempt], window_secs: i64, min_size: usize) -> Vec<Cluster> {
+	let mut groups: BTreeMap<(Signal, String), Group> = BTreeMap::new();
+
+	let mut registered = attempts.iter().filter(|attempt| attempt.outcome == "registered").collect::<Vec<_>>();
+	registered.sort_by_key(|attempt| attempt.created_at);
+
+	let mut burst: Option<(String, NaiveDateTime)> = None;
+	for attempt in &registered {
+		let at = attempt.created_at;
+		let mut add = |signal: Signal, key: String| {
+			groups.entry((signal, key)).or_insert_with(|| Group::new(at)).add(&attempt.address, at)
+		};
+
+		if let Some(ip) = attempt.ip.as_ref().and_then(|ip| ip.parse::<IpAddr>().ok()) {
+			add(Signal::Ip, ip.to_string());
+			add(Signal::Net, network(ip));
+		}
+		if let Some(ua) = attempt.user_agent.as_ref().filter(|ua| !ua.is_empty()) {
+			add(Signal::Ua, ua.clone());
+		}
+		if let Some(referrer) = attempt.referrer.as_ref().filter(|referrer| !referrer.is_empty()) {
+			add(Signal::Referrer, referrer.to_lowercase());
+		}
+		// a burst is named after its first registration
+		let key = match burst.take() {
+			Some((key, last)) if (at - last).num_seconds() < window_secs => key,
+			_ => at.format("%Y-%m-%dT%H:%M:%S").to_string(),
+		};
+		add(Signal::Time, key.clone());
+		burst = Some((key, at));
+	}
+
+	let groups = groups.into_iter()
+	                   .filter(|(_, group)| group.addresses.len() >= min_size.max(2))
+	                   .collect::<Vec<_>>();
+
+	// the signals each address shares with others
+	let mut signals: HashMap<&str, BTreeSet<Signal>> = HashMap::new();
+	for ((signal, _), group) in &groups {
+		for address in &group.addresses {
+			signals.entry(address.as_str()).or_default().insert(*signal);
+		}
+	}
+
+	let mut clusters = groups.iter()
+	                         .map(|((signal, key), group)| {
+		                         let size = group.addresses.len();
+		                         let overlapping = group.addresses
+		                                                .iter()
+		                                                .filter(|address| signals[address.as_str()].len() > 1)
+		                                                .count();
+		                         let overlap = overlapping as f64 / size as f64;
+		                         let score = signal.weight() * (size - 1) as f64 * (1.0 + overlap);
+		                         Cluster { id: format!("{}:{}", signal.as_str(), key),
+		                                   signal: *signal,
+		                                   key: key.clone(),
+		                                   size,
+		                                   score: (score * 100.0).round() / 100.0,
+		                                   first_seen: group.first_seen,
+		                                   last_seen: group.last_seen,
+		                                   addresses: group.addresses.iter().cloned().collect() }
+		                        })
+	                         .collect::<Vec<_>>();
+	clusters.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal).then_with(|| a.id.cmp(&b.id)));
+	clusters
+}
+
+
+fn load_attempts(conn: &TheConnection, since: Option<NaiveDateTime>) -> QueryResult<Vec<RegistrationAttempt>> {
+	use crate::db::schema::registration_attempts::dsl::*;
+
+	let mut query = registration_attempts.filter(outcome.eq("registered")).into_boxed();
+	if let Some(since) = since {
+		query = query.filter(created_at.ge(since));
+	}
+	query.order(created_at.asc()).load(conn)
+}
+
+/// Marks every user of the reviewed cluster ineligible, with an audit record each.
+pub fn flag_cluster(conn: &TheConnection, cluster: &Reviewed, actor: &str, reason: &str) -> Result<usize, Error> {
+	use crate::db::schema::users::dsl::*;
+
+	conn.transaction(|| {
+		    let mut flagged = 0;
+		    for user_address in &cluster.addresses {
+			    let found = users.filter(lower(address).eq(user_address.trim().to_lowercase()))
+			                     .select((id, ineligible))
+			                     .load::<(i32, bool)>(conn)?;
+			    for (user_id, was_ineligible) in found {
+				    if was_ineligible {
+					    continue;
+				    }
+				    diesel::update(users.filter(id.eq(user_id))).set(ineligible.eq(true)).execute(conn)?;
+				    let detail = serde_json::json!({ "cluster": cluster.id, "score": cluster.score, "reason": reason });
+				    crate::admin::service::audit(conn, actor, "flag_ineligible", Some(user_id), &detail)?;
+				    flagged += 1;
+			    }
+		    }
+		    Ok(flagged)
+	    })
+}
+
+
+/// `sybil-report` command.
+///
+/// Options:
+/// - `--since YYYY-MM-DD` only registrations from that day on
+/// - `--window SECS` burst gap (default 10), `--min-size N` smallest cluster reported (default 3)
+/// - `--format csv|json` (default csv), `--out PATH` stdout by default
+/// - `--flag ID --report PATH --reason TEXT [--moderator NAME]` marks the users of a cluster ineligible instead,
+///   the addresses listed for it in the reviewed report at `PATH`: clusters computed again may have grown since
+pub fn report(config: &Config, args: &Args) -> Result<(), Error> {
+	if let Some(cluster_id) = args.get("flag") {
+		return flag(config, args, cluster_id);
+	}
+
+	let since = match args.get("since") {
+		Some(day) => {
+			Some(chrono::NaiveDate::parse_from_str(day, "%Y-%m-%d").map_err(|err| format_err!("--since: {}", err))?
+			                                                         .and_hms(0, 0, 0))
+		},
+		None => None,
+	};
+	let window = args.parse_opt("window")?.unwrap_or(DEFAULT_WINDOW_SECS);
+	let min_size = args.parse_opt("min-size")?.unwrap_or(DEFAULT_MIN_SIZE);
+
+	let conn = cli::establish_connection(config)?;
+	let attempts = load_attempts(&conn, since)?;
+	let clusters = clusters(&attempts, window, min_size);
+	log::info!("{} registrations, {} clusters", attempts.len(), clusters.len());
+
+	let mut out: Box<dyn Write> = match args.get("out") {
+		Some(path) => Box::new(std::fs::File::create(path)?),
+		None => Box::new(std::io::stdout()),
+	};
+	match args.get("format").unwrap_or("csv") {
+		"json" => writeln!(out, "{}", serde_json::to_string_pretty(&clusters)?)?,
+		"csv" => {
+			let mut writer = csv::Writer::from_writer(out);
+			for cluster in &clusters {
+				writer.serialize(Row { id: &cluster.id,
+				                       signal: cluster.signal.as_str(),
+				                       key: &cluster.key,
+				                       size: cluster.size,
+				                       score: cluster.score,
+				                       first_seen: cluster.first_seen,
+				                       last_seen: cluster.last_seen,
+				                       addresses: cluster.addresses.join(" ") })?;
+			}
+			writer.flush()?;
+		},
+		format => bail!("--format: expected csv or json, got {}", format),
+	}
+	Ok(())
+}
+
+
+fn flag(config: &Config, args: &Args, cluster_id: &str) -> Result<(), Error> {
+	let path = args.require("report")?;
+	let reason = args.require("reason")?;
+	if reason.trim().is_empty() {
+		bail!("--reason is empty");
+	}
+	let moderator = match args.get("moderator") {
+		Some(moderator) => moderator.to_owned(),
+		None => std::env::var("USER").map_err(|_| format_err!("--moderator is required"))?,
+	};
+	let report = std::fs::read_to_string(path).map_err(|err| format_err!("--report {}: {}", path, err))?;
+	let cluster = read_reviewed(&report, cluster_id)?;
+
+	let conn = cli::establish_connection(config)?;
+	let flagged = flag_cluster(&conn, &cluster, &format!("cli:{}", moderator), reason)?;
+	log::info!("{} of {} users in {} flagged ineligible", flagged, cluster.addresses.len(), cluster.id);
+	Ok(())
+}
+
+
+#[test]
+fn clusters_test() {
+	let at = |secs: i64| chrono::NaiveDate::from_ymd(2019, 9, 1).and_hms(12, 0, 0) + chrono::Duration::seconds(secs);
+	let attempt = |n: i32, ip: &str, secs: i64, outcome: &str| {
+		RegistrationAttempt { id: n,
+		                      address: format!("0x{:040}", n),
+		                      ip: Some(ip.to_owned()),
+		                      ip_list: None,
+		                      outcome: outcome.to_owned(),
+		                      created_at: at(secs),
+		                      user_agent: Some("bot/1.0".to_owned()),
+		                      referrer: None,
+		                      risk_score: None,
+		                      risk_factors: None,
+		                      risk_decision: None }
+	};
+	let attempts = vec![attempt(1, "198.51.100.1", 0, "registered"),
+	                    attempt(2, "198.51.100.1", 3, "registered"),
+	                    attempt(3, "198.51.100.1", 6, "registered"),
+	                    attempt(4, "198.51.100.2", 3600, "registered"),
+	                    attempt(5, "203.0.113.9", 7200, "rejected")];
+	let clusters = clusters(&attempts, DEFAULT_WINDOW_SECS, 3);
+
+	let ids = clusters.iter().map(|cluster| cluster.id.as_str()).collect::<Vec<_>>();
+	assert_eq!(ids, vec!["ip:198.51.100.1", "net:198.51.100.0/24", "time:2019-09-01T12:00:00", "ua:bot/1.0"]);
+	assert_eq!(clusters[0].size, 3);
+	assert_eq!(clusters[0].score, 4.0);
+	assert_eq!(clusters[1].size, 4);
+	assert_eq!(clusters[2].score, 3.2);
+	assert_eq!(network("2001:db8:1:2::1".parse().unwrap()), "2001:db8:1::/48");
+}
+
+#[test]
+fn read_reviewed_test() {
+	let csv = "id,signal,key,size,score,first_seen,last_seen,addresses\n\
+	           ip:198.51.100.1,ip,198.51.100.1,2,1.0,2019-09-01T12:00:00,2019-09-01T12:00:03,0xaa 0xbb\n";
+	let cluster = read_reviewed(csv, "ip:198.51.100.1").unwrap();
+	assert_eq!((cluster.score, cluster.addresses), (1.0, vec!["0xaa".to_owned(), "0xbb".to_owned()]));
+	assert!(read_reviewed(csv, "ip:198.51.100.2").is_err());
+
+	let json = r#"[{ "id": "ua:bot/1.0", "signal": "ua", "key": "bot/1.0", "size": 1, "score": 0.3, "addresses": ["0xcc"] }]"#;
+	assert_eq!(read_reviewed(json, "ua:bot/1.0").unwrap().addresses, vec!["0xcc".to_owned()]);
+}
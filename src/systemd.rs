@@ -0,0 +1,225 @@
+//! systemd integration for `Type=notify` units: `READY=1` once the listeners are bound, `STOPPING=1`
+//! when shutdown begins and `WATCHDOG=1` every half `WatchdogSec=` while the event loop runs.
+//!
+//! Messages go to the datagram socket in `NOTIFY_SOCKET` (a path, or `@name` in the abstract namespace).
+//! Without the variable, e.g. when started by hand, nothing is sent.
+//!
+//! With socket activation systemd opens the listening sockets and passes them in `LISTEN_FDS`,
+//! named by `FileDescriptorName=` in `LISTEN_FDNAMES`. They stay open across restarts of the service,
+//! so connections wait in the backlog instead of being refused.
+
+use std::ffi::OsStr;
+use std::io;
+use std::net::TcpListener;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::time::Duration;
+use actix::prelude::*;
+
+
+/// Sends a state like `READY=1`, failures are only logged.
+pub fn notify(state: &str) {
+	if let Some(path) = std::env::var_os("NOTIFY_SOCKET") {
+		if let Err(err) = send(&path, state) {
+			log::warn!("systemd: notify {:?} failed: {}", state, err);
+		}
+	}
+}
+
+fn send(path: &OsStr, state: &str) -> io::Result<()> {
+	let path = path.as_bytes();
+	let mut addr: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+	if path.is_empty() || path.len() >= addr.sun_path.len() {
+		return Err(io::Error::new(io::ErrorKind::InvalidInput, "NOTIFY_SOCKET is not a socket path"));
+	}
+	addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+	for (dst, src) in addr.sun_path.iter_mut().zip(path) {
+		*dst = *src as libc::c_char;
+	}
+	if path[0] == b'@' {
+		addr.sun_path[0] = 0;
+	}
+	let len = std::mem::size_of::<libc::sa_family_t>() + path.len();
+
+	unsafe {
+		let fd = libc::socket(libc::AF_UNIX, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+		if fd < 0 {
+			return Err(io::Error::last_os_error());
+		}
+		let sent = libc::sendto(fd,
+		                        state.as_ptr() as *const libc::c_void,
+		                        state.len(),
+		                        libc::MSG_NOSIGNAL,
+		                        &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+		                        len as libc::socklen_t);
+		let result = if sent < 0 { Err(io::Error::last_os_error()) } else { Ok(()) };
+		libc::close(fd);
+		result
+	}
+}
+
+
+/// Watchdog interval systemd asked for, half of `WATCHDOG_USEC`.
+/// `None` when the watchdog is off or meant for another process (`WATCHDOG_PID`).
+fn watchdog_interval() -> Option<Duration> {
+	if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+		if pid.parse::<u32>().ok() != Some(std::process::id()) {
+			return None;
+		}
+	}
+	let usec = std::env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok().filter(|usec| *usec > 0)?;
+	Some(Duration::from_micros(usec / 2))
+}
+
+/// Pings the watchdog from the main event loop, so a stuck loop gets the unit restarted.
+pub struct Watchdog {
+	interval: Duration,
+}
+
+impl Watchdog {
+	pub fn start() {
+		if let Some(interval) = watchdog_interval() {
+			log::info!("systemd: watchdog every {:?}", interval);
+			Actor::start(Watchdog { interval });
+		}
+	}
+}
+
+impl Actor for Watchdog {
+	type Context = Context<Self>;
+
+	fn started(&mut self, ctx: &mut Self::Context) {
+		ctx.run_interval(self.interval, |_, _| notify("WATCHDOG=1"));
+	}
+}
+
+
+/// The first descriptor of `LISTEN_FDS`.
+const LISTEN_FDS_START: RawFd = 3;
+
+pub enum Socket {
+	Tcp(TcpListener),
+	Unix(UnixListener),
+}
+
+/// Sockets passed by socket activation and their names, `unknown` when unnamed.
+pub struct Activated {
+	sockets: Vec<(String, Socket)>,
+}
+
+impl Activated {
+	/// Whether systemd passed sockets to this process.
+	pub fn pending() -> bool { listen_fds().is_some() }
+
+	/// Takes the passed sockets and unsets the variables, so processes started later don't take them too.
+	pub fn take() -> io::Result<Self> {
+		let count = match listen_fds() {
+			Some(count) => count,
+			None => return Ok(Self { sockets: Vec::new() }),
+		};
+		let names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+		let mut names = names.split(':');
+		let mut sockets = Vec::new();
+		for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+			let name = names.next().filter(|name| !name.is_empty()).unwrap_or("unknown");
+			log::info!("systemd: socket {} passed as {:?}", fd, name);
+			sockets.push((name.to_owned(), socket(fd)?));
+		}
+		for name in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+			std::env::remove_var(name);
+		}
+		Ok(Self { sockets })
+	}
+
+	/// Removes the TCP sockets named `name`.
+	pub fn tcp(&mut self, name: &str) -> io::Result<Vec<TcpListener>> {
+		let (named, rest) = self.sockets.drain(..).partition::<Vec<_>, _>(|(socket_name, _)| socket_name == name);
+		self.sockets = rest;
+		named.into_iter()
+		     .map(|(_, socket)| match socket {
+			     Socket::Tcp(listener) => Ok(listener),
+			     Socket::Unix(_) => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("socket {:?} must be TCP", name))),
+		     })
+		     .collect()
+	}
+
+	/// The sockets not taken by name, for the public API.
+	pub fn public(self) -> (Vec<TcpListener>, Vec<UnixListener>) {
+		let mut tcp = Vec::new();
+		let mut unix = Vec::new();
+		for (_, socket) in self.sockets {
+			match socket {
+				Socket::Tcp(listener) => tcp.push(listener),
+				Socket::Unix(listener) => unix.push(listener),
+			}
+		}
+		(tcp, unix)
+	}
+}
+
+/// `LISTEN_FDS` when `LISTEN_PID` is this process.
+fn listen_fds() -> Option<RawFd> {
+	let pid = std::env::var("LISTEN_PID").ok()?.parse::<u32>().ok()?;
+	if pid != std::process::id() {
+		return None;
+	}
+	std::env::var("LISTEN_FDS").ok()?.parse().ok().filter(|count| *count > 0)
+}
+
+/// Wraps a passed descriptor by its address family.
+fn socket(fd: RawFd) -> io::Result<Socket> {
+	let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+	let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+	unsafe {
+		if libc::getsockname(fd, &mut addr as *mut libc::sockaddr_storage as *mut libc::sockaddr, &mut len) < 0 {
+			return Err(io::Error::last_os_error());
+		}
+		// not inherited by processes started later
+		if libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) < 0 {
+			return Err(io::Error::last_os_error());
+		}
+		match libc::c_int::from(addr.ss_family) {
+			libc::AF_INET | libc::AF_INET6 => Ok(Socket::Tcp(TcpListener::from_raw_fd(fd))),
+			libc::AF_UNIX => Ok(Socket::Unix(UnixListener::from_raw_fd(fd))),
+			family => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("socket {} has address family {}", fd, family))),
+		}
+	}
+}
+
+
+#[test]
+fn socket_test() {
+	use std::os::unix::io::IntoRawFd;
+
+	let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+	let addr = tcp.local_addr().unwrap();
+	match socket(tcp.into_raw_fd()).unwrap() {
+		Socket::Tcp(listener) => assert_eq!(listener.local_addr().unwrap(), addr),
+		Socket::Unix(_) => panic!("expected a TCP socket"),
+	}
+	let mut activated = Activated { sockets: vec![("https".to_owned(), Socket::Tcp(TcpListener::bind("127.0.0.1:0").unwrap())),
+	                                              ("unknown".to_owned(), Socket::Tcp(TcpListener::bind("127.0.0.1:0").unwrap()))] };
+	assert_eq!(activated.tcp("https").unwrap().len(), 1);
+	assert!(activated.tcp("metrics").unwrap().is_empty());
+	let (tcp, unix) = activated.public();
+	assert_eq!((tcp.len(), unix.len()), (1, 0));
+}
+
+#[test]
+fn notify_test() {
+	use std::os::unix::net::UnixDatagram;
+
+	let dir = std::env::temp_dir().join(format!("bounty-notify-{}", std::process::id()));
+	std::fs::create_dir_all(&dir).unwrap();
+	let path = dir.join("notify.sock");
+	let _ = std::fs::remove_file(&path);
+	let socket = UnixDatagram::bind(&path).unwrap();
+
+	send(path.as_os_str(), "READY=1").unwrap();
+	let mut buf = [0; 64];
+	let len = socket.recv(&mut buf).unwrap();
+	assert_eq!(&buf[..len], b"READY=1");
+	assert!(send(OsStr::new(""), "READY=1").is_err());
+	std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,272 @@
+//! Bounty tasks and the moderation queue of submissions.
+//! An approved submission credits the task's reward to the user's amount.
+
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::api::ApiError;
+use crate::db::TheConnection;
+use crate::db::models::{NewSubmission, NewTask, Submission, Task, User};
+
+
+pub const STATUS_PENDING: &str = "pending";
+pub const STATUS_APPROVED: &str = "approved";
+pub const STATUS_REJECTED: &str = "rejected";
+
+const MAX_PROOF_URL: usize = 2048;
+const MAX_PROOF_TEXT: usize = 8192;
+
+
+#[derive(Debug)]
+pub enum Error {
+	UserNotFound,
+	TaskNotFound,
+	SubmissionNotFound,
+	AlreadySubmitted,
+	AlreadyModerated,
+	InvalidProof(&'static str),
+	Db(diesel::result::Error),
+}
+
+impl From<diesel::result::Error> for Error {
+	fn from(error: diesel::result::Error) -> Self { Error::Db(error) }
+}
+
+impl From<Error> for ApiError {
+	fn from(error: Error) -> Self {
+		match error {
+			Error::UserNotFound => ApiError::UserNotFound,
+			Error::TaskNotFound => ApiError::TaskNotFound,
+			Error::AlreadySubmitted => ApiError::AlreadySubmitted,
+			Error::InvalidProof(_) => ApiError::InvalidProof,
+			Error::SubmissionNotFound | Error::AlreadyModerated | Error::Db(_) => {
+				log::error!("tasks: {:?}", error);
+				ApiError::Internal
+			},
+		}
+	}
+}
+
+impl std::fmt::Display for Error {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Error::UserNotFound => write!(f, "user not found"),
+			Error::TaskNotFound => write!(f, "task not found"),
+			Error::SubmissionNotFound => write!(f, "submission not found"),
+			Error::AlreadySubmitted => write!(f, "already submitted"),
+			Error::AlreadyModerated => write!(f, "submission is already moderated"),
+			Error::InvalidProof(reason) => write!(f, "invalid proof: {}", reason),
+			Error::Db(err) => write!(f, "{}", err),
+		}
+	}
+}
+
+impl std::error::Error for Error {}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Decision {
+	Approve,
+	Reject,
+}
+
+
+pub fn active_tasks(conn: &TheConnection) -> QueryResult<Vec<Task>> {
+	use crate::db::schema::tasks::dsl::*;
+
+	tasks.filter(active.eq(true)).order(id.asc()).load::<Task>(conn)
+}
+
+pub fn create_task(conn: &TheConnection, title: &str, description: &str, reward: i64) -> QueryResult<usize> {
+	use crate::db::schema::tasks::dsl::tasks;
+
+	diesel::insert_into(tasks).values(&NewTask { title,
+	                                             description,
+	                                             reward,
+	                                             active: true,
+	                                             created_at: Utc::now().naive_utc() })
+	                          .execute(conn)
+}
+
+
+/// Adds a pending submission of `address` for the task.
+/// A user can have only one pending or approved submission per task.
+pub fn submit(conn: &TheConnection, address: &str, task_id: i32, proof_url: &str, proof_text: &str) -> Result<(), Error> {
+	use crate::db::schema::submissions::dsl as s;
+	use crate::db::schema::tasks::dsl as t;
+	use crate::db::schema::users::dsl as u;
+	use diesel::result::DatabaseErrorKind;
+	use diesel::result::Error::DatabaseError;
+
+	validate_proof(proof_url, proof_text)?;
+
+	conn.transaction(|| {
+		    let user = u::users.filter(u::address.eq(address))
+		                       .first::<User>(conn)
+		                       .optional()?
+		                       .ok_or(Error::UserNotFound)?;
+		    t::tasks.filter(t::id.eq(task_id))
+		            .filter(t::active.eq(true))
+		            .first::<Task>(conn)
+		            .optional()?
+		            .ok_or(Error::TaskNotFound)?;
+
+		    let existing: i64 = s::submissions.filter(s::task_id.eq(task_id))
+		                                      .filter(s::user_id.eq(user.id))
+		                                      .filter(s::status.ne(STATUS_REJECTED))
+		                                      .count()
+		                                      .get_result(conn)?;
+		    if existing > 0 {
+			    return Err(Error::AlreadySubmitted);
+		    }
+
+		    // a concurrent submission gets past the count, not past the unique index
+		    diesel::insert_into(s::submissions).values(&NewSubmission { task_id,
+		                                                                user_id: user.id,
+		                                                                proof_url: proof_url.trim(),
+		                                                                proof_text: proof_text.trim(),
+		                                                                status: STATUS_PENDING,
+		                                                                created_at: Utc::now().naive_utc() })
+		                                       .execute(conn)
+		                                       .map_err(|err| match err {
+			                                       DatabaseError(DatabaseErrorKind::UniqueViolation, _) => Error::AlreadySubmitted,
+			                                       err => Error::Db(err),
+			                                      })?;
+		    Ok(())
+	    })
+}
+
+fn validate_proof(proof_url: &str, proof_text: &str) -> Result<(), Error> {
+	let proof_url = proof_url.trim();
+	if proof_url.len() > MAX_PROOF_URL {
+		return Err(Error::InvalidProof("url is too long"));
+	}
+	if proof_text.len() > MAX_PROOF_TEXT {
+		return Err(Error::InvalidProof("text is too long"));
+	}
+	match url::Url::parse(proof_url) {
+		Ok(ref url) if url.scheme() == "https" || url.scheme() == "http" => Ok(()),
+		_ => Err(Error::InvalidProof("url must be http(s)")),
+	}
+}
+
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubmissionView {
+	pub id: i32,
+	pub task_id: i32,
+	pub task: String,
+	pub address: String,
+	pub proof_url: String,
+	pub proof_text: String,
+	pub status: String,
+	pub reason: Option<String>,
+	pub reward: i64,
+	pub created_at: NaiveDateTime,
+	pub moderated_at: Option<NaiveDateTime>,
+}
+
+impl SubmissionView {
+	fn new((submission, task, user): (Submission, Task, User)) -> Self {
+		Self { id: submission.id,
+		       task_id: task.id,
+		       task: task.title,
+		       address: user.address,
+		       proof_url: submission.proof_url,
+		       proof_text: submission.proof_text,
+		       status: submission.status,
+		       reason: submission.reason,
+		       reward: submission.reward,
+		       created_at: submission.created_at,
+		       moderated_at: submission.moderated_at }
+	}
+}
+
+pub fn user_submissions(conn: &TheConnection, address: &str) -> QueryResult<Vec<SubmissionView>> {
+	use crate::db::schema::submissions::dsl as s;
+	use crate::db::schema::{tasks, users};
+
+	s::submissions.inner_join(tasks::table)
+	              .inner_join(users::table)
+	              .filter(users::address.eq(address))
+	              .order(s::id.desc())
+	              .load::<(Submission, Task, User)>(conn)
+	              .map(|rows| rows.into_iter().map(SubmissionView::new).collect())
+}
+
+/// Pending submissions, oldest first.
+pub fn queue(conn: &TheConnection) -> QueryResult<Vec<SubmissionView>> {
+	use crate::db::schema::submissions::dsl as s;
+	use crate::db::schema::{tasks, users};
+
+	s::submissions.inner_join(tasks::table)
+	              .inner_join(users::table)
+	              .filter(s::status.eq(STATUS_PENDING))
+	              .order(s::id.asc())
+	              .load::<(Submission, Task, User)>(conn)
+	              .map(|rows| rows.into_iter().map(SubmissionView::new).collect())
+}
+
+
+/// Approves or rejects a pending submission.
+/// Approval credits the task's reward to the user's amount.
+pub fn moderate(conn: &TheConnection,
+                submission_id: i32,
+                decision: Decision,
+                reason: &str,
+                moderator: &str)
+                -> Result<(), Error> {
+	use crate::db::schema::submissions::dsl as s;
+	use crate::db::schema::tasks::dsl as t;
+	use crate::db::schema::users::dsl as u;
+
+	conn.transaction(|| {
+		    let submission = s::submissions.filter(s::id.eq(submission_id))
+		                                   .first::<Submission>(conn)
+		                                   .optional()?
+		                                   .ok_or(Error::SubmissionNotFound)?;
+		    if submission.status != STATUS_PENDING {
+			    return Err(Error::AlreadyModerated);
+		    }
+
+		    let (status, reward) = match decision {
+			    Decision::Approve => {
+				    let reward = t::tasks.filter(t::id.eq(submission.task_id))
+				                         .select(t::reward)
+				                         .first::<i64>(conn)?;
+				    (STATUS_APPROVED, reward)
+				   },
+			    Decision::Reject => (STATUS_REJECTED, 0),
+		    };
+
+		    // only one of concurrent moderators gets the pending row and credits the reward
+		    let moderated = diesel::update(s::submissions.filter(s::id.eq(submission.id))
+		                                                 .filter(s::status.eq(STATUS_PENDING)))
+		                    .set((s::status.eq(status),
+		                          s::reason.eq(Some(reason)),
+		                          s::reward.eq(reward),
+		                          s::moderator.eq(Some(moderator)),
+		                          s::moderated_at.eq(Some(Utc::now().naive_utc()))))
+		                    .execute(conn)?;
+		    if moderated != 1 {
+			    return Err(Error::AlreadyModerated);
+		    }
+		    if reward > 0 {
+			    diesel::update(u::users.filter(u::id.eq(submission.user_id))).set(u::amount.eq(u::amount + reward))
+			                                                                 .execute(conn)?;
+		    }
+		    log::info!("submission #{} {} by {}: {}", submission.id, status, moderator, reason);
+		    Ok(())
+	    })
+}
+
+
+#[test]
+fn validate_proof_test() {
+	assert!(validate_proof("https://medium.com/@someone/post", "").is_ok());
+	assert!(validate_proof(" http://example.com ", "text").is_ok());
+	assert!(validate_proof("javascript:alert(1)", "").is_err());
+	assert!(validate_proof("not a url", "").is_err());
+}
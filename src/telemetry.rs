@@ -0,0 +1,386 @@
+//! Request tracing exported over OTLP/HTTP (JSON) to `telemetry.endpoint`,
+//! e.g. `http://localhost:4318` for a local collector. Disabled when unset.
+//! `telemetry.service_name` names the service, `bounty-server` by default.
+//!
+//! Spans: the incoming request (continuing a W3C `traceparent` if given),
+//! the reCAPTCHA siteverify call and the DB queries wrapped in `db_span`.
+
+use std::cell::Cell;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use actix::{Actor, AsyncContext, Context};
+use actix::fut::WrapFuture;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::Error;
+use futures::future::{ok, FutureResult};
+use futures::{Future, Poll};
+use once_cell::sync::OnceCell;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde_json::{json, Value};
+
+
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+/// Spans beyond this are dropped until the next export
+const MAX_BUFFERED: usize = 4096;
+
+static SETTINGS: OnceCell<Option<Settings>> = OnceCell::INIT;
+static BUFFER: OnceCell<Mutex<Vec<SpanData>>> = OnceCell::INIT;
+
+thread_local! {
+	/// Span of the request being handled on this thread
+	static CURRENT: Cell<Option<SpanContext>> = Cell::new(None);
+}
+
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+	/// Full url of the traces endpoint
+	pub url: String,
+	pub service_name: String,
+}
+
+impl Settings {
+	pub fn from_config(config: &crate::config::Telemetry) -> Option<Self> {
+		let endpoint = config.endpoint.as_ref().filter(|endpoint| !endpoint.is_empty())?;
+		Some(Self { url: format!("{}/v1/traces", endpoint.trim_end_matches('/')),
+		            service_name: config.service_name.clone() })
+	}
+}
+
+/// Sets up the exporter settings, before any span is started.
+pub fn init(config: &crate::config::Telemetry) { let _ = SETTINGS.set(Settings::from_config(config)); }
+
+fn settings() -> Option<&'static Settings> { SETTINGS.get().and_then(Option::as_ref) }
+
+fn buffer() -> &'static Mutex<Vec<SpanData>> { BUFFER.get_or_init(|| Mutex::new(Vec::new())) }
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpanContext {
+	pub trace_id: [u8; 16],
+	pub span_id: [u8; 8],
+	pub sampled: bool,
+}
+
+impl SpanContext {
+	/// Parses a version 00 `traceparent` header.
+	pub fn from_traceparent(header: &str) -> Option<Self> {
+		let mut parts = header.trim().split('-');
+		let (version, trace, span, flags) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+		if version != "00" || parts.next().is_some() || trace.len() != 32 || span.len() != 16 || flags.len() != 2 {
+			return None;
+		}
+		let mut trace_id = [0; 16];
+		let mut span_id = [0; 8];
+		trace_id.copy_from_slice(&hex::decode(trace).ok()?);
+		span_id.copy_from_slice(&hex::decode(span).ok()?);
+		let flags = u8::from_str_radix(flags, 16).ok()?;
+		if trace_id == [0; 16] || span_id == [0; 8] {
+			return None;
+		}
+		Some(Self { trace_id,
+		            span_id,
+		            sampled: flags & 1 == 1 })
+	}
+
+	pub fn traceparent(&self) -> String {
+		format!("00-{}-{}-{:02x}",
+		        hex::encode(&self.trace_id),
+		        hex::encode(&self.span_id),
+		        if self.sampled { 1 } else { 0 })
+	}
+}
+
+pub fn current() -> Option<SpanContext> { CURRENT.with(Cell::get) }
+
+fn with_current<T, C: FnOnce() -> T>(context: Option<SpanContext>, f: C) -> T {
+	let previous = CURRENT.with(|current| current.replace(context));
+	let result = f();
+	CURRENT.with(|current| current.set(previous));
+	result
+}
+
+fn random_bytes<A: AsMut<[u8]> + Default>() -> A {
+	let mut bytes = A::default();
+	// ids only need to be unique
+	let _ = SystemRandom::new().fill(bytes.as_mut());
+	bytes
+}
+
+fn unix_nanos(time: SystemTime) -> u64 {
+	time.duration_since(UNIX_EPOCH)
+	    .map(|since| since.as_secs() * 1_000_000_000 + u64::from(since.subsec_nanos()))
+	    .unwrap_or(0)
+}
+
+
+#[derive(Debug, Clone, Copy)]
+pub enum Kind {
+	Internal = 1,
+	Server = 2,
+	Client = 3,
+}
+
+#[derive(Debug, Clone)]
+struct SpanData {
+	context: SpanContext,
+	parent: Option<[u8; 8]>,
+	name: String,
+	kind: Kind,
+	start: SystemTime,
+	end: SystemTime,
+	attributes: Vec<(&'static str, Value)>,
+	error: bool,
+}
+
+/// Recorded when dropped. Does nothing when tracing is disabled or the trace is not sampled.
+pub struct Span {
+	data: Option<SpanData>,
+}
+
+impl Span {
+	/// Child of the current span, or a new trace.
+	pub fn start<S: Into<String>>(name: S, kind: Kind) -> Self { Self::with_parent(name, kind, current()) }
+
+	pub fn with_parent<S: Into<String>>(name: S, kind: Kind, parent: Option<SpanContext>) -> Self {
+		if settings().is_none() || parent.map(|parent| !parent.sampled).unwrap_or(false) {
+			return Span { data: None };
+		}
+		let context = SpanContext { trace_id: parent.map(|parent| parent.trace_id).unwrap_or_else(random_bytes),
+		                            span_id: random_bytes(),
+		                            sampled: true };
+		let now = SystemTime::now();
+		Span { data: Some(SpanData { context,
+		                             parent: parent.map(|parent| parent.span_id),
+		                             name: name.into(),
+		                             kind,
+		                             start: now,
+		                             end: now,
+		                             attributes: Vec::new(),
+		                             error: false }) }
+	}
+
+	pub fn context(&self) -> Option<SpanContext> { self.data.as_ref().map(|data| data.context) }
+
+	pub fn set_attribute<V: Into<Value>>(&mut self, key: &'static str, value: V) {
+		if let Some(data) = &mut self.data {
+			data.attributes.push((key, value.into()));
+		}
+	}
+
+	pub fn set_name<S: Into<String>>(&mut self, name: S) {
+		if let Some(data) = &mut self.data {
+			data.name = name.into();
+		}
+	}
+
+	pub fn set_error(&mut self) {
+		if let Some(data) = &mut self.data {
+			data.error = true;
+		}
+	}
+}
+
+impl Drop for Span {
+	fn drop(&mut self) {
+		if let Some(mut data) = self.data.take() {
+			data.end = SystemTime::now();
+			let mut buffer = buffer().lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+			if buffer.len() < MAX_BUFFERED {
+				buffer.push(data);
+			}
+		}
+	}
+}
+
+/// Span of a DB query, ends when dropped.
+pub fn db_span(query: &'static str) -> Span {
+	let mut span = Span::start(format!("db {}", query), Kind::Client);
+	span.set_attribute("db.system", if cfg!(feature = "sqlite") { "sqlite" } else { "postgresql" });
+	span.set_attribute("db.operation", query);
+	span
+}
+
+
+fn any_value(value: &Value) -> Value {
+	match value {
+		Value::Bool(value) => json!({ "boolValue": value }),
+		Value::Number(number) if number.is_i64() => json!({ "intValue": number.to_string() }),
+		Value::Number(number) => json!({ "doubleValue": number.as_f64() }),
+		Value::String(value) => json!({ "stringValue": value }),
+		value => json!({ "stringValue": value.to_string() }),
+	}
+}
+
+fn otlp_json(service_name: &str, spans: &[SpanData]) -> Value {
+	let spans = spans.iter()
+	                 .map(|span| {
+		                 let mut otlp = json!({
+			                 "traceId": hex::encode(&span.context.trace_id),
+			                 "spanId": hex::encode(&span.context.span_id),
+			                 "name": span.name,
+			                 "kind": span.kind as u8,
+			                 "startTimeUnixNano": unix_nanos(span.start).to_string(),
+			                 "endTimeUnixNano": unix_nanos(span.end).to_string(),
+			                 "attributes": span.attributes.iter()
+			                                   .map(|(key, value)| json!({ "key": key, "value": any_value(value) }))
+			                                   .collect::<Vec<_>>(),
+			                 "status": { "code": if span.error { 2 } else { 0 } },
+		                 });
+		                 if let Some(parent) = span.parent {
+			                 otlp["parentSpanId"] = json!(hex::encode(&parent));
+		                 }
+		                 otlp
+	                 })
+	                 .collect::<Vec<_>>();
+	json!({ "resourceSpans": [{
+		"resource": { "attributes": [{ "key": "service.name", "value": { "stringValue": service_name } }] },
+		"scopeSpans": [{ "scope": { "name": "bounty-server" }, "spans": spans }],
+	}] })
+}
+
+
+/// Posts the buffered spans to the collector periodically.
+pub struct Exporter {
+	settings: Settings,
+}
+
+impl Exporter {
+	/// Starts the exporter in the current system if tracing is enabled.
+	pub fn start() {
+		if let Some(settings) = settings() {
+			log::info!("tracing: exporting to {}", settings.url);
+			Actor::start(Exporter { settings: settings.clone() });
+		}
+	}
+
+	fn export(&mut self, ctx: &mut Context<Self>) {
+		let spans = std::mem::replace(&mut *buffer().lock().unwrap_or_else(|poisoned| poisoned.into_inner()), Vec::new());
+		if spans.is_empty() {
+			return;
+		}
+		let count = spans.len();
+		let body = otlp_json(&self.settings.service_name, &spans);
+		let fut = actix_web::client::Client::default().post(&self.settings.url)
+		                                              .send_json(&body)
+		                                              .then(move |res| {
+			                                              match res {
+				                                              Ok(ref res) if res.status().is_success() => {
+					                                              log::debug!("tracing: exported {} spans", count)
+				                                              },
+			                                                  Ok(res) => log::warn!("tracing: collector answered {}", res.status()),
+			                                                  Err(err) => log::warn!("tracing: export failed: {:?}", err),
+			                                              }
+			                                              Ok(())
+		                                              });
+		ctx.spawn(fut.into_actor(self));
+	}
+}
+
+impl Actor for Exporter {
+	type Context = Context<Self>;
+
+	fn started(&mut self, ctx: &mut Self::Context) { ctx.run_interval(EXPORT_INTERVAL, |this, ctx| this.export(ctx)); }
+}
+
+
+/// Middleware opening a server span per request.
+pub struct Tracing;
+
+impl<S, B> Transform<S> for Tracing
+	where S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+	      S::Future: 'static,
+	      B: 'static
+{
+	type Request = ServiceRequest;
+	type Response = ServiceResponse<B>;
+	type Error = Error;
+	type InitError = ();
+	type Transform = TracingMiddleware<S>;
+	type Future = FutureResult<Self::Transform, Self::InitError>;
+
+	fn new_transform(&self, service: S) -> Self::Future { ok(TracingMiddleware { service }) }
+}
+
+pub struct TracingMiddleware<S> {
+	service: S,
+}
+
+impl<S, B> Service for TracingMiddleware<S>
+	where S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+	      S::Future: 'static,
+	      B: 'static
+{
+	type Request = ServiceRequest;
+	type Response = ServiceResponse<B>;
+	type Error = Error;
+	type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+	fn poll_ready(&mut self) -> Poll<(), Self::Error> { self.service.poll_ready() }
+
+	fn call(&mut self, req: ServiceRequest) -> Self::Future {
+		let parent = req.headers()
+		                .get(TRACEPARENT_HEADER)
+		                .and_then(|value| value.to_str().ok())
+		                .and_then(SpanContext::from_traceparent);
+		// named once routed, the raw path would make a span name per URL
+		let method = req.method().to_string();
+		let mut span = Span::with_parent(format!("{} {}", method, crate::metrics::UNMATCHED), Kind::Server, parent);
+		span.set_attribute("http.method", method.as_str());
+		span.set_attribute("http.target", req.path());
+		if let Some(id) = crate::logging::current_request_id() {
+			span.set_attribute("request.id", id);
+		}
+
+		// children of an unsampled parent are not recorded either, so keep the parent current
+		let context = span.context().or(parent);
+		let inner = with_current(context, || self.service.call(req));
+		Box::new(Scoped { context, inner }.then(move |res| {
+			                                      match &res {
+				                                      Ok(res) => {
+					                                      let route = crate::metrics::route_label(res);
+					                                      span.set_name(format!("{} {}", method, route));
+					                                      span.set_attribute("http.route", route);
+					                                      span.set_attribute("http.status_code", res.status().as_u16());
+					                                      if res.status().is_server_error() {
+						                                      span.set_error();
+					                                      }
+				                                      },
+			                                          Err(_) => span.set_error(),
+			                                      }
+			                                      res
+			                                     }))
+	}
+}
+
+/// Sets the current span on this thread whenever the inner future runs.
+struct Scoped<F> {
+	context: Option<SpanContext>,
+	inner: F,
+}
+
+impl<F: Future> Future for Scoped<F> {
+	type Item = F::Item;
+	type Error = F::Error;
+
+	fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+		let inner = &mut self.inner;
+		with_current(self.context, || inner.poll())
+	}
+}
+
+
+#[test]
+fn traceparent_test() {
+	let header = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+	let context = SpanContext::from_traceparent(header).unwrap();
+	assert!(context.sampled);
+	assert_eq!(hex::encode(&context.span_id), "00f067aa0ba902b7");
+	assert_eq!(context.traceparent(), header);
+
+	assert!(!SpanContext::from_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00").unwrap().sampled);
+	assert_eq!(SpanContext::from_traceparent("00-00000000000000000000000000000000-00f067aa0ba902b7-01"), None);
+	assert_eq!(SpanContext::from_traceparent("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"), None);
+	assert_eq!(SpanContext::from_traceparent("garbage"), None);
+}
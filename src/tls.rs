@@ -0,0 +1,299 @@
+//! HTTPS listener, certificate reloading, redirects from plain HTTP and HSTS.
+//!
+//! `[tls]`:
+//! - `listen_url`: `host:port` of the HTTPS listener, TLS is off when unset
+//! - `cert`: PEM certificate chain, leaf first (e.g. `fullchain.pem`)
+//! - `key`: PEM private key
+//! - `reload_secs`: how often the files are checked for changes (default 300)
+//! - `redirect_url`: `host:port` of a plain listener answering everything with a redirect to HTTPS
+//! - `hsts_max_age_secs`: `Strict-Transport-Security` max-age on HTTPS responses, not sent when 0 (default a year)
+//! - `hsts_include_subdomains`
+//!
+//! Every handshake switches to the certificate loaded last in the servername callback, which OpenSSL
+//! runs with and without SNI, so renewed files are served from the next connection on.
+
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime};
+use actix::prelude::*;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::{Error, HttpRequest, HttpResponse};
+use failure::format_err;
+use futures::future::{ok, FutureResult};
+use futures::{Future, Poll};
+use openssl::ssl::{AlpnError, SniError, SslAcceptor, SslAcceptorBuilder, SslContext, SslContextBuilder, SslFiletype, SslMethod};
+
+use crate::state::State;
+
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+	pub listen_url: String,
+	pub cert: PathBuf,
+	pub key: PathBuf,
+	pub reload_interval: Duration,
+	pub redirect_url: Option<String>,
+	/// Port of `listen_url`, redirects name it unless it is 443
+	pub https_port: u16,
+	pub hsts: Option<HeaderValue>,
+}
+
+impl Settings {
+	/// `None` when TLS is off.
+	pub fn from_config(config: &crate::config::Tls) -> Result<Option<Self>, String> {
+		let listen_url = match &config.listen_url {
+			Some(listen_url) => listen_url,
+			None if config.redirect_url.is_some() => return Err("tls.redirect_url needs tls.listen_url".to_owned()),
+			None => return Ok(None),
+		};
+		let https_port = listen_url.rsplit(':')
+		                           .next()
+		                           .and_then(|port| port.parse().ok())
+		                           .ok_or_else(|| format!("tls.listen_url: expected host:port, got {:?}", listen_url))?;
+		let cert = config.cert.clone().ok_or("tls.cert (TLS_CERT) must be set with tls.listen_url")?;
+		let key = config.key.clone().ok_or("tls.key (TLS_KEY) must be set with tls.listen_url")?;
+		let hsts = Some(config.hsts_max_age_secs).filter(|secs| *secs > 0)
+		                                         .map(|secs| hsts_value(secs, config.hsts_include_subdomains));
+		Ok(Some(Self { listen_url: listen_url.clone(),
+		               cert,
+		               key,
+		               reload_interval: Duration::from_secs(config.reload_secs.max(1)),
+		               redirect_url: config.redirect_url.clone(),
+		               https_port,
+		               hsts }))
+	}
+}
+
+fn hsts_value(max_age_secs: u64, include_subdomains: bool) -> HeaderValue {
+	let value = if include_subdomains {
+		format!("max-age={}; includeSubDomains", max_age_secs)
+	} else {
+		format!("max-age={}", max_age_secs)
+	};
+	HeaderValue::from_str(&value).expect("ascii header value")
+}
+
+
+struct Loaded {
+	modified: (Option<SystemTime>, Option<SystemTime>),
+	context: SslContext,
+}
+
+pub struct Certs {
+	settings: Settings,
+	loaded: RwLock<Loaded>,
+}
+
+impl Certs {
+	/// Loads the certificate and key, missing or mismatched files fail startup.
+	pub fn new(settings: Settings) -> Result<Self, failure::Error> {
+		let loaded = load(&settings)?;
+		Ok(Self { settings,
+		          loaded: RwLock::new(loaded) })
+	}
+
+	pub fn settings(&self) -> &Settings { &self.settings }
+
+	/// Loads the files again if either changed since they were loaded.
+	/// A failed reload keeps serving the previous certificate.
+	pub fn reload(&self) -> Result<bool, failure::Error> {
+		let modified = modified(&self.settings)?;
+		if modified.0.is_some() && modified.1.is_some()
+		   && self.loaded.read().unwrap_or_else(|poisoned| poisoned.into_inner()).modified == modified
+		{
+			return Ok(false);
+		}
+		let loaded = load(&self.settings)?;
+		log::info!("tls: loaded {}", self.settings.cert.display());
+		*self.loaded.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = loaded;
+		Ok(true)
+	}
+
+	fn context(&self) -> SslContext { self.loaded.read().unwrap_or_else(|poisoned| poisoned.into_inner()).context.clone() }
+
+	/// Acceptor for `HttpServer::listen_ssl`, its handshakes use the certificate in the state.
+	pub fn acceptor(&self) -> Result<SslAcceptorBuilder, failure::Error> {
+		let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+		set_certificate(&mut builder, &self.settings)?;
+		builder.set_servername_callback(|ssl, _| {
+			       let certs = State::get().tls().expect("tls certificates in the state");
+			       ssl.set_ssl_context(&certs.context()).map_err(|err| {
+				                                            log::error!("tls: switching the certificate failed: {}", err);
+				                                            SniError::ALERT_FATAL
+				                                           })
+			      });
+		Ok(builder)
+	}
+}
+
+fn modified(settings: &Settings) -> Result<(Option<SystemTime>, Option<SystemTime>), failure::Error> {
+	let modified = |path: &Path| -> Result<_, failure::Error> {
+		Ok(std::fs::metadata(path).map_err(|err| format_err!("{}: {}", path.display(), err))?.modified().ok())
+	};
+	Ok((modified(&settings.cert)?, modified(&settings.key)?))
+}
+
+fn set_certificate(builder: &mut SslContextBuilder, settings: &Settings) -> Result<(), failure::Error> {
+	builder.set_certificate_chain_file(&settings.cert)
+	       .map_err(|err| format_err!("tls.cert {}: {}", settings.cert.display(), err))?;
+	builder.set_private_key_file(&settings.key, SslFiletype::PEM)
+	       .map_err(|err| format_err!("tls.key {}: {}", settings.key.display(), err))?;
+	builder.check_private_key().map_err(|err| format_err!("tls.key does not match tls.cert: {}", err))?;
+	Ok(())
+}
+
+/// The context handshakes switch to only supplies the certificate and the ALPN choice,
+/// protocol versions and ciphers stay those of the acceptor.
+fn load(settings: &Settings) -> Result<Loaded, failure::Error> {
+	let modified = modified(settings)?;
+	let mut builder = SslContext::builder(SslMethod::tls())?;
+	set_certificate(&mut builder, settings)?;
+	// the same choice actix-web sets up on the acceptor
+	builder.set_alpn_select_callback(|_, protos| {
+		       const H2: &[u8] = b"\x02h2";
+		       if protos.windows(3).any(|window| window == H2) {
+			       Ok(b"h2")
+			      } else {
+			       Err(AlpnError::NOACK)
+			      }
+		      });
+	Ok(Loaded { modified,
+	            context: builder.build() })
+}
+
+
+/// Checks the certificate files for changes in the current system.
+pub struct Reloader {
+	interval: Duration,
+}
+
+impl Reloader {
+	pub fn start() {
+		if let Some(certs) = State::get().tls() {
+			Actor::start(Reloader { interval: certs.settings().reload_interval });
+		}
+	}
+}
+
+impl Actor for Reloader {
+	type Context = Context<Self>;
+
+	fn started(&mut self, ctx: &mut Self::Context) {
+		ctx.run_interval(self.interval, |_, _| {
+			   if let Some(Err(err)) = State::get().tls().map(Certs::reload) {
+				   log::error!("tls: reload failed, keeping the loaded certificate: {:?}", err);
+			   }
+		   });
+	}
+}
+
+
+/// `https://` URL for a request that came over plain HTTP, `None` for a missing or odd `Host`.
+fn https_location(host: &str, path_and_query: &str, https_port: u16) -> Option<String> {
+	let name = if host.starts_with('[') {
+		&host[..=host.find(']')?]
+	} else {
+		host.split(':').next().unwrap_or_default()
+	};
+	let valid = |b: u8| b.is_ascii_alphanumeric() || b == b'-' || b == b'.' || b == b'[' || b == b']' || b == b':';
+	if name.is_empty() || !name.bytes().all(valid) {
+		return None;
+	}
+	Some(match https_port {
+		443 => format!("https://{}{}", name, path_and_query),
+		port => format!("https://{}:{}{}", name, port, path_and_query),
+	})
+}
+
+/// Default service of the redirect listener.
+pub fn redirect(req: HttpRequest) -> HttpResponse {
+	let https_port = State::get().tls().expect("tls certificates in the state").settings().https_port;
+	let path_and_query = req.uri().path_and_query().map(|path| path.as_str()).unwrap_or("/");
+	let location = req.headers()
+	                  .get(header::HOST)
+	                  .and_then(|host| host.to_str().ok())
+	                  .and_then(|host| https_location(host, path_and_query, https_port));
+	match location {
+		Some(location) => HttpResponse::PermanentRedirect().header(header::LOCATION, location).finish(),
+		None => HttpResponse::BadRequest().finish(),
+	}
+}
+
+
+/// Adds `Strict-Transport-Security` to responses sent over TLS.
+pub struct Hsts {
+	value: Option<HeaderValue>,
+}
+
+impl Hsts {
+	pub fn new(value: Option<HeaderValue>) -> Self { Self { value } }
+}
+
+impl<S, B> Transform<S> for Hsts
+	where S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+	      S::Future: 'static,
+	      B: 'static
+{
+	type Request = ServiceRequest;
+	type Response = ServiceResponse<B>;
+	type Error = Error;
+	type InitError = ();
+	type Transform = HstsMiddleware<S>;
+	type Future = FutureResult<Self::Transform, Self::InitError>;
+
+	fn new_transform(&self, service: S) -> Self::Future {
+		ok(HstsMiddleware { service,
+		                    value: self.value.clone() })
+	}
+}
+
+pub struct HstsMiddleware<S> {
+	service: S,
+	value: Option<HeaderValue>,
+}
+
+impl<S, B> Service for HstsMiddleware<S>
+	where S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+	      S::Future: 'static,
+	      B: 'static
+{
+	type Request = ServiceRequest;
+	type Response = ServiceResponse<B>;
+	type Error = Error;
+	type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+	fn poll_ready(&mut self) -> Poll<(), Self::Error> { self.service.poll_ready() }
+
+	fn call(&mut self, req: ServiceRequest) -> Self::Future {
+		let value = match &self.value {
+			Some(value) if req.app_config().secure() => value.clone(),
+			_ => return Box::new(self.service.call(req)),
+		};
+		Box::new(self.service.call(req).map(move |mut res| {
+			                                   res.headers_mut().insert(header::STRICT_TRANSPORT_SECURITY, value);
+			                                   res
+			                                  }))
+	}
+}
+
+
+#[test]
+fn redirect_test() {
+	assert_eq!(https_location("akropolis.io", "/1.0/?a=b", 443), Some("https://akropolis.io/1.0/?a=b".to_owned()));
+	assert_eq!(https_location("akropolis.io:8080", "/", 8443), Some("https://akropolis.io:8443/".to_owned()));
+	assert_eq!(https_location("[2001:db8::1]:80", "/", 443), Some("https://[2001:db8::1]/".to_owned()));
+	assert_eq!(https_location("evil.example/x", "/", 443), None);
+	assert_eq!(https_location("", "/", 443), None);
+
+	let mut config = crate::config::Tls::default();
+	assert!(Settings::from_config(&config).unwrap().is_none());
+	config.listen_url = Some("0.0.0.0:8443".to_owned());
+	assert!(Settings::from_config(&config).unwrap_err().contains("tls.cert"));
+	config.cert = Some("fullchain.pem".into());
+	config.key = Some("privkey.pem".into());
+	config.hsts_include_subdomains = true;
+	let settings = Settings::from_config(&config).unwrap().unwrap();
+	assert_eq!(settings.https_port, 8443);
+	assert_eq!(settings.hsts, Some(HeaderValue::from_static("max-age=31536000; includeSubDomains")));
+}
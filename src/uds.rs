@@ -0,0 +1,233 @@
+//! Unix domain socket listener, e.g. behind nginx with `proxy_pass http://unix:/run/bounty/bounty.sock:`.
+//!
+//! actix-server only listens on TCP, so connections are accepted here on the main arbiter and handed
+//! round-robin to worker arbiters, one per CPU like `HttpServer` starts, running the same `HttpService`.
+//! Handlers block on the database, so the main arbiter never serves requests itself. Unix peers have no address,
+//! requests from them carry `client_ip::UnixPeer` and the proxy is trusted to name the client.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::Permissions;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use actix::Arbiter;
+use actix_http::body::MessageBody;
+use actix_http::{HttpMessage, HttpService, Request, Response};
+use actix_server_config::{Io, IoStream, ServerConfig};
+use actix_service::{new_apply_fn, IntoNewService, NewService, Service};
+use futures::sync::{mpsc, oneshot};
+use futures::{try_ready, Async, Future, Poll, Stream};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_reactor::PollEvented;
+
+use crate::client_ip::UnixPeer;
+
+
+/// The local address the app sees, unix sockets have none.
+const LOCAL: ([u8; 4], u16) = ([127, 0, 0, 1], 0);
+
+
+/// `server.unix_socket_mode`, octal like `chmod`.
+pub fn parse_mode(mode: &str) -> Result<u32, String> {
+	u32::from_str_radix(mode, 8).ok()
+	                            .filter(|mode| *mode <= 0o777)
+	                            .ok_or_else(|| format!("server.unix_socket_mode: expected an octal mode like 660, got {:?}", mode))
+}
+
+/// Binds the socket file with `mode`, replacing a stale file no server listens on.
+pub fn bind(path: &Path, mode: u32) -> io::Result<net::UnixListener> {
+	if path.exists() {
+		if net::UnixStream::connect(path).is_ok() {
+			return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{} is in use", path.display())));
+		}
+		std::fs::remove_file(path)?;
+	}
+	let listener = net::UnixListener::bind(path)?;
+	std::fs::set_permissions(path, Permissions::from_mode(mode))?;
+	Ok(listener)
+}
+
+
+pub struct UnixStream(PollEvented<mio_uds::UnixStream>);
+
+impl Read for UnixStream {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.0.read(buf) }
+}
+
+impl Write for UnixStream {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.0.write(buf) }
+
+	fn flush(&mut self) -> io::Result<()> { self.0.flush() }
+}
+
+impl AsyncRead for UnixStream {}
+
+impl AsyncWrite for UnixStream {
+	fn shutdown(&mut self) -> Poll<(), io::Error> { self.0.shutdown() }
+}
+
+impl IoStream for UnixStream {
+	fn peer_addr(&self) -> Option<SocketAddr> { None }
+
+	fn set_nodelay(&mut self, _: bool) -> io::Result<()> { Ok(()) }
+
+	fn set_linger(&mut self, _: Option<Duration>) -> io::Result<()> { Ok(()) }
+
+	fn set_keepalive(&mut self, _: Option<Duration>) -> io::Result<()> { Ok(()) }
+}
+
+struct Incoming(PollEvented<mio_uds::UnixListener>);
+
+impl Stream for Incoming {
+	type Item = mio_uds::UnixStream;
+	type Error = io::Error;
+
+	fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+		try_ready!(self.0.poll_read_ready(mio::Ready::readable()));
+		match self.0.get_ref().accept() {
+			Ok(Some((stream, _))) => Ok(Async::Ready(Some(stream))),
+			Ok(None) => {
+				self.0.clear_read_ready(mio::Ready::readable())?;
+				Ok(Async::NotReady)
+			},
+			// out of descriptors and the like should not end the listener
+			Err(err) => {
+				log::warn!("unix socket: accept failed: {}", err);
+				self.0.clear_read_ready(mio::Ready::readable())?;
+				Ok(Async::NotReady)
+			},
+		}
+	}
+}
+
+
+/// A running listener, it stops when dropped.
+pub struct Listener {
+	/// Socket file to remove on stop, `None` for sockets passed by systemd
+	path: Option<PathBuf>,
+	stop: Option<oneshot::Sender<()>>,
+	workers: Vec<Arbiter>,
+	connections: Arc<AtomicUsize>,
+}
+
+impl Listener {
+	/// Stops accepting and removes the socket file, open connections are served on.
+	pub fn stop(&mut self) {
+		if let Some(stop) = self.stop.take() {
+			let _ = stop.send(());
+		}
+		if let Some(path) = self.path.take() {
+			if let Err(err) = std::fs::remove_file(&path) {
+				log::warn!("unix socket: removing {} failed: {}", path.display(), err);
+			}
+		}
+	}
+
+	/// Connections still open.
+	pub fn connections(&self) -> usize { self.connections.load(Ordering::SeqCst) }
+}
+
+impl Drop for Listener {
+	fn drop(&mut self) {
+		self.stop();
+		self.workers.iter().for_each(Arbiter::stop);
+	}
+}
+
+/// Serves the app built by `factory` on worker arbiters, like `HttpServer` does on its workers.
+pub fn serve<F, I, S, B>(listener: net::UnixListener, path: Option<PathBuf>, factory: F) -> io::Result<Listener>
+	where F: Fn() -> I + Send + Clone + 'static,
+	      I: IntoNewService<S>,
+	      S: NewService<Config = ServerConfig, Request = Request> + 'static,
+	      S::Error: Into<actix_http::Error>,
+	      S::InitError: fmt::Debug,
+	      S::Response: Into<Response<B>>,
+	      S::Service: 'static,
+	      <S::Service as Service>::Future: 'static,
+	      B: MessageBody + 'static
+{
+	let incoming = Incoming(PollEvented::new(mio_uds::UnixListener::from_listener(listener)?));
+	let (stop, stopped) = oneshot::channel();
+	let connections = Arc::new(AtomicUsize::new(0));
+
+	let mut workers = Vec::new();
+	let mut queues = Vec::new();
+	for _ in 0..num_cpus::get() {
+		let (queue, streams) = mpsc::unbounded();
+		let worker = Arbiter::new();
+		let factory = factory.clone();
+		let open = Arc::clone(&connections);
+		worker.exec_fn(move || work(streams, factory, open));
+		workers.push(worker);
+		queues.push(queue);
+	}
+
+	let mut next = 0;
+	let accept = incoming.for_each(move |stream| {
+		                     next = (next + 1) % queues.len();
+		                     if queues[next].unbounded_send(stream).is_err() {
+			                     log::warn!("unix socket: worker {} is gone, connection dropped", next);
+		                     }
+		                     Ok(())
+		                    })
+	                     .map_err(|err| log::error!("unix socket: accepting failed: {}", err));
+	actix::spawn(accept.select(stopped.map_err(drop)).map(drop).map_err(drop));
+
+	Ok(Listener { path,
+	              stop: Some(stop),
+	              workers,
+	              connections })
+}
+
+/// Serves the connections accepted for this worker arbiter until the listener stops.
+fn work<F, I, S, B>(streams: mpsc::UnboundedReceiver<mio_uds::UnixStream>, factory: F, open: Arc<AtomicUsize>)
+	where F: Fn() -> I,
+	      I: IntoNewService<S>,
+	      S: NewService<Config = ServerConfig, Request = Request> + 'static,
+	      S::Error: Into<actix_http::Error>,
+	      S::InitError: fmt::Debug,
+	      S::Response: Into<Response<B>>,
+	      S::Service: 'static,
+	      <S::Service as Service>::Future: 'static,
+	      B: MessageBody + 'static
+{
+	let service = HttpService::<UnixStream, (), _, B>::build().client_disconnect(5000)
+	                                                           .finish(new_apply_fn(factory(), |req: Request, app: &mut S::Service| {
+		                                                           req.extensions_mut().insert(UnixPeer);
+		                                                           app.call(req)
+		                                                          }))
+	                                                           .new_service(&ServerConfig::new(SocketAddr::from(LOCAL)));
+	actix::spawn(service.map_err(|err| log::error!("unix socket: starting the app failed: {:?}", err))
+	                    .and_then(move |service| {
+		                    let service = Rc::new(RefCell::new(service));
+		                    streams.for_each(move |stream| {
+			                           open.fetch_add(1, Ordering::SeqCst);
+			                           let open = Arc::clone(&open);
+			                           let conn = service.borrow_mut().call(Io::new(UnixStream(PollEvented::new(stream))));
+			                           actix::spawn(conn.then(move |result| {
+				                                            open.fetch_sub(1, Ordering::SeqCst);
+				                                            if let Err(err) = result {
+					                                            log::debug!("unix socket: connection failed: {:?}", err);
+				                                            }
+				                                            Ok(())
+				                                           }));
+			                           Ok(())
+			                          })
+		                   }));
+}
+
+
+#[test]
+fn mode_test() {
+	assert_eq!(parse_mode("660"), Ok(0o660));
+	assert_eq!(parse_mode("0600"), Ok(0o600));
+	assert!(parse_mode("999").is_err());
+	assert!(parse_mode("1777").is_err());
+}
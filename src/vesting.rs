@@ -0,0 +1,71 @@
+//! Unlocking of allocations over time.
+
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+
+use crate::db::TheConnection;
+use crate::db::models::VestingSchedule;
+
+
+/// Part of `total` unlocked at `now`.
+/// Without a schedule the whole amount is unlocked.
+pub fn vested(schedule: Option<&VestingSchedule>, total: i64, now: NaiveDateTime) -> i64 {
+	let schedule = match schedule {
+		Some(schedule) => schedule,
+		None => return total,
+	};
+
+	let elapsed = now.timestamp() - schedule.start_at.timestamp();
+	if elapsed < schedule.cliff_secs.max(0) {
+		return 0;
+	}
+	if elapsed >= schedule.duration_secs {
+		return total;
+	}
+
+	let elapsed = if schedule.period_secs > 0 {
+		elapsed - elapsed % schedule.period_secs
+	} else {
+		elapsed
+	};
+	(i128::from(total) * i128::from(elapsed) / i128::from(schedule.duration_secs)) as i64
+}
+
+/// Unlocked but not paid out yet.
+pub fn due(schedule: Option<&VestingSchedule>, total: i64, paid: Option<i64>, now: NaiveDateTime) -> i64 {
+	(vested(schedule, total, now) - paid.unwrap_or(0)).max(0)
+}
+
+
+pub fn find_schedule(conn: &TheConnection, user_id: i32) -> QueryResult<Option<VestingSchedule>> {
+	use crate::db::schema::vesting_schedules::dsl;
+
+	dsl::vesting_schedules.filter(dsl::user_id.eq(user_id))
+	                      .first::<VestingSchedule>(conn)
+	                      .optional()
+}
+
+
+#[test]
+fn vested_test() {
+	use chrono::NaiveDate;
+
+	let start = NaiveDate::from_ymd(2019, 7, 1).and_hms(0, 0, 0);
+	let day = 24 * 3600;
+	let schedule = VestingSchedule { id: 1,
+	                                 user_id: 1,
+	                                 start_at: start,
+	                                 cliff_secs: 30 * day,
+	                                 duration_secs: 100 * day,
+	                                 period_secs: 10 * day };
+	let at = |days: i64| start + chrono::Duration::seconds(days * day);
+
+	assert_eq!(vested(None, 1000, at(0)), 1000);
+	assert_eq!(vested(Some(&schedule), 1000, at(-1)), 0);
+	assert_eq!(vested(Some(&schedule), 1000, at(29)), 0);
+	assert_eq!(vested(Some(&schedule), 1000, at(30)), 300);
+	assert_eq!(vested(Some(&schedule), 1000, at(39)), 300);
+	assert_eq!(vested(Some(&schedule), 1000, at(45)), 400);
+	assert_eq!(vested(Some(&schedule), 1000, at(100)), 1000);
+	assert_eq!(due(Some(&schedule), 1000, Some(300), at(45)), 100);
+}